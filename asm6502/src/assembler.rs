@@ -0,0 +1,1062 @@
+use std::collections::hash_map::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostic::{Diagnostic, LineError, Location};
+use crate::encode::{self, Mode};
+use crate::expr::Expr;
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::macros::{self, Macro};
+use crate::parser::{parse_expr, parse_line, Arg, Directive, Index, Instruction, Operand, Span, Width};
+use crate::Program;
+
+// how many times layout goes over the statements before giving up on it settling
+const MAX_LAYOUT_PASSES: usize = 100;
+// how deep macros can invoke macros, so one that invokes itself stops
+const MAX_MACRO_DEPTH: usize = 32;
+
+// What a statement assembles to
+enum Item {
+    Instruction {
+        instruction: Instruction,
+        mode: Mode,
+        opcode: u8,
+        // zero page for now, but absolute if the operand turns out not to fit
+        flexible: bool,
+    },
+    // `.byte` and `.text`, then a zero for `.asciiz`
+    Bytes(Vec<Arg>, bool),
+    Words(Vec<Expr>),
+    Org(Expr),
+    // `.fill` and `.res`: how many bytes, and what they are
+    Fill(Expr, Option<Expr>),
+    // `.align`: the boundary, and what the padding is
+    Align(Expr, Option<Expr>),
+    Binary(Vec<u8>),
+}
+
+// The file being assembled, or one it includes
+struct SourceFile {
+    // as diagnostics and the debug map name it
+    path: String,
+    // to tell when a file includes itself
+    canonical: PathBuf,
+    // the `.include` it came from, in `lines`
+    included_from: Option<(usize, Span)>,
+}
+
+// A line to assemble: one from a file, or one from the body of a macro as expanded
+struct SourceLine {
+    text: String,
+    // in `files`
+    file: usize,
+    line_number: usize,
+    // the expansion it came out of, if it did
+    expansion: Option<usize>,
+}
+
+// Where a macro was invoked
+struct Expansion {
+    name: String,
+    // the invocation, in `lines`
+    line: usize,
+    span: Span,
+}
+
+// A `.macro` whose body is being collected
+struct Definition {
+    // `None` when the `.macro` line was wrong, so the body is skipped but not kept
+    name: Option<String>,
+    line: usize,
+    params: Vec<String>,
+    body: Vec<usize>,
+    locals: Vec<String>,
+    // how many `.macro`s inside the body haven't ended yet
+    depth: usize,
+}
+
+// An `.if` (or `.ifdef` or `.ifndef`) up to its `.endif`
+struct Condition {
+    // the `.if`, in `lines`
+    line: usize,
+    // whether the lines in the branch so far are assembled
+    active: bool,
+    // whether one of the branches has been, so the rest aren't
+    taken: bool,
+    // the `.else`, if there's been one, in `lines`
+    else_line: Option<usize>,
+}
+
+// An instruction or directive from the first pass
+struct Statement {
+    // in `lines`
+    line: usize,
+    // the mnemonic or directive, to point at
+    span: Span,
+    address: u16,
+    item: Item,
+}
+
+// A value from `name = expr`, `.equ name, expr` or `define name expr`
+struct Constant {
+    value: i64,
+    // written like `$0010`, so it's an absolute address
+    wide: bool,
+    // in `lines`
+    line: usize,
+}
+
+struct Label {
+    // as written
+    name: String,
+    // in `lines`
+    line: usize,
+    // the statement it's the address of, which is the end when there isn't one
+    statement: usize,
+}
+
+struct Assembler<'a> {
+    files: Vec<SourceFile>,
+    // every line assembled, in order, macro expansions and included files included
+    lines: Vec<SourceLine>,
+    start_mem_address: u16,
+    // where `.include` looks after the including file's directory
+    include_paths: &'a [PathBuf],
+
+    // by lower case name
+    macros: HashMap<String, Macro>,
+    definition: Option<Definition>,
+    expansions: Vec<Expansion>,
+    conditions: Vec<Condition>,
+
+    // by lower case name, as labels aren't case sensitive
+    labels: HashMap<String, Label>,
+    // by lower case name too, sharing names with labels
+    constants: HashMap<String, Constant>,
+    // lower case names in source order, for the symbol file
+    label_order: Vec<String>,
+    statements: Vec<Statement>,
+    // the address after the last statement
+    end: u16,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Assemble lines from `file`, reporting every error found rather than
+/// stopping at the first. Files it includes are looked for next to it, then in each of
+/// `include_paths`.
+pub fn assemble_lines(file: &str, lines: &[String], start_mem_address: u16, include_paths: &[PathBuf]) -> Result<Program, Vec<Diagnostic>> {
+    let main = SourceFile {
+        path: file.to_string(),
+        canonical: fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file)),
+        included_from: None,
+    };
+    let mut assembler = Assembler {
+        files: vec![main],
+        lines: Vec::new(),
+        start_mem_address,
+        include_paths,
+        macros: HashMap::new(),
+        definition: None,
+        expansions: Vec::new(),
+        conditions: Vec::new(),
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        label_order: Vec::new(),
+        statements: Vec::new(),
+        end: start_mem_address,
+        diagnostics: Vec::new(),
+    };
+    assembler.first_pass(lines);
+    assembler.layout();
+    let program = assembler.second_pass();
+    if assembler.diagnostics.is_empty() {
+        Ok(program)
+    } else {
+        Err(assembler.diagnostics)
+    }
+}
+
+impl<'a> Assembler<'a> {
+    // An error on a line, with a note at each macro invocation it was expanded from and
+    // each `.include` its file came from
+    fn error(&mut self, line: usize, error: LineError) {
+        let source = &self.lines[line];
+        let mut diagnostic = Diagnostic::at(&self.files[source.file].path, source.line_number, &source.text, error);
+        let mut line = line;
+        loop {
+            let source = &self.lines[line];
+            let (message, from, span) = match (source.expansion, &self.files[source.file].included_from) {
+                (Some(index), _) => {
+                    let expansion = &self.expansions[index];
+                    (format!("in this expansion of `{}`", expansion.name), expansion.line, expansion.span)
+                },
+                (None, &Some((from, span))) => (format!("`{}` is included here", self.files[source.file].path), from, span),
+                (None, None) => break,
+            };
+            let from_source = &self.lines[from];
+            let file = &self.files[from_source.file].path;
+            let location = Location::new(file, from_source.line_number, &from_source.text, span.column, span.length);
+            diagnostic = diagnostic.with_note(&message, location);
+            line = from;
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    // Where a line is, for a hint on `other`: its line number, and its file when that
+    // isn't the same
+    fn place(&self, line: usize, other: usize) -> String {
+        let source = &self.lines[line];
+        if source.file == self.lines[other].file {
+            format!("line {}", source.line_number)
+        } else {
+            format!("line {} of `{}`", source.line_number, self.files[source.file].path)
+        }
+    }
+
+    // Parse every line, expanding macros and includes and leaving out what conditions
+    // exclude, find the labels and pick addressing modes, leaving zero page or absolute
+    // to `layout` when it depends on a label
+    fn first_pass(&mut self, lines: &[String]) {
+        // lines still to assemble, the next one last, as expanding a macro or including a
+        // file puts its lines in front of whatever comes after it
+        let mut pending: Vec<SourceLine> = lines.iter().enumerate().rev()
+            .map(|(index, text)| SourceLine { text: text.clone(), file: 0, line_number: index + 1, expansion: None })
+            .collect();
+        while let Some(source) = pending.pop() {
+            let line = self.lines.len();
+            self.lines.push(source);
+            self.line(line, &mut pending);
+        }
+
+        if let Some(definition) = self.definition.take() {
+            let span = self.whole_line(definition.line);
+            self.error(definition.line, span.error("`.macro` without `.endmacro`"));
+        }
+        for condition in std::mem::take(&mut self.conditions) {
+            let span = self.whole_line(condition.line);
+            self.error(condition.line, span.error("`.if` without `.endif`"));
+        }
+    }
+
+    fn whole_line(&self, line: usize) -> Span {
+        let text = &self.lines[line].text;
+        Span { column: text.len() - text.trim_start().len(), length: text.trim().len() }
+    }
+
+    fn line(&mut self, line: usize, pending: &mut Vec<SourceLine>) {
+        let text = self.lines[line].text.clone();
+        let tokens = match tokenize(&text) {
+            Ok(tokens) => tokens,
+            Err(_) if self.definition.is_some() => {
+                // reported if it's ever expanded
+                self.definition.as_mut().unwrap().body.push(line);
+                return;
+            },
+            // not assembled, so it doesn't matter
+            Err(_) if !self.active() => return,
+            Err(error) => return self.error(line, error),
+        };
+
+        // what the line starts with, after any label
+        let label = match (tokens.first(), tokens.get(1)) {
+            (Some(Token { kind: TokenKind::Ident(name), .. }), Some(Token { kind: TokenKind::Colon, .. })) => Some(name.clone()),
+            _ => None,
+        };
+        let head_index = if label.is_some() { 2 } else { 0 };
+        let (head, head_span) = match tokens.get(head_index) {
+            Some(token @ Token { kind: TokenKind::Ident(name), .. }) => {
+                (name.to_lowercase(), Span { column: token.column, length: token.length })
+            },
+            _ => (String::new(), Span { column: 0, length: 0 }),
+        };
+
+        if let Some(ref mut definition) = self.definition {
+            match head.as_str() {
+                ".macro" => definition.depth += 1,
+                ".endmacro" | ".endm" if definition.depth > 0 => definition.depth -= 1,
+                ".endmacro" | ".endm" => {
+                    let definition = self.definition.take().unwrap();
+                    if let Some(name) = definition.name {
+                        let key = name.to_lowercase();
+                        let m = Macro { name, line: definition.line, params: definition.params, body: definition.body, locals: definition.locals };
+                        self.macros.insert(key, m);
+                    }
+                    if label.is_some() {
+                        self.error(line, Span::of(&tokens[0]).error("labels can't go on `.endmacro`"));
+                    }
+                    return;
+                },
+                _ => {
+                    if let (Some(label), 0) = (label, definition.depth) {
+                        definition.locals.push(label.to_lowercase());
+                    }
+                },
+            }
+            definition.body.push(line);
+            return;
+        }
+
+        if head.starts_with(".if") || head.starts_with(".else") || head == ".endif" {
+            if label.is_some() {
+                return self.error(line, Span::of(&tokens[0]).error(&format!("labels can't go on `{}`", head)));
+            }
+            let args = &tokens[head_index + 1..];
+            match self.condition(line, &head, head_span, args, text.len()) {
+                Ok(()) => return,
+                Err(Some(error)) => return self.error(line, error),
+                // not a conditional directive, like `.elsewhere`
+                Err(None) => (),
+            }
+        }
+        if !self.active() {
+            return;
+        }
+
+        match head.as_str() {
+            ".macro" => {
+                let mut definition = Definition { name: None, line, params: Vec::new(), body: Vec::new(), locals: Vec::new(), depth: 0 };
+                let defined = match macros::parse_definition(&tokens[head_index + 1..], head_span) {
+                    _ if label.is_some() => Err(Span::of(&tokens[0]).error("labels can't go on `.macro`")),
+                    Ok((name, _)) if self.macros.contains_key(&name.to_lowercase()) => {
+                        let first = self.macros[&name.to_lowercase()].line;
+                        let error = Span::of(&tokens[head_index + 1]).error(&format!("macro `{}` is already defined", name));
+                        Err(error.with_hint(format!("first defined on {}", self.place(first, line))))
+                    },
+                    defined => defined,
+                };
+                match defined {
+                    Ok((name, params)) => {
+                        definition.name = Some(name);
+                        definition.params = params;
+                    },
+                    Err(error) => self.error(line, error),
+                }
+                self.definition = Some(definition);
+                return;
+            },
+            ".endmacro" | ".endm" => return self.error(line, head_span.error(&format!("`{}` without `.macro`", head))),
+            _ => (),
+        }
+
+        // `name = expr`, `.equ name, expr` and `define name expr`, by where the name is
+        // and where the value starts
+        let constant = match tokens.get(1) {
+            Some(Token { kind: TokenKind::Equals, .. }) if label.is_none() => Some((0, 2)),
+            _ if head == "define" && label.is_none() => Some((1, 2)),
+            _ if head == ".equ" => match tokens.get(head_index + 2) {
+                None | Some(Token { kind: TokenKind::Comma, .. }) => Some((head_index + 1, head_index + 3)),
+                Some(token) => {
+                    let error = Span::of(token).error(&format!("expected `,`, found {}", token.kind));
+                    return self.error(line, error.with_hint("`.equ name, value`".to_string()));
+                },
+            },
+            _ => None,
+        };
+        if let Some((name, value)) = constant {
+            if label.is_some() {
+                return self.error(line, Span::of(&tokens[0]).error(&format!("labels can't go on `{}`", head)));
+            }
+            let value = tokens.get(value..).unwrap_or(&[]);
+            if let Err(error) = self.define_constant(line, tokens.get(name), head_span, value, text.len()) {
+                self.error(line, error);
+            }
+            return;
+        }
+
+        if let Some(m) = self.macros.get(&head) {
+            let args = macros::split_args(&text, &tokens[head_index + 1..]);
+            let args = match args {
+                Ok(args) if args.len() != m.params.len() => {
+                    let span = args.first().map_or(head_span, |&(_, first)| first.to(args[args.len() - 1].1));
+                    let plural = if m.params.len() == 1 { "" } else { "s" };
+                    let error = span.error(&format!("`{}` takes {} argument{}, found {}", m.name, m.params.len(), plural, args.len()));
+                    Err(error.with_hint(format!("`{}` is defined on {}", m.name, self.place(m.line, line))))
+                },
+                args => args,
+            };
+            let args: Vec<String> = match args {
+                Ok(args) => args.into_iter().map(|(arg, _)| arg).collect(),
+                Err(error) => return self.error(line, error),
+            };
+            let mut depth = 1;
+            let mut outer = self.lines[line].expansion;
+            while let Some(index) = outer {
+                depth += 1;
+                outer = self.lines[self.expansions[index].line].expansion;
+            }
+            if depth > MAX_MACRO_DEPTH {
+                let error = head_span.error(&format!("macros are nested more than {} deep", MAX_MACRO_DEPTH));
+                return self.error(line, error.with_hint(format!("`{}` might invoke itself", m.name)));
+            }
+
+            let expansion = self.expansions.len();
+            let body: Vec<SourceLine> = m.body.iter()
+                .map(|&body_line| {
+                    let source = &self.lines[body_line];
+                    let text = m.expand(&source.text, &args, expansion);
+                    SourceLine { text, file: source.file, line_number: source.line_number, expansion: Some(expansion) }
+                })
+                .collect();
+            self.expansions.push(Expansion { name: m.name.clone(), line, span: head_span });
+            pending.extend(body.into_iter().rev());
+            if let Some(label) = label {
+                self.define_label(line, label, Span::of(&tokens[0]));
+            }
+            return;
+        }
+
+        let tokens = self.substitute(tokens, head_index);
+        let parsed = match parse_line(&tokens, text.len()) {
+            Ok(parsed) => parsed,
+            Err(error) => return self.error(line, error),
+        };
+        if let Some((label, span)) = parsed.label {
+            self.define_label(line, label, span);
+        }
+
+        let item = match (parsed.instruction, parsed.directive) {
+            (Some(instruction), _) => {
+                let span = instruction.span;
+                select_mode(&instruction).map(|(mode, flexible)| {
+                    let opcode = encode::opcode(&instruction.mnemonic, mode).expect("selected modes have opcodes");
+                    (span, Item::Instruction { instruction, mode, opcode, flexible })
+                })
+            },
+            (None, Some(directive)) if directive.name == ".include" => {
+                if let Err(error) = self.include(line, directive, pending) {
+                    self.error(line, error);
+                }
+                return;
+            },
+            (None, Some(directive)) => {
+                let span = directive.span;
+                self.directive(line, directive).map(|item| (span, item))
+            },
+            (None, None) => return,
+        };
+        match item {
+            Ok((span, item)) => self.statements.push(Statement { line, span, address: 0, item }),
+            Err(error) => self.error(line, error),
+        }
+    }
+
+    // A label for the next statement
+    fn define_label(&mut self, line: usize, label: String, span: Span) {
+        let key = label.to_lowercase();
+        if let Some(first) = self.labels.get(&key) {
+            let hint = format!("first defined on {}", self.place(first.line, line));
+            self.error(line, span.error(&format!("label `{}` is already defined", label)).with_hint(hint));
+        } else if let Some(constant) = self.constants.get(&key) {
+            let hint = format!("defined on {}", self.place(constant.line, line));
+            self.error(line, span.error(&format!("`{}` is already defined as a constant", label)).with_hint(hint));
+        } else {
+            let statement = self.statements.len();
+            self.labels.insert(key.clone(), Label { name: label, line, statement });
+            self.label_order.push(key);
+        }
+    }
+
+    // A constant, worked out now from numbers and the constants before it, so it can be
+    // used in conditions and keeps a number's width
+    fn define_constant(&mut self, line: usize, name: Option<&Token>, directive: Span, value: &[Token], length: usize) -> Result<(), LineError> {
+        let (name, span) = match name {
+            Some(token @ Token { kind: TokenKind::Ident(name), .. }) if !name.starts_with('.') => (name.clone(), Span::of(token)),
+            Some(token) => return Err(Span::of(token).error(&format!("expected a name for the constant, found {}", token.kind))),
+            None => return Err(directive.error("expected a name for the constant")),
+        };
+        if value.is_empty() {
+            return Err(span.error(&format!("expected a value for `{}`", name)));
+        }
+        let expr = parse_expr(value, length)?;
+        if expr.uses_pc() {
+            return Err(expr.span.error("`*` can't be used in a constant")
+                .with_hint("constants are worked out before anything has an address".to_string()));
+        }
+        let value = expr.eval(0, &|name| self.constants.get(name).map(|constant| constant.value)).map_err(|error| {
+            error.with_hint("constants can only use numbers and the constants defined before them".to_string())
+        })?;
+
+        let key = name.to_lowercase();
+        if let Some(first) = self.constants.get(&key) {
+            let hint = format!("first defined on {}", self.place(first.line, line));
+            return Err(span.error(&format!("constant `{}` is already defined", name)).with_hint(hint));
+        }
+        if let Some(label) = self.labels.get(&key) {
+            let hint = format!("defined on {}", self.place(label.line, line));
+            return Err(span.error(&format!("`{}` is already defined as a label", name)).with_hint(hint));
+        }
+        self.constants.insert(key, Constant { value, wide: expr.is_wide(), line });
+        Ok(())
+    }
+
+    // Replace the names of constants with their values, leaving alone any label being
+    // defined and the mnemonic or directive (the tokens up to `head`). `a`, `x` and `y`
+    // are left as well, as they can be registers; they're looked up as labels are.
+    fn substitute(&self, tokens: Vec<Token>, head: usize) -> Vec<Token> {
+        tokens.into_iter().enumerate()
+            .map(|(index, token)| match token.kind {
+                TokenKind::Ident(ref name) if index > head && !["a", "x", "y"].contains(&name.to_lowercase().as_str()) => {
+                    match self.constants.get(&name.to_lowercase()) {
+                        Some(constant) => Token { kind: TokenKind::Number { value: constant.value, wide: constant.wide }, ..token },
+                        None => token,
+                    }
+                },
+                _ => token,
+            })
+            .collect()
+    }
+
+    // Whether lines are assembled, rather than left out by a condition
+    fn active(&self) -> bool {
+        self.conditions.iter().all(|condition| condition.active)
+    }
+
+    // `.if`, `.ifdef`, `.ifndef`, `.elseif`, `.else` and `.endif`, `args` being the tokens
+    // after the directive. Conditions are worked out now, so they can only use numbers and
+    // what's been defined before them, not label addresses.
+    fn condition(&mut self, line: usize, name: &str, span: Span, args: &[Token], length: usize) -> Result<(), Option<LineError>> {
+        // a condition inside one that's left out is left out too, without being worked out
+        let enclosing = self.active();
+        match name {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let mut condition = Condition { line, active: false, taken: true, else_line: None };
+                if enclosing {
+                    let value = self.test(name, span, args, length);
+                    condition.active = *value.as_ref().unwrap_or(&false);
+                    condition.taken = condition.active;
+                    self.conditions.push(condition);
+                    value.map(|_| ()).map_err(Some)
+                } else {
+                    self.conditions.push(condition);
+                    Ok(())
+                }
+            },
+            ".elseif" | ".else" | ".endif" => {
+                let (taken, else_line) = match self.conditions.last() {
+                    Some(condition) => (condition.taken, condition.else_line),
+                    None => return Err(Some(span.error(&format!("`{}` without `.if`", name)))),
+                };
+                let outer = self.conditions[..self.conditions.len() - 1].iter().all(|condition| condition.active);
+                if name == ".endif" {
+                    self.conditions.pop();
+                    return if args.is_empty() { Ok(()) } else { Err(Some(Span::of(&args[0]).error("`.endif` doesn't take anything"))) };
+                }
+                if let Some(else_line) = else_line {
+                    let hint = format!("the `.else` is on {}", self.place(else_line, line));
+                    return Err(Some(span.error(&format!("`{}` after `.else`", name)).with_hint(hint)));
+                }
+
+                let value = if name == ".else" {
+                    self.conditions.last_mut().unwrap().else_line = Some(line);
+                    if args.is_empty() { Ok(true) } else { Err(Span::of(&args[0]).error("`.else` doesn't take anything")) }
+                } else if outer && !taken {
+                    self.test(".if", span, args, length)
+                } else {
+                    Ok(false)
+                };
+                let condition = self.conditions.last_mut().unwrap();
+                condition.active = outer && !taken && *value.as_ref().unwrap_or(&false);
+                condition.taken |= condition.active;
+                value.map(|_| ()).map_err(Some)
+            },
+            _ => Err(None),
+        }
+    }
+
+    // Whether a condition holds: `.if` an expression that isn't zero, `.ifdef` a label,
+    // constant or macro defined before it and `.ifndef` one that isn't
+    fn test(&self, name: &str, span: Span, args: &[Token], length: usize) -> Result<bool, LineError> {
+        if name == ".if" {
+            let expr = parse_expr(args, length).map_err(|error| match args.is_empty() {
+                true => span.error("expected a condition, like `.if DEBUG`"),
+                false => error,
+            })?;
+            let value = expr.eval(0, &|name| self.constants.get(name).map(|constant| constant.value)).map_err(|error| {
+                error.with_hint("conditions are worked out before labels have addresses, so they can only use constants".to_string())
+            })?;
+            return Ok(value != 0);
+        }
+        let defined = match args {
+            [Token { kind: TokenKind::Ident(symbol), .. }] => {
+                let key = symbol.to_lowercase();
+                self.labels.contains_key(&key) || self.constants.contains_key(&key) || self.macros.contains_key(&key)
+            },
+            [] => return Err(span.error(&format!("expected a name, like `{} DEBUG`", name))),
+            [token, ..] => return Err(Span::of(token).error(&format!("`{}` takes one name", name))),
+        };
+        Ok(defined == (name == ".ifdef"))
+    }
+
+    // `.include "file"`, looked for next to the file it's in and then in the include paths,
+    // puts the file's lines next
+    fn include(&mut self, line: usize, directive: Directive, pending: &mut Vec<SourceLine>) -> Result<(), LineError> {
+        let (path, path_span) = match directive.args.as_slice() {
+            [Arg::Str(path, span)] => (path.clone(), *span),
+            _ => {
+                let span = directive.args.first().map_or(directive.span, |arg| arg.span());
+                return Err(span.error("expected a file name in quotes").with_hint("`.include \"file\"`".to_string()));
+            },
+        };
+
+        let file = self.lines[line].file;
+        let here = Path::new(&self.files[file].path).parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        let directories: Vec<PathBuf> = std::iter::once(here).chain(self.include_paths.iter().cloned()).collect();
+        let full_path = match directories.iter().map(|directory| directory.join(&path)).find(|full_path| full_path.is_file()) {
+            Some(full_path) => full_path,
+            None => {
+                let looked: Vec<String> = directories.iter().map(|directory| format!("`{}`", directory.display())).collect();
+                let hint = format!("looked in {}; add directories to look in with `-I`", looked.join(", "));
+                return Err(path_span.error(&format!("can't find `{}`", path)).with_hint(hint));
+            },
+        };
+        let canonical = fs::canonicalize(&full_path).unwrap_or_else(|_| full_path.clone());
+
+        // a file can't include itself, even by way of another
+        let mut ancestor = Some(file);
+        while let Some(index) = ancestor {
+            if self.files[index].canonical == canonical {
+                let hint = "files can't include themselves, even by way of other files".to_string();
+                return Err(path_span.error(&format!("`{}` is already being included", path)).with_hint(hint));
+            }
+            ancestor = self.files[index].included_from.map(|(from, _)| self.lines[from].file);
+        }
+
+        let source = fs::read_to_string(&full_path)
+            .map_err(|error| path_span.error(&format!("can't read `{}`: {}", full_path.display(), error)))?;
+        let index = self.files.len();
+        self.files.push(SourceFile { path: full_path.display().to_string(), canonical, included_from: Some((line, path_span)) });
+        let lines: Vec<&str> = source.lines().collect();
+        pending.extend(lines.into_iter().enumerate().rev()
+            .map(|(number, text)| SourceLine { text: text.to_string(), file: index, line_number: number + 1, expansion: None }));
+        Ok(())
+    }
+
+    fn directive(&self, line: usize, directive: Directive) -> Result<Item, LineError> {
+        let Directive { name, span, args } = directive;
+        let usage = match name.as_str() {
+            ".org" => "`.org address`",
+            ".byte" | ".db" | ".text" | ".asciiz" => "a list of bytes and strings, like `.byte 1, \"two\", 3`",
+            ".word" | ".dw" => "a list of 16 bit values, like `.word 1, label`",
+            ".fill" | ".res" => "`.fill count` or `.fill count, value`",
+            ".align" => "`.align boundary` or `.align boundary, value`",
+            ".incbin" => "`.incbin \"file\"`, `.incbin \"file\", offset` or `.incbin \"file\", offset, length`",
+            _ => return Err(span.error(&format!("unknown directive `{}`", name))),
+        };
+        let usage_error = |message: &str| span.error(message).with_hint(format!("`{}` takes {}", name, usage));
+
+        match name.as_str() {
+            ".byte" | ".db" | ".text" | ".asciiz" => {
+                if args.is_empty() && name != ".asciiz" {
+                    return Err(usage_error("expected at least one value"));
+                }
+                Ok(Item::Bytes(args, name == ".asciiz"))
+            },
+            ".incbin" => self.incbin(line, args, span).map_err(|error| match error.hint {
+                Some(_) => error,
+                None => error.with_hint(format!("`{}` takes {}", name, usage)),
+            }),
+            _ => {
+                let (min, max) = match name.as_str() {
+                    ".org" => (1, 1),
+                    ".word" | ".dw" => (1, usize::MAX),
+                    _ => (1, 2),
+                };
+                if args.len() < min || args.len() > max {
+                    return Err(usage_error(&format!("wrong number of arguments to `{}`", name)));
+                }
+                let mut exprs = Vec::new();
+                for arg in args {
+                    match arg {
+                        Arg::Expr(expr) => exprs.push(expr),
+                        Arg::Str(_, span) => {
+                            return Err(span.error("expected a number, found a string").with_hint(format!("`{}` takes {}", name, usage)));
+                        },
+                    }
+                }
+                let mut exprs = exprs.into_iter();
+                let first = exprs.next().expect("there's at least one argument");
+                Ok(match name.as_str() {
+                    ".org" => Item::Org(first),
+                    ".fill" | ".res" => Item::Fill(first, exprs.next()),
+                    ".align" => Item::Align(first, exprs.next()),
+                    _ => Item::Words(std::iter::once(first).chain(exprs).collect()),
+                })
+            },
+        }
+    }
+
+    // `.incbin "file", offset, length` reads the file now, relative to this one, as its
+    // size has to be known
+    fn incbin(&self, line: usize, args: Vec<Arg>, span: Span) -> Result<Item, LineError> {
+        let mut args = args.into_iter();
+        let (path, path_span) = match args.next() {
+            Some(Arg::Str(path, span)) => (path, span),
+            Some(arg) => return Err(arg.span().error("expected a file name in quotes")),
+            None => return Err(span.error("expected a file name")),
+        };
+        let mut numbers = Vec::new();
+        for arg in args {
+            match arg {
+                Arg::Expr(expr) => numbers.push(expr.eval(0, &|_| None).map_err(|error| {
+                    error.with_hint("the offset and length have to be known before any labels".to_string())
+                })?),
+                Arg::Str(_, span) => return Err(span.error("expected a number, found a string")),
+            }
+        }
+        if numbers.len() > 2 {
+            return Err(span.error("wrong number of arguments to `.incbin`"));
+        }
+
+        let file = &self.files[self.lines[line].file].path;
+        let full_path = Path::new(file).parent().unwrap_or_else(|| Path::new("")).join(&path);
+        let data = fs::read(&full_path)
+            .map_err(|error| path_span.error(&format!("can't read `{}`: {}", full_path.display(), error)))?;
+        let offset = numbers.first().cloned().unwrap_or(0);
+        let length = numbers.get(1).cloned().unwrap_or(data.len() as i64 - offset);
+        if offset < 0 || offset > data.len() as i64 {
+            return Err(path_span.error(&format!("offset {} is outside `{}`, which is {} bytes", offset, path, data.len())));
+        }
+        if length < 0 || offset + length > data.len() as i64 {
+            return Err(path_span.error(&format!("{} bytes from offset {} runs past the end of `{}`, which is {} bytes", length, offset, path, data.len())));
+        }
+        Ok(Item::Binary(data[offset as usize..(offset + length) as usize].to_vec()))
+    }
+
+    // Give every statement its address. Operands that could be zero page start out that
+    // way, and are widened to absolute whenever they don't fit, which moves everything
+    // after them. `.org`, `.fill` and `.align` can depend on labels too, so this repeats
+    // until nothing moves. Widening never goes back, so it settles unless the directives
+    // keep it from it.
+    fn layout(&mut self) {
+        for _ in 0..MAX_LAYOUT_PASSES {
+            let mut moved = false;
+            let mut address = self.start_mem_address;
+            for index in 0..self.statements.len() {
+                moved |= self.statements[index].address != address;
+                self.statements[index].address = address;
+                address = self.next_address(&self.statements[index]);
+            }
+            moved |= self.end != address;
+            self.end = address;
+
+            let too_wide: Vec<usize> = self.statements.iter().enumerate()
+                .filter(|(_, statement)| match statement.item {
+                    Item::Instruction { ref instruction, flexible: true, .. } => {
+                        let value = operand(instruction).map(|expr| self.value(expr, statement.address));
+                        !matches!(value, Some(Ok(value)) if (0..=0xff).contains(&value))
+                    },
+                    _ => false,
+                })
+                .map(|(index, _)| index)
+                .collect();
+            if too_wide.is_empty() && !moved {
+                return;
+            }
+            for index in too_wide {
+                if let Item::Instruction { ref instruction, ref mut mode, ref mut opcode, ref mut flexible } = self.statements[index].item {
+                    *mode = mode.widened().expect("flexible modes are zero page");
+                    *opcode = encode::opcode(&instruction.mnemonic, *mode).expect("flexible modes widen");
+                    *flexible = false;
+                }
+            }
+        }
+        self.diagnostics.push(Diagnostic::new(&self.files[0].path, "addresses never settle, as `.org`, `.fill` or `.align` depend on labels they move"));
+    }
+
+    // Where the statement after this one goes. Sizes that can't be worked out yet count
+    // as nothing, and are reported by the second pass if they still can't.
+    fn next_address(&self, statement: &Statement) -> u16 {
+        let address = statement.address;
+        let size = match statement.item {
+            Item::Instruction { mode, .. } => mode.size() as i64,
+            Item::Bytes(ref args, terminated) => {
+                let length: usize = args.iter().map(|arg| match arg {
+                    Arg::Str(text, _) => text.len(),
+                    Arg::Expr(_) => 1,
+                }).sum();
+                (length + terminated as usize) as i64
+            },
+            Item::Words(ref exprs) => 2 * exprs.len() as i64,
+            Item::Org(ref expr) => return self.value(expr, address).map_or(address, |value| value as u16),
+            Item::Fill(ref count, _) => self.value(count, address).unwrap_or(0).max(0),
+            Item::Align(ref boundary, _) => match self.value(boundary, address) {
+                Ok(boundary) if boundary > 0 => padding(address, boundary),
+                _ => 0,
+            },
+            Item::Binary(ref data) => data.len() as i64,
+        };
+        address.wrapping_add(size as u16)
+    }
+
+    // Now labels are known, work out operands and emit the bytes. Gaps left by `.org`
+    // are filled with zeros.
+    fn second_pass(&mut self) -> Program {
+        let mut origin = None;
+        let mut output_bin_bytes: Vec<u8> = Vec::new();
+        let mut debug_map: Vec<(u16, String, u16)> = Vec::new();
+
+        let mut errors = Vec::new();
+        for statement in &self.statements {
+            if let Item::Instruction { .. } = statement.item {
+                let source = &self.lines[statement.line];
+                debug_map.push((statement.address, self.files[source.file].path.clone(), source.line_number as u16));
+            }
+            let bytes = match self.encode(statement) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    errors.push((statement.line, error));
+                    continue;
+                }
+            };
+            if bytes.is_empty() {
+                continue;
+            }
+            let origin = *origin.get_or_insert(statement.address);
+            let end = origin as usize + output_bin_bytes.len();
+            if (statement.address as usize) < end {
+                let message = if statement.address < origin {
+                    format!("this would go at ${:04x}, before the start at ${:04x}", statement.address, origin)
+                } else {
+                    format!("this would overwrite ${:04x}, which is already assembled", statement.address)
+                };
+                let error = statement.span.error(&message);
+                errors.push((statement.line, error.with_hint("`.org` can only move forward".to_string())));
+                continue;
+            }
+            output_bin_bytes.resize(statement.address as usize - origin as usize, 0);
+            output_bin_bytes.extend(bytes);
+            if origin as usize + output_bin_bytes.len() > 0x10000 {
+                errors.push((statement.line, statement.span.error("this runs past $ffff")));
+            }
+        }
+        for (line, error) in errors {
+            self.error(line, error);
+        }
+
+        let symbols = self.label_order.iter()
+            .map(|key| (self.labels[key].name.clone(), self.label_address(&self.labels[key])))
+            .collect();
+        Program {
+            origin: origin.unwrap_or(self.start_mem_address),
+            bytes: output_bin_bytes,
+            debug_map,
+            symbols,
+        }
+    }
+
+    fn label_address(&self, label: &Label) -> u16 {
+        self.statements.get(label.statement).map_or(self.end, |statement| statement.address)
+    }
+
+    // Evaluate an expression in the statement at `address`
+    fn value(&self, expr: &Expr, address: u16) -> Result<i64, LineError> {
+        expr.eval(address as i64, &|name| {
+            self.labels.get(name).map(|label| self.label_address(label) as i64)
+                .or_else(|| self.constants.get(name).map(|constant| constant.value))
+        })
+    }
+
+    // A byte, where negative numbers are two's complement
+    fn byte(&self, expr: &Expr, address: u16) -> Result<u8, LineError> {
+        let value = self.value(expr, address)?;
+        if !(-0x80..=0xff).contains(&value) {
+            return Err(expr.span.error(&format!("${:x} does not fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    // How many bytes `.fill` writes or `.align` pads to, checked
+    fn count(&self, expr: &Expr, address: u16, name: &str) -> Result<i64, LineError> {
+        let value = self.value(expr, address)?;
+        if !(0..=0xffff).contains(&value) {
+            return Err(expr.span.error(&format!("{} has to be between 0 and $ffff, not {}", name, value)));
+        }
+        Ok(value)
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, LineError> {
+        let address = statement.address;
+        let mut bytes = Vec::new();
+        match statement.item {
+            Item::Instruction { ref instruction, mode, opcode, .. } => return self.encode_instruction(instruction, mode, opcode, address),
+            Item::Bytes(ref args, terminated) => {
+                for arg in args {
+                    match arg {
+                        Arg::Str(text, _) => bytes.extend(text.bytes()),
+                        Arg::Expr(expr) => bytes.push(self.byte(expr, address)?),
+                    }
+                }
+                if terminated {
+                    bytes.push(0);
+                }
+            },
+            Item::Words(ref exprs) => {
+                for expr in exprs {
+                    let value = self.value(expr, address)?;
+                    if !(-0x8000..=0xffff).contains(&value) {
+                        return Err(expr.span.error(&format!("${:x} does not fit in 16 bits", value)));
+                    }
+                    bytes.extend(&u16_to_two_u8s(value as u16));
+                }
+            },
+            Item::Org(ref expr) => {
+                let value = self.value(expr, address)?;
+                if !(0..=0xffff).contains(&value) {
+                    return Err(expr.span.error(&format!("${:x} is not a 16 bit address", value)));
+                }
+            },
+            Item::Fill(ref count, ref value) => {
+                let count = self.count(count, address, "the count")?;
+                let value = value.as_ref().map_or(Ok(0), |value| self.byte(value, address))?;
+                bytes.resize(count as usize, value);
+            },
+            Item::Align(ref boundary, ref value) => {
+                let boundary_value = self.count(boundary, address, "the boundary")?;
+                if boundary_value == 0 {
+                    return Err(boundary.span.error("the boundary can't be 0"));
+                }
+                let value = value.as_ref().map_or(Ok(0), |value| self.byte(value, address))?;
+                bytes.resize(padding(address, boundary_value) as usize, value);
+            },
+            Item::Binary(ref data) => bytes.extend(data),
+        }
+        Ok(bytes)
+    }
+
+    fn encode_instruction(&self, instruction: &Instruction, mode: Mode, opcode: u8, address: u16) -> Result<Vec<u8>, LineError> {
+        let mut bytes = vec![opcode];
+        let expr = match operand(instruction) {
+            _ if mode == Mode::Accumulator => return Ok(bytes),
+            None => return Ok(bytes),
+            Some(expr) => expr,
+        };
+
+        match mode {
+            Mode::Relative => {
+                let value = self.value(expr, address)?;
+                let offset = value - (address as i64 + 2);
+                if !(-128..=127).contains(&offset) {
+                    let hint = format!("branches reach 128 bytes back or 127 forward, this one is {} bytes", offset);
+                    return Err(expr.span.error("branch target is out of range").with_hint(hint));
+                }
+                bytes.push(offset as u8);
+            },
+            Mode::Immediate => bytes.push(self.byte(expr, address)?),
+            mode if mode.size() == 2 => {
+                let value = self.value(expr, address)?;
+                if !(0..=0xff).contains(&value) {
+                    let hint = match instruction.width {
+                        Some(Width::ZeroPage) => "`.z` forces zero page".to_string(),
+                        _ => format!("{} only takes a zero page address here", instruction.mnemonic.to_uppercase()),
+                    };
+                    return Err(expr.span.error(&format!("${:x} is not in zero page", value)).with_hint(hint));
+                }
+                bytes.push(value as u8);
+            },
+            _ => {
+                let value = self.value(expr, address)?;
+                if !(0..=0xffff).contains(&value) {
+                    return Err(expr.span.error(&format!("${:x} is not a 16 bit address", value)));
+                }
+                bytes.extend(&u16_to_two_u8s(value as u16));
+            },
+        }
+        Ok(bytes)
+    }
+}
+
+// How far `address` is from the next multiple of `boundary`
+fn padding(address: u16, boundary: i64) -> i64 {
+    (boundary - address as i64 % boundary) % boundary
+}
+
+// The expression an instruction's operand has, if it has one
+fn operand(instruction: &Instruction) -> Option<&Expr> {
+    match instruction.operand {
+        Operand::None => None,
+        Operand::Immediate(ref expr)
+        | Operand::Direct(ref expr, _)
+        | Operand::Indirect(ref expr)
+        | Operand::IndexedIndirect(ref expr)
+        | Operand::IndirectIndexed(ref expr) => Some(expr),
+    }
+}
+
+// note endianess
+fn u16_to_two_u8s(arg: u16) -> [u8; 2] {
+    [arg as u8, (arg >> 8) as u8]
+}
+
+// Pick the addressing mode an instruction's operand means. When it could be zero page or
+// absolute, and the operand isn't a number written wide like `$0010` or the size forced
+// with `.z` or `.w`, it's zero page for now and flexible.
+fn select_mode(instruction: &Instruction) -> Result<(Mode, bool), LineError> {
+    let mnemonic = &instruction.mnemonic;
+    let name = mnemonic.to_uppercase();
+    let modes = encode::modes(mnemonic);
+    if modes.is_empty() {
+        return Err(instruction.span.error(&format!("unknown instruction `{}`", mnemonic)));
+    }
+    let supports = |mode| modes.contains(&mode);
+    let mut flexible = false;
+
+    let mode = match instruction.operand {
+        Operand::None if supports(Mode::Implied) => Mode::Implied,
+        Operand::None if supports(Mode::Accumulator) => Mode::Accumulator,
+        Operand::None => {
+            let error = instruction.span.error(&format!("{} needs an operand", name));
+            return Err(error.with_hint(format!("{} supports {}", name, list_modes(&modes))));
+        },
+        _ if modes == [Mode::Implied] => {
+            return Err(instruction.operand_span.error(&format!("{} does not take an operand", name)));
+        },
+        Operand::Immediate(_) => Mode::Immediate,
+        Operand::Direct(_, Index::None) if supports(Mode::Relative) => Mode::Relative,
+        Operand::Direct(ref expr, Index::None) if expr.is_label("a") && supports(Mode::Accumulator) => Mode::Accumulator,
+        Operand::Direct(ref expr, index) => {
+            let (zero_page, absolute) = match index {
+                Index::None => (Mode::ZeroPage, Mode::Absolute),
+                Index::X => (Mode::ZeroPageX, Mode::AbsoluteX),
+                Index::Y => (Mode::ZeroPageY, Mode::AbsoluteY),
+            };
+            match instruction.width {
+                Some(Width::ZeroPage) => zero_page,
+                Some(Width::Absolute) => absolute,
+                None if supports(zero_page) && supports(absolute) => {
+                    flexible = !expr.is_wide();
+                    if flexible { zero_page } else { absolute }
+                },
+                None if supports(zero_page) => zero_page,
+                None if supports(absolute) || expr.is_wide() => absolute,
+                None => zero_page,
+            }
+        },
+        Operand::Indirect(_) => Mode::Indirect,
+        Operand::IndexedIndirect(_) => Mode::IndexedIndirect,
+        Operand::IndirectIndexed(_) => Mode::IndirectIndexed,
+    };
+
+    // `.z` and `.w` only mean something when there's an address
+    if let Some(width) = instruction.width {
+        if mode.widened().is_none() && !matches!(mode, Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY) {
+            let suffix = if width == Width::ZeroPage { ".z" } else { ".w" };
+            return Err(instruction.span.error(&format!("`{}` only applies to zero page or absolute operands", suffix)));
+        }
+    }
+
+    match encode::opcode(mnemonic, mode) {
+        Some(_) => Ok((mode, flexible)),
+        None => {
+            let hint = format!("{} does not support {}; it supports {}", name, mode, list_modes(&modes));
+            Err(instruction.operand_span.error(&format!("invalid addressing mode for {}", name)).with_hint(hint))
+        },
+    }
+}
+
+fn list_modes(modes: &[Mode]) -> String {
+    let names: Vec<String> = modes.iter().map(|mode| mode.to_string()).collect();
+    match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        _ => names.join(""),
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+
+/// An error found on one line, before it's known which file and line that is
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineError {
+    // byte offset and length of the offending text
+    pub column: usize,
+    pub length: usize,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl LineError {
+    pub fn new(column: usize, length: usize, message: &str) -> LineError {
+        LineError {
+            column,
+            length,
+            message: message.to_string(),
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: String) -> LineError {
+        self.hint = Some(hint);
+        self
+    }
+}
+
+/// Where in a source file something went wrong
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    // 1-based, in bytes
+    pub column: usize,
+    pub length: usize,
+    // the text of the line, to point at
+    pub text: String,
+}
+
+impl Location {
+    /// `length` bytes from byte offset `column` on line `line` (1-based) of a file
+    pub fn new(file: &str, line: usize, text: &str, column: usize, length: usize) -> Location {
+        Location {
+            file: file.to_string(),
+            line,
+            column: column + 1,
+            length: length.max(1),
+            text: text.to_string(),
+        }
+    }
+
+    // `file:line:column: kind: message`, the line, and a caret under the text
+    fn write(&self, f: &mut fmt::Formatter, kind: &str, message: &str) -> fmt::Result {
+        writeln!(f, "{}:{}:{}: {}: {}", self.file, self.line, self.column, kind, message)?;
+        writeln!(f, "    {}", self.text)?;
+
+        // keep tabs so the caret lines up under the text
+        let indent: String = self.text.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "    {}{}", indent, "^".repeat(self.length))
+    }
+}
+
+/// Somewhere else an error has to do with, like the macro invocation a line came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub location: Location,
+}
+
+/// An error in a source file, shown as `file:line:column: error: message`, then the line
+/// with a caret under the offending text, a hint at how to fix it if there is one, and
+/// any notes the same way
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub location: Option<Location>,
+    pub hint: Option<String>,
+    pub notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    /// An error about a whole file, like one that couldn't be read
+    pub fn new(file: &str, message: &str) -> Diagnostic {
+        Diagnostic {
+            message: format!("{}: {}", file, message),
+            location: None,
+            hint: None,
+            notes: Vec::new(),
+        }
+    }
+
+    /// An error at `error.column` on line `line` (1-based) of a file, whose text is `text`
+    pub fn at(file: &str, line: usize, text: &str, error: LineError) -> Diagnostic {
+        Diagnostic {
+            message: error.message,
+            location: Some(Location::new(file, line, text, error.column, error.length)),
+            hint: error.hint,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, message: &str, location: Location) -> Diagnostic {
+        self.notes.push(Note { message: message.to_string(), location });
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(ref location) => location.write(f, "error", &self.message)?,
+            None => write!(f, "error: {}", self.message)?,
+        }
+        if let Some(ref hint) = self.hint {
+            write!(f, "\n    = hint: {}", hint)?;
+        }
+        for note in &self.notes {
+            writeln!(f)?;
+            note.location.write(f, "note", &note.message)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let error = LineError::new(6, 7, "invalid addressing mode for LDX")
+            .with_hint("LDX does not support (zp),Y".to_string());
+        let diagnostic = Diagnostic::at("test.6502asm", 3, "\tldx  ($01),y", error);
+        assert_eq!(diagnostic.to_string(), "test.6502asm:3:7: error: invalid addressing mode for LDX\n\
+                                            \x20   \tldx  ($01),y\n\
+                                            \x20   \t     ^^^^^^^\n\
+                                            \x20   = hint: LDX does not support (zp),Y");
+
+        let invocation = Location::new("test.6502asm", 9, "  load $10, $01", 2, 4);
+        let diagnostic = diagnostic.with_note("in this expansion of `load`", invocation);
+        assert_eq!(diagnostic.to_string(), "test.6502asm:3:7: error: invalid addressing mode for LDX\n\
+                                            \x20   \tldx  ($01),y\n\
+                                            \x20   \t     ^^^^^^^\n\
+                                            \x20   = hint: LDX does not support (zp),Y\n\
+                                            test.6502asm:9:3: note: in this expansion of `load`\n\
+                                            \x20     load $10, $01\n\
+                                            \x20     ^^^^");
+
+        let diagnostic = Diagnostic::new("missing.6502asm", "No such file or directory");
+        assert_eq!(diagnostic.to_string(), "error: missing.6502asm: No such file or directory");
+    }
+}
@@ -1,212 +1,191 @@
-use regex::{Regex, Captures};
-
-pub fn get_opcode_and_arguments(line: String,
-                                line_number: u16,
-                                compiled_patterns: &Vec<(Regex, u8, &'static str)>) -> 
-                                (u8, String, &'static str) {
-
-    for (re, opcode, instr_type) in compiled_patterns{
-        if re.is_match(&line) {
-            let caps =  re.captures(&line).unwrap();
-            let group1 = caps.get(1).map_or("", |m| m.as_str());
-            return (*opcode, group1.to_string(), instr_type);
+use std::fmt;
+
+/// The ways an instruction can address its operand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect,
+    IndirectIndexed,
+    Relative,
+}
+
+impl Mode {
+    /// Bytes taken by an instruction in this mode, opcode included
+    pub fn size(self) -> u16 {
+        match self {
+            Mode::Implied | Mode::Accumulator => 1,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 3,
+            _ => 2,
         }
     }
-    // If the function hasn't returned by now, there is a problem
-    panic!("the line {}, \"{}\" was not a valid instruction.", line_number, line);
-}
 
+    /// The absolute mode a zero page one widens to, if it's one of them
+    pub fn widened(self) -> Option<Mode> {
+        match self {
+            Mode::ZeroPage => Some(Mode::Absolute),
+            Mode::ZeroPageX => Some(Mode::AbsoluteX),
+            Mode::ZeroPageY => Some(Mode::AbsoluteY),
+            _ => None,
+        }
+    }
+}
 
-pub fn compile_patterns() -> Vec<(Regex, u8, &'static str)> {
-
-    let mut patterns: Vec<(&str, u8, &'static str)> = Vec::new();
-
-    // all the impls and As
-    patterns.push((r"^brk$", 0x00, "no_arg"));
-    patterns.push((r"^rti$", 0x40, "no_arg"));
-    patterns.push((r"^rts$", 0x60, "no_arg"));
-    patterns.push((r"^php$", 0x08, "no_arg"));
-    patterns.push((r"^clc$", 0x18, "no_arg"));
-    patterns.push((r"^plp$", 0x28, "no_arg"));
-    patterns.push((r"^sec$", 0x38, "no_arg"));
-    patterns.push((r"^pha$", 0x48, "no_arg"));
-    patterns.push((r"^cli$", 0x58, "no_arg"));
-    patterns.push((r"^pla$", 0x68, "no_arg"));
-    patterns.push((r"^sei$", 0x78, "no_arg"));
-    patterns.push((r"^dey$", 0x88, "no_arg"));
-    patterns.push((r"^tya$", 0x98, "no_arg"));
-    patterns.push((r"^tay$", 0xa8, "no_arg"));
-    patterns.push((r"^clv$", 0xb8, "no_arg"));
-    patterns.push((r"^iny$", 0xc8, "no_arg"));
-    patterns.push((r"^cld$", 0xd8, "no_arg"));
-    patterns.push((r"^inx$", 0xe8, "no_arg"));
-    patterns.push((r"^sed$", 0xf8, "no_arg"));
-    patterns.push((r"^txa$", 0x8a, "no_arg"));
-    patterns.push((r"^txs$", 0x9a, "no_arg"));
-    patterns.push((r"^tax$", 0xaa, "no_arg"));
-    patterns.push((r"^tsx$", 0xba, "no_arg"));
-    patterns.push((r"^dex$", 0xca, "no_arg"));
-    patterns.push((r"^nop$", 0xea, "no_arg"));
-
-    patterns.push((r"^asl$", 0x0a, "no_arg"));
-    patterns.push((r"^rol$", 0x2a, "no_arg"));
-    patterns.push((r"^lsr$", 0x4a, "no_arg"));
-    patterns.push((r"^ror$", 0x6a, "no_arg"));
-
-
-    // rels (relative label)
-    patterns.push((r"^bpl\s+([a-zA-Z]\w*)$", 0x10, "label_rel"));
-    patterns.push((r"^bmi\s+([a-zA-Z]\w*)$", 0x30, "label_rel"));
-    patterns.push((r"^bvc\s+([a-zA-Z]\w*)$", 0x50, "label_rel"));
-    patterns.push((r"^bvs\s+([a-zA-Z]\w*)$", 0x70, "label_rel"));
-    patterns.push((r"^bcc\s+([a-zA-Z]\w*)$", 0x90, "label_rel"));
-    patterns.push((r"^bcs\s+([a-zA-Z]\w*)$", 0xb0, "label_rel"));
-    patterns.push((r"^bne\s+([a-zA-Z]\w*)$", 0xd0, "label_rel"));
-    patterns.push((r"^beq\s+([a-zA-Z]\w*)$", 0xf0, "label_rel"));
-
-    // rels unlabeled (relative offset)
-    patterns.push((r"^bpl\s+\$([0-9a-f]{1,2})$", 0x10, "u8"));
-    patterns.push((r"^bmi\s+\$([0-9a-f]{1,2})$", 0x30, "u8"));
-    patterns.push((r"^bvc\s+\$([0-9a-f]{1,2})$", 0x50, "u8"));
-    patterns.push((r"^bvs\s+\$([0-9a-f]{1,2})$", 0x70, "u8"));
-    patterns.push((r"^bcc\s+\$([0-9a-f]{1,2})$", 0x90, "u8"));
-    patterns.push((r"^bcs\s+\$([0-9a-f]{1,2})$", 0xb0, "u8"));
-    patterns.push((r"^bne\s+\$([0-9a-f]{1,2})$", 0xd0, "u8"));
-    patterns.push((r"^beq\s+\$([0-9a-f]{1,2})$", 0xf0, "u8"));
-
-    // immediates
-    patterns.push((r"^ldy\s+#\$?([0-9a-f]{1,2})", 0xa0, "u8"));
-    patterns.push((r"^ldx\s+#\$?([0-9a-f]{1,2})", 0xa2, "u8"));
-    patterns.push((r"^cpy\s+#\$?([0-9a-f]{1,2})", 0xc0, "u8"));
-    patterns.push((r"^cpx\s+#\$?([0-9a-f]{1,2})", 0xe0, "u8"));
-    patterns.push((r"^ora\s+#\$?([0-9a-f]{1,2})", 0x09, "u8"));
-    patterns.push((r"^and\s+#\$?([0-9a-f]{1,2})", 0x29, "u8"));
-    patterns.push((r"^eor\s+#\$?([0-9a-f]{1,2})", 0x49, "u8"));
-    patterns.push((r"^adc\s+#\$?([0-9a-f]{1,2})", 0x69, "u8"));
-    patterns.push((r"^lda\s+#\$?([0-9a-f]{1,2})", 0xa9, "u8"));
-    patterns.push((r"^cmp\s+#\$?([0-9a-f]{1,2})", 0xc9, "u8"));
-    patterns.push((r"^sbc\s+#\$?([0-9a-f]{1,2})", 0xe9, "u8"));
-
-    // indirect
-    patterns.push((r"^ora\s+\(\$([0-9a-f]{1,2}),x\)$", 0x01, "u8"));
-    patterns.push((r"^ora\s+\(\$([0-9a-f]{1,2})\),y", 0x11, "u8"));
-    patterns.push((r"^and\s+\(\$([0-9a-f]{1,2}),x\)$", 0x21, "u8"));
-    patterns.push((r"^and\s+\(\$([0-9a-f]{1,2})\),y", 0x31, "u8"));
-    patterns.push((r"^eor\s+\(\$([0-9a-f]{1,2}),x\)$", 0x41, "u8"));
-    patterns.push((r"^eor\s+\(\$([0-9a-f]{1,2})\),y", 0x51, "u8"));
-    patterns.push((r"^adc\s+\(\$([0-9a-f]{1,2}),x\)$", 0x61, "u8"));
-    patterns.push((r"^adc\s+\(\$([0-9a-f]{1,2})\),y", 0x71, "u8"));
-    patterns.push((r"^sta\s+\(\$([0-9a-f]{1,2}),x\)$", 0x81, "u8"));
-    patterns.push((r"^sta\s+\(\$([0-9a-f]{1,2})\),y", 0x91, "u8"));
-    patterns.push((r"^lda\s+\(\$([0-9a-f]{1,2}),x\)$", 0xa1, "u8"));
-    patterns.push((r"^lda\s+\(\$([0-9a-f]{1,2})\),y", 0xb1, "u8"));
-    patterns.push((r"^cmp\s+\(\$([0-9a-f]{1,2}),x\)$", 0xc1, "u8"));
-    patterns.push((r"^cmp\s+\(\$([0-9a-f]{1,2})\),y", 0xd1, "u8"));
-    patterns.push((r"^sbc\s+\(\$([0-9a-f]{1,2}),x\)$", 0xe1, "u8"));
-    patterns.push((r"^sbc\s+\(\$([0-9a-f]{1,2})\),y", 0xf1, "u8"));
-
-    patterns.push((r"^jmp\s+\(\$([0-9a-f]{1,4})\)$",   0x6c, "u16"));
-
-    // zpgs
-    patterns.push((r"^ora\s+\$([0-9a-f]{1,2})$",    0x05, "u8"));
-    patterns.push((r"^ora\s+\$([0-9a-f]{1,2}),x$",  0x15, "u8"));
-    patterns.push((r"^and\s+\$([0-9a-f]{1,2})$",    0x25, "u8"));
-    patterns.push((r"^and\s+\$([0-9a-f]{1,2}),x$",  0x35, "u8"));
-    patterns.push((r"^eor\s+\$([0-9a-f]{1,2})$",    0x45, "u8"));
-    patterns.push((r"^eor\s+\$([0-9a-f]{1,2}),x$",  0x55, "u8"));
-    patterns.push((r"^adc\s+\$([0-9a-f]{1,2})$",    0x65, "u8"));
-    patterns.push((r"^adc\s+\$([0-9a-f]{1,2}),x$",  0x75, "u8"));
-    patterns.push((r"^sta\s+\$([0-9a-f]{1,2})$",    0x85, "u8"));
-    patterns.push((r"^sta\s+\$([0-9a-f]{1,2}),x$",  0x95, "u8"));
-    patterns.push((r"^lda\s+\$([0-9a-f]{1,2})$",    0xa5, "u8"));
-    patterns.push((r"^lda\s+\$([0-9a-f]{1,2}),x$",  0xb5, "u8"));
-    patterns.push((r"^cmp\s+\$([0-9a-f]{1,2})$",    0xc5, "u8"));
-    patterns.push((r"^cmp\s+\$([0-9a-f]{1,2}),x$",  0xd5, "u8"));
-    patterns.push((r"^sbc\s+\$([0-9a-f]{1,2})$",    0xe5, "u8"));
-    patterns.push((r"^sbc\s+\$([0-9a-f]{1,2}),x$",  0xf5, "u8"));
-    patterns.push((r"^asl\s+\$([0-9a-f]{1,2})$",    0x06, "u8"));
-    patterns.push((r"^asl\s+\$([0-9a-f]{1,2}),x$",  0x16, "u8"));
-    patterns.push((r"^rol\s+\$([0-9a-f]{1,2})$",    0x26, "u8"));
-    patterns.push((r"^rol\s+\$([0-9a-f]{1,2}),x$",  0x36, "u8"));
-    patterns.push((r"^lsr\s+\$([0-9a-f]{1,2})$",    0x46, "u8"));
-    patterns.push((r"^lsr\s+\$([0-9a-f]{1,2}),x$",  0x56, "u8"));
-    patterns.push((r"^ror\s+\$([0-9a-f]{1,2})$",    0x66, "u8"));
-    patterns.push((r"^ror\s+\$([0-9a-f]{1,2}),x$",  0x76, "u8"));
-    patterns.push((r"^stx\s+\$([0-9a-f]{1,2})$",    0x86, "u8"));
-    patterns.push((r"^stx\s+\$([0-9a-f]{1,2}),y$",  0x96, "u8"));
-    patterns.push((r"^ldx\s+\$([0-9a-f]{1,2})$",    0xa6, "u8"));
-    patterns.push((r"^ldx\s+\$([0-9a-f]{1,2}),y$",  0xb6, "u8"));
-    patterns.push((r"^dec\s+\$([0-9a-f]{1,2})$",    0xc6, "u8"));
-    patterns.push((r"^dec\s+\$([0-9a-f]{1,2}),x$",  0xd6, "u8"));
-    patterns.push((r"^inc\s+\$([0-9a-f]{1,2})$",    0xe6, "u8"));
-    patterns.push((r"^inc\s+\$([0-9a-f]{1,2}),x$",  0xf6, "u8"));
-    patterns.push((r"^sty\s+\$([0-9a-f]{1,2})$",    0x84, "u8"));
-    patterns.push((r"^sty\s+\$([0-9a-f]{1,2}),x$",  0x94, "u8"));
-    patterns.push((r"^ldy\s+\$([0-9a-f]{1,2})$",    0xa4, "u8"));
-    patterns.push((r"^ldy\s+\$([0-9a-f]{1,2}),x$",  0xb4, "u8"));
-    patterns.push((r"^bit\s+\$([0-9a-f]{1,2})$",    0x24, "u8"));
-    patterns.push((r"^cpy\s+\$([0-9a-f]{1,2})$",    0xc4, "u8"));
-    patterns.push((r"^cpx\s+\$([0-9a-f]{1,2})$",    0xe4, "u8"));
-    
-    // absolutes
-    patterns.push((r"^ora\s+\$([0-9a-f]{1,4})$",    0x0d, "u16"));
-    patterns.push((r"^ora\s+\$([0-9a-f]{1,4}),x$",  0x1d, "u16"));
-    patterns.push((r"^ora\s+\$([0-9a-f]{1,4}),y$",  0x19, "u16"));
-    patterns.push((r"^and\s+\$([0-9a-f]{1,4})$",    0x2d, "u16"));
-    patterns.push((r"^and\s+\$([0-9a-f]{1,4}),x$",  0x3d, "u16"));
-    patterns.push((r"^and\s+\$([0-9a-f]{1,4}),y$",  0x39, "u16"));
-    patterns.push((r"^eor\s+\$([0-9a-f]{1,4})$",    0x4d, "u16"));
-    patterns.push((r"^eor\s+\$([0-9a-f]{1,4}),x$",  0x5d, "u16"));
-    patterns.push((r"^eor\s+\$([0-9a-f]{1,4}),y$",  0x59, "u16"));
-    patterns.push((r"^adc\s+\$([0-9a-f]{1,4})$",    0x6d, "u16"));
-    patterns.push((r"^adc\s+\$([0-9a-f]{1,4}),x$",  0x7d, "u16"));
-    patterns.push((r"^adc\s+\$([0-9a-f]{1,4}),y$",  0x79, "u16"));
-    patterns.push((r"^sta\s+\$([0-9a-f]{1,4})$",    0x8d, "u16"));
-    patterns.push((r"^sta\s+\$([0-9a-f]{1,4}),x$",  0x9d, "u16"));
-    patterns.push((r"^sta\s+\$([0-9a-f]{1,4}),y$",  0x99, "u16"));
-    patterns.push((r"^lda\s+\$([0-9a-f]{1,4})$",    0xad, "u16"));
-    patterns.push((r"^lda\s+\$([0-9a-f]{1,4}),x$",  0xbd, "u16"));
-    patterns.push((r"^lda\s+\$([0-9a-f]{1,4}),y$",  0xb9, "u16"));
-    patterns.push((r"^cmp\s+\$([0-9a-f]{1,4})$",    0xcd, "u16"));
-    patterns.push((r"^cmp\s+\$([0-9a-f]{1,4}),x$",  0xdd, "u16"));
-    patterns.push((r"^cmp\s+\$([0-9a-f]{1,4}),y$",  0xd9, "u16"));
-    patterns.push((r"^sbc\s+\$([0-9a-f]{1,4})$",    0xed, "u16"));
-    patterns.push((r"^sbc\s+\$([0-9a-f]{1,4}),x$",  0xfd, "u16"));
-    patterns.push((r"^sbc\s+\$([0-9a-f]{1,4}),y$",  0xf9, "u16"));
-    patterns.push((r"^asl\s+\$([0-9a-f]{1,4})$",    0x0e, "u16"));
-    patterns.push((r"^asl\s+\$([0-9a-f]{1,4}),x$",  0x1e, "u16"));
-    patterns.push((r"^rol\s+\$([0-9a-f]{1,4})$",    0x2e, "u16"));
-    patterns.push((r"^rol\s+\$([0-9a-f]{1,4}),x$",  0x3e, "u16"));
-    patterns.push((r"^lsr\s+\$([0-9a-f]{1,4})$",    0x4e, "u16"));
-    patterns.push((r"^lsr\s+\$([0-9a-f]{1,4}),x$",  0x5e, "u16"));
-    patterns.push((r"^ror\s+\$([0-9a-f]{1,4})$",    0x6e, "u16"));
-    patterns.push((r"^ror\s+\$([0-9a-f]{1,4}),x$",  0x7e, "u16"));
-    patterns.push((r"^stx\s+\$([0-9a-f]{1,4})$",    0x8e, "u16"));
-    patterns.push((r"^ldx\s+\$([0-9a-f]{1,4})$",    0x9e, "u16"));
-    patterns.push((r"^ldx\s+\$([0-9a-f]{1,4}),y$",  0xae, "u16"));
-    patterns.push((r"^dec\s+\$([0-9a-f]{1,4})$",    0xbe, "u16"));
-    patterns.push((r"^dec\s+\$([0-9a-f]{1,4}),x$",  0xce, "u16"));
-    patterns.push((r"^inc\s+\$([0-9a-f]{1,4})$",    0xde, "u16"));
-    patterns.push((r"^inc\s+\$([0-9a-f]{1,4}),x$",  0xee, "u16"));
-    patterns.push((r"^sty\s+\$([0-9a-f]{1,4})$",    0x8c, "u16"));
-    patterns.push((r"^ldy\s+\$([0-9a-f]{1,4})$",    0xac, "u16"));
-    patterns.push((r"^ldy\s+\$([0-9a-f]{1,4}),x$",  0xbc, "u16"));
-    patterns.push((r"^bit\s+\$([0-9a-f]{1,4})$",    0x2c, "u16"));
-    patterns.push((r"^cpy\s+\$([0-9a-f]{1,4})$",    0xcc, "u16"));
-    patterns.push((r"^cpx\s+\$([0-9a-f]{1,4})$",    0xec, "u16"));
-
-    patterns.push((r"^jmp\s+\$([0-9a-f]{1,4})$",    0x4c, "u16"));
-    patterns.push((r"^jmp\s+([a-zA-Z]\w*)$",        0x4c, "label_abs"));
-
-    patterns.push((r"^jsr\s+\$([0-9a-f]{1,4})$",    0x20, "u16"));
-    patterns.push((r"^jsr\s+([a-zA-Z]\w+)$",        0x20, "label_abs"));
-
-    let mut compiled_patterns = Vec::new();
-    for (pattern, opcode, instr_type) in patterns {
-        let re = Regex::new(pattern).unwrap();
-        compiled_patterns.push((re, opcode, instr_type));
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Mode::Implied => "implied",
+            Mode::Accumulator => "A",
+            Mode::Immediate => "#imm",
+            Mode::ZeroPage => "zp",
+            Mode::ZeroPageX => "zp,X",
+            Mode::ZeroPageY => "zp,Y",
+            Mode::Absolute => "abs",
+            Mode::AbsoluteX => "abs,X",
+            Mode::AbsoluteY => "abs,Y",
+            Mode::Indirect => "(abs)",
+            Mode::IndexedIndirect => "(zp,X)",
+            Mode::IndirectIndexed => "(zp),Y",
+            Mode::Relative => "rel",
+        };
+        write!(f, "{}", name)
     }
+}
+
+// Every documented opcode, by mnemonic and addressing mode
+const OPCODES: &[(&str, Mode, u8)] = &[
+    ("adc", Mode::Immediate, 0x69), ("adc", Mode::ZeroPage, 0x65), ("adc", Mode::ZeroPageX, 0x75),
+    ("adc", Mode::Absolute, 0x6d), ("adc", Mode::AbsoluteX, 0x7d), ("adc", Mode::AbsoluteY, 0x79),
+    ("adc", Mode::IndexedIndirect, 0x61), ("adc", Mode::IndirectIndexed, 0x71),
+
+    ("and", Mode::Immediate, 0x29), ("and", Mode::ZeroPage, 0x25), ("and", Mode::ZeroPageX, 0x35),
+    ("and", Mode::Absolute, 0x2d), ("and", Mode::AbsoluteX, 0x3d), ("and", Mode::AbsoluteY, 0x39),
+    ("and", Mode::IndexedIndirect, 0x21), ("and", Mode::IndirectIndexed, 0x31),
+
+    ("asl", Mode::Accumulator, 0x0a), ("asl", Mode::ZeroPage, 0x06), ("asl", Mode::ZeroPageX, 0x16),
+    ("asl", Mode::Absolute, 0x0e), ("asl", Mode::AbsoluteX, 0x1e),
+
+    ("bcc", Mode::Relative, 0x90), ("bcs", Mode::Relative, 0xb0), ("beq", Mode::Relative, 0xf0),
+    ("bmi", Mode::Relative, 0x30), ("bne", Mode::Relative, 0xd0), ("bpl", Mode::Relative, 0x10),
+    ("bvc", Mode::Relative, 0x50), ("bvs", Mode::Relative, 0x70),
+
+    ("bit", Mode::ZeroPage, 0x24), ("bit", Mode::Absolute, 0x2c),
+
+    ("brk", Mode::Implied, 0x00),
+
+    ("clc", Mode::Implied, 0x18), ("cld", Mode::Implied, 0xd8), ("cli", Mode::Implied, 0x58),
+    ("clv", Mode::Implied, 0xb8),
+
+    ("cmp", Mode::Immediate, 0xc9), ("cmp", Mode::ZeroPage, 0xc5), ("cmp", Mode::ZeroPageX, 0xd5),
+    ("cmp", Mode::Absolute, 0xcd), ("cmp", Mode::AbsoluteX, 0xdd), ("cmp", Mode::AbsoluteY, 0xd9),
+    ("cmp", Mode::IndexedIndirect, 0xc1), ("cmp", Mode::IndirectIndexed, 0xd1),
+
+    ("cpx", Mode::Immediate, 0xe0), ("cpx", Mode::ZeroPage, 0xe4), ("cpx", Mode::Absolute, 0xec),
+    ("cpy", Mode::Immediate, 0xc0), ("cpy", Mode::ZeroPage, 0xc4), ("cpy", Mode::Absolute, 0xcc),
+
+    ("dec", Mode::ZeroPage, 0xc6), ("dec", Mode::ZeroPageX, 0xd6), ("dec", Mode::Absolute, 0xce),
+    ("dec", Mode::AbsoluteX, 0xde),
+    ("dex", Mode::Implied, 0xca), ("dey", Mode::Implied, 0x88),
+
+    ("eor", Mode::Immediate, 0x49), ("eor", Mode::ZeroPage, 0x45), ("eor", Mode::ZeroPageX, 0x55),
+    ("eor", Mode::Absolute, 0x4d), ("eor", Mode::AbsoluteX, 0x5d), ("eor", Mode::AbsoluteY, 0x59),
+    ("eor", Mode::IndexedIndirect, 0x41), ("eor", Mode::IndirectIndexed, 0x51),
+
+    ("inc", Mode::ZeroPage, 0xe6), ("inc", Mode::ZeroPageX, 0xf6), ("inc", Mode::Absolute, 0xee),
+    ("inc", Mode::AbsoluteX, 0xfe),
+    ("inx", Mode::Implied, 0xe8), ("iny", Mode::Implied, 0xc8),
+
+    ("jmp", Mode::Absolute, 0x4c), ("jmp", Mode::Indirect, 0x6c),
+    ("jsr", Mode::Absolute, 0x20),
 
-    return compiled_patterns;
+    ("lda", Mode::Immediate, 0xa9), ("lda", Mode::ZeroPage, 0xa5), ("lda", Mode::ZeroPageX, 0xb5),
+    ("lda", Mode::Absolute, 0xad), ("lda", Mode::AbsoluteX, 0xbd), ("lda", Mode::AbsoluteY, 0xb9),
+    ("lda", Mode::IndexedIndirect, 0xa1), ("lda", Mode::IndirectIndexed, 0xb1),
+
+    ("ldx", Mode::Immediate, 0xa2), ("ldx", Mode::ZeroPage, 0xa6), ("ldx", Mode::ZeroPageY, 0xb6),
+    ("ldx", Mode::Absolute, 0xae), ("ldx", Mode::AbsoluteY, 0xbe),
+
+    ("ldy", Mode::Immediate, 0xa0), ("ldy", Mode::ZeroPage, 0xa4), ("ldy", Mode::ZeroPageX, 0xb4),
+    ("ldy", Mode::Absolute, 0xac), ("ldy", Mode::AbsoluteX, 0xbc),
+
+    ("lsr", Mode::Accumulator, 0x4a), ("lsr", Mode::ZeroPage, 0x46), ("lsr", Mode::ZeroPageX, 0x56),
+    ("lsr", Mode::Absolute, 0x4e), ("lsr", Mode::AbsoluteX, 0x5e),
+
+    ("nop", Mode::Implied, 0xea),
+
+    ("ora", Mode::Immediate, 0x09), ("ora", Mode::ZeroPage, 0x05), ("ora", Mode::ZeroPageX, 0x15),
+    ("ora", Mode::Absolute, 0x0d), ("ora", Mode::AbsoluteX, 0x1d), ("ora", Mode::AbsoluteY, 0x19),
+    ("ora", Mode::IndexedIndirect, 0x01), ("ora", Mode::IndirectIndexed, 0x11),
+
+    ("pha", Mode::Implied, 0x48), ("php", Mode::Implied, 0x08), ("pla", Mode::Implied, 0x68),
+    ("plp", Mode::Implied, 0x28),
+
+    ("rol", Mode::Accumulator, 0x2a), ("rol", Mode::ZeroPage, 0x26), ("rol", Mode::ZeroPageX, 0x36),
+    ("rol", Mode::Absolute, 0x2e), ("rol", Mode::AbsoluteX, 0x3e),
+
+    ("ror", Mode::Accumulator, 0x6a), ("ror", Mode::ZeroPage, 0x66), ("ror", Mode::ZeroPageX, 0x76),
+    ("ror", Mode::Absolute, 0x6e), ("ror", Mode::AbsoluteX, 0x7e),
+
+    ("rti", Mode::Implied, 0x40), ("rts", Mode::Implied, 0x60),
+
+    ("sbc", Mode::Immediate, 0xe9), ("sbc", Mode::ZeroPage, 0xe5), ("sbc", Mode::ZeroPageX, 0xf5),
+    ("sbc", Mode::Absolute, 0xed), ("sbc", Mode::AbsoluteX, 0xfd), ("sbc", Mode::AbsoluteY, 0xf9),
+    ("sbc", Mode::IndexedIndirect, 0xe1), ("sbc", Mode::IndirectIndexed, 0xf1),
+
+    ("sec", Mode::Implied, 0x38), ("sed", Mode::Implied, 0xf8), ("sei", Mode::Implied, 0x78),
+
+    ("sta", Mode::ZeroPage, 0x85), ("sta", Mode::ZeroPageX, 0x95), ("sta", Mode::Absolute, 0x8d),
+    ("sta", Mode::AbsoluteX, 0x9d), ("sta", Mode::AbsoluteY, 0x99),
+    ("sta", Mode::IndexedIndirect, 0x81), ("sta", Mode::IndirectIndexed, 0x91),
+
+    ("stx", Mode::ZeroPage, 0x86), ("stx", Mode::ZeroPageY, 0x96), ("stx", Mode::Absolute, 0x8e),
+    ("sty", Mode::ZeroPage, 0x84), ("sty", Mode::ZeroPageX, 0x94), ("sty", Mode::Absolute, 0x8c),
+
+    ("tax", Mode::Implied, 0xaa), ("tay", Mode::Implied, 0xa8), ("tsx", Mode::Implied, 0xba),
+    ("txa", Mode::Implied, 0x8a), ("txs", Mode::Implied, 0x9a), ("tya", Mode::Implied, 0x98),
+];
+
+/// The opcode for a mnemonic (in lower case) in an addressing mode, if there is one
+pub fn opcode(mnemonic: &str, mode: Mode) -> Option<u8> {
+    OPCODES.iter()
+        .find(|&&(name, m, _)| name == mnemonic && m == mode)
+        .map(|&(_, _, opcode)| opcode)
 }
 
+/// Every addressing mode a mnemonic (in lower case) supports, none if it isn't one
+pub fn modes(mnemonic: &str) -> Vec<Mode> {
+    OPCODES.iter()
+        .filter(|&&(name, _, _)| name == mnemonic)
+        .map(|&(_, mode, _)| mode)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_opcodes() {
+        assert_eq!(OPCODES.len(), 151);
+        assert_eq!(opcode("ldx", Mode::AbsoluteY), Some(0xbe));
+        assert_eq!(opcode("ldx", Mode::IndirectIndexed), None);
+        assert_eq!(modes("jmp"), vec![Mode::Absolute, Mode::Indirect]);
+        assert!(modes("ldq").is_empty());
+
+        // each opcode is used once
+        let mut seen = vec![false; 256];
+        for &(_, _, opcode) in OPCODES {
+            assert!(!seen[opcode as usize], "{:02x} is in the table twice", opcode);
+            seen[opcode as usize] = true;
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::diagnostic::LineError;
+use crate::parser::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    // `<` and `>`
+    LowByte,
+    HighByte,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    // `wide` when written with more hex digits than a byte needs, like `$0010`, which asks
+    // for an absolute address
+    Number { value: i64, wide: bool },
+    Label(String),
+    // `*`, the address of the current instruction
+    Pc,
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// An operand expression, evaluated once every label is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    /// Evaluate at `pc`, looking up labels (by lower case name) with `lookup`
+    pub fn eval(&self, pc: i64, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, LineError> {
+        match self.kind {
+            ExprKind::Number { value, .. } => Ok(value),
+            ExprKind::Label(ref name) => lookup(&name.to_lowercase())
+                .ok_or_else(|| self.span.error(&format!("undefined label `{}`", name))),
+            ExprKind::Pc => Ok(pc),
+            ExprKind::Unary(op, ref operand) => {
+                let value = operand.eval(pc, lookup)?;
+                Ok(match op {
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::LowByte => value & 0xff,
+                    UnaryOp::HighByte => (value >> 8) & 0xff,
+                })
+            },
+            ExprKind::Binary(op, ref left, ref right) => {
+                let a = left.eval(pc, lookup)?;
+                let b = right.eval(pc, lookup)?;
+                match op {
+                    BinaryOp::Add => Ok(a.wrapping_add(b)),
+                    BinaryOp::Subtract => Ok(a.wrapping_sub(b)),
+                    BinaryOp::Multiply => Ok(a.wrapping_mul(b)),
+                    BinaryOp::Divide if b == 0 => Err(right.span.error("division by zero")),
+                    BinaryOp::Divide => Ok(a.wrapping_div(b)),
+                    BinaryOp::And => Ok(a & b),
+                    BinaryOp::Or => Ok(a | b),
+                    BinaryOp::Xor => Ok(a ^ b),
+                    BinaryOp::ShiftLeft | BinaryOp::ShiftRight if !(0..64).contains(&b) => {
+                        Err(right.span.error(&format!("can't shift by {}", b)))
+                    },
+                    BinaryOp::ShiftLeft => Ok(a << b),
+                    BinaryOp::ShiftRight => Ok(a >> b),
+                }
+            },
+        }
+    }
+
+    /// A number written with more digits than a byte needs, like `$0010`, which asks
+    /// for an absolute address even though its value would fit in zero page
+    pub fn is_wide(&self) -> bool {
+        match self.kind {
+            ExprKind::Number { wide, .. } => wide,
+            _ => false,
+        }
+    }
+
+    /// Whether `*` is in it anywhere
+    pub fn uses_pc(&self) -> bool {
+        match self.kind {
+            ExprKind::Pc => true,
+            ExprKind::Unary(_, ref operand) => operand.uses_pc(),
+            ExprKind::Binary(_, ref left, ref right) => left.uses_pc() || right.uses_pc(),
+            _ => false,
+        }
+    }
+
+    /// A bare label called `name`, in any case
+    pub fn is_label(&self, name: &str) -> bool {
+        match self.kind {
+            ExprKind::Label(ref label) => label.eq_ignore_ascii_case(name),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse_expr;
+
+    fn eval(text: &str) -> Result<i64, LineError> {
+        let expr = parse_expr(&tokenize(text).unwrap(), text.len()).unwrap();
+        expr.eval(0x0600, &|name| if name == "table" { Some(0x1234) } else { None })
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("%1010 | $01 ^ 3 & 2"), Ok(0b1011));
+        assert_eq!(eval("1 << 4 + 1"), Ok(32));
+        assert_eq!(eval("'A' + 1"), Ok(0x42));
+        assert_eq!(eval("10 - -2 / 2"), Ok(11));
+        assert_eq!(eval("* + 3"), Ok(0x0603));
+        assert_eq!(eval("* * 2"), Ok(0x0c00));
+        assert_eq!(eval("<table"), Ok(0x34));
+        assert_eq!(eval(">table"), Ok(0x12));
+        assert_eq!(eval(">TABLE + $0100"), Ok(0x13));
+        assert_eq!(eval("table - 1 >> 8"), Ok(0x12));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("1 + nowhere"), Err(LineError::new(4, 7, "undefined label `nowhere`")));
+        assert_eq!(eval("1 / (2 - 2)"), Err(LineError::new(4, 7, "division by zero")));
+        assert_eq!(eval("1 << 64"), Err(LineError::new(5, 2, "can't shift by 64")));
+    }
+}
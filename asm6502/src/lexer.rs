@@ -0,0 +1,274 @@
+use std::fmt;
+
+use crate::diagnostic::LineError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    // `wide` when written with more digits than a byte needs, so `$0010` can mean an
+    // absolute address
+    Number { value: i64, wide: bool },
+    // a double quoted string, escapes worked out
+    Str(String),
+    Hash,
+    LParen,
+    RParen,
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Amp,
+    Pipe,
+    Caret,
+    ShiftLeft,
+    ShiftRight,
+    Less,
+    Greater,
+    Equals,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Ident(name) => write!(f, "`{}`", name),
+            TokenKind::Number { .. } => write!(f, "a number"),
+            TokenKind::Str(_) => write!(f, "a string"),
+            TokenKind::Hash => write!(f, "`#`"),
+            TokenKind::LParen => write!(f, "`(`"),
+            TokenKind::RParen => write!(f, "`)`"),
+            TokenKind::Comma => write!(f, "`,`"),
+            TokenKind::Colon => write!(f, "`:`"),
+            TokenKind::Plus => write!(f, "`+`"),
+            TokenKind::Minus => write!(f, "`-`"),
+            TokenKind::Star => write!(f, "`*`"),
+            TokenKind::Slash => write!(f, "`/`"),
+            TokenKind::Amp => write!(f, "`&`"),
+            TokenKind::Pipe => write!(f, "`|`"),
+            TokenKind::Caret => write!(f, "`^`"),
+            TokenKind::ShiftLeft => write!(f, "`<<`"),
+            TokenKind::ShiftRight => write!(f, "`>>`"),
+            TokenKind::Less => write!(f, "`<`"),
+            TokenKind::Greater => write!(f, "`>`"),
+            TokenKind::Equals => write!(f, "`=`"),
+        }
+    }
+}
+
+/// A token and where it is on its line: a byte offset and a length
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub column: usize,
+    pub length: usize,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Split a line of source into tokens, stopping at a `;` comment
+pub fn tokenize(line: &str) -> Result<Vec<Token>, LineError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        // take characters while they match, returning the end of the run
+        let mut take_while = |pred: &dyn Fn(char) -> bool| {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if i != start && !pred(c) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            end
+        };
+
+        let kind = if is_ident_start(c) {
+            let end = take_while(&is_ident_char);
+            TokenKind::Ident(line[start..end].to_string())
+        } else if c == '$' || c == '%' || c.is_ascii_digit() {
+            // `$` hex, `%` binary or decimal
+            let end = take_while(&|c: char| c.is_ascii_alphanumeric());
+            let text = &line[start..end];
+            let (radix, digits, name) = match c {
+                '$' => (16, &text[1..], "hex"),
+                '%' => (2, &text[1..], "binary"),
+                _ => (10, text, "decimal"),
+            };
+            if digits.is_empty() {
+                return Err(LineError::new(start, 1, &format!("expected {} digits after `{}`", name, c)));
+            }
+            if !digits.chars().all(|c| c.is_digit(radix)) {
+                let error = LineError::new(start, end - start, &format!("invalid {} number `{}`", name, text));
+                return Err(if radix == 10 { error.with_hint("hex numbers start with `$`".to_string()) } else { error });
+            }
+            match i64::from_str_radix(digits, radix) {
+                Ok(value) if value <= 0xffff_ffff => {
+                    let wide = match radix {
+                        16 => digits.len() > 2,
+                        2 => digits.len() > 8,
+                        _ => false,
+                    };
+                    TokenKind::Number { value, wide }
+                },
+                _ => return Err(LineError::new(start, end - start, "number is too large")),
+            }
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                let c = match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((i, '\\')) => match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, '0')) => '\0',
+                        Some((_, c)) if c == '\\' || c == '"' || c == '\'' => c,
+                        _ => return Err(LineError::new(i, 2, "unknown escape")
+                            .with_hint("strings can have \\n, \\r, \\t, \\0, \\\\ and \\\"".to_string())),
+                    },
+                    Some((i, c)) if !c.is_ascii() => {
+                        return Err(LineError::new(i, c.len_utf8(), "strings can only have ASCII characters"));
+                    },
+                    Some((_, c)) => c,
+                    None => return Err(LineError::new(start, line.len() - start, "unterminated string")),
+                };
+                text.push(c);
+            }
+            TokenKind::Str(text)
+        } else if c == '\'' {
+            // a character, as its ASCII code
+            chars.next();
+            match (chars.next(), chars.next()) {
+                (Some((_, c)), Some((_, '\''))) if c.is_ascii() => TokenKind::Number { value: c as i64, wide: false },
+                _ => return Err(LineError::new(start, 1, "expected one ASCII character between quotes, like 'a'")),
+            }
+        } else {
+            chars.next();
+            match c {
+                '#' => TokenKind::Hash,
+                '(' => TokenKind::LParen,
+                ')' => TokenKind::RParen,
+                ',' => TokenKind::Comma,
+                ':' => TokenKind::Colon,
+                '+' => TokenKind::Plus,
+                '-' => TokenKind::Minus,
+                '*' => TokenKind::Star,
+                '/' => TokenKind::Slash,
+                '&' => TokenKind::Amp,
+                '|' => TokenKind::Pipe,
+                '^' => TokenKind::Caret,
+                '<' if chars.peek().map(|&(_, c)| c) == Some('<') => {
+                    chars.next();
+                    TokenKind::ShiftLeft
+                },
+                '>' if chars.peek().map(|&(_, c)| c) == Some('>') => {
+                    chars.next();
+                    TokenKind::ShiftRight
+                },
+                '<' => TokenKind::Less,
+                '>' => TokenKind::Greater,
+                '=' => TokenKind::Equals,
+                _ => return Err(LineError::new(start, c.len_utf8(), &format!("unexpected character `{}`", c))),
+            }
+        };
+        let end = chars.peek().map_or(line.len(), |&(i, _)| i);
+        tokens.push(Token { kind, column: start, length: end - start });
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<TokenKind> {
+        tokenize(line).unwrap().into_iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(kinds("loop: LDA ($0a),y ; comment"), vec![
+            TokenKind::Ident("loop".to_string()),
+            TokenKind::Colon,
+            TokenKind::Ident("LDA".to_string()),
+            TokenKind::LParen,
+            TokenKind::Number { value: 0x0a, wide: false },
+            TokenKind::RParen,
+            TokenKind::Comma,
+            TokenKind::Ident("y".to_string()),
+        ]);
+        assert_eq!(kinds("  adc #2"), vec![
+            TokenKind::Ident("adc".to_string()),
+            TokenKind::Hash,
+            TokenKind::Number { value: 2, wide: false },
+        ]);
+        assert_eq!(kinds("$0010 %101 'a' 300"), vec![
+            TokenKind::Number { value: 0x10, wide: true },
+            TokenKind::Number { value: 5, wide: false },
+            TokenKind::Number { value: 0x61, wide: false },
+            TokenKind::Number { value: 300, wide: false },
+        ]);
+        assert_eq!(kinds("<a>>1<<2>b*-"), vec![
+            TokenKind::Less,
+            TokenKind::Ident("a".to_string()),
+            TokenKind::ShiftRight,
+            TokenKind::Number { value: 1, wide: false },
+            TokenKind::ShiftLeft,
+            TokenKind::Number { value: 2, wide: false },
+            TokenKind::Greater,
+            TokenKind::Ident("b".to_string()),
+            TokenKind::Star,
+            TokenKind::Minus,
+        ]);
+        assert_eq!(kinds(".text \"a;\\\"b\\n\", 0"), vec![
+            TokenKind::Ident(".text".to_string()),
+            TokenKind::Str("a;\"b\n".to_string()),
+            TokenKind::Comma,
+            TokenKind::Number { value: 0, wide: false },
+        ]);
+        assert_eq!(kinds("size = 2"), vec![
+            TokenKind::Ident("size".to_string()),
+            TokenKind::Equals,
+            TokenKind::Number { value: 2, wide: false },
+        ]);
+        assert_eq!(kinds("; only a comment"), vec![]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("  sta $0200,x").unwrap();
+        let spans: Vec<(usize, usize)> = tokens.iter().map(|token| (token.column, token.length)).collect();
+        assert_eq!(spans, vec![(2, 3), (6, 5), (11, 1), (12, 1)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(tokenize("lda $").unwrap_err(), LineError::new(4, 1, "expected hex digits after `$`"));
+        assert_eq!(tokenize("lda $1g").unwrap_err(), LineError::new(4, 3, "invalid hex number `$1g`"));
+        assert_eq!(tokenize("lda %12").unwrap_err(), LineError::new(4, 3, "invalid binary number `%12`"));
+        assert_eq!(tokenize("lda 0a").unwrap_err().hint, Some("hex numbers start with `$`".to_string()));
+        assert_eq!(tokenize("lda #'ab'").unwrap_err().message, "expected one ASCII character between quotes, like 'a'");
+        assert_eq!(tokenize(".text \"abc").unwrap_err(), LineError::new(6, 4, "unterminated string"));
+        assert_eq!(tokenize(".text \"a\\qc\"").unwrap_err().message, "unknown escape");
+        assert_eq!(tokenize("lda @").unwrap_err(), LineError::new(4, 1, "unexpected character `@`"));
+    }
+}
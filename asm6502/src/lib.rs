@@ -0,0 +1,473 @@
+//! A multi pass assembler for the MOS 6502, usable as a library (the emulator runs sources
+//! through it directly) as well as from the `asm6502` binary.
+
+use std::fs::File;
+use std::vec::Vec;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+mod assembler;
+mod diagnostic;
+mod encode;
+mod expr;
+mod lexer;
+mod macros;
+mod parser;
+
+pub use diagnostic::{Diagnostic, Location, Note};
+
+/// Where programs go when they don't say with `.org`
+pub const DEFAULT_ORIGIN: u16 = 0x0600;
+
+/// An assembled program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// Where `bytes` go: the address of the first byte assembled
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    /// The absolute address of every instruction, with the file and line it came from
+    pub debug_map: Vec<(u16, String, u16)>,
+    /// The absolute address of every label
+    pub symbols: Vec<(String, u16)>,
+}
+
+/// Assemble a source file into the bytes to load at `start_mem_address`, unless the
+/// source moves them with `.org`
+pub fn assemble(in_file_path: String, start_mem_address: u16) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    assemble_with_debug_map(in_file_path, start_mem_address).map(|program| program.bytes)
+}
+
+/// Assemble, and also return the debug map and the labels. Every error in the file is
+/// returned, not just the first.
+pub fn assemble_with_debug_map(in_file_path: String, start_mem_address: u16) -> Result<Program, Vec<Diagnostic>> {
+    assemble_with_include_paths(in_file_path, start_mem_address, &[])
+}
+
+/// Like `assemble_with_debug_map`, with directories `.include` looks in when a file
+/// isn't next to the one including it
+pub fn assemble_with_include_paths(in_file_path: String, start_mem_address: u16, include_paths: &[PathBuf]) -> Result<Program, Vec<Diagnostic>> {
+
+    // Read file. File closes automatically at the end of the scope.
+    let mut source = String::new();
+    File::open(&in_file_path)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .map_err(|error| vec![Diagnostic::new(&in_file_path, &error.to_string())])?;
+    let lines: Vec<String> = source.lines().map(String::from).collect();
+    assembler::assemble_lines(&in_file_path, &lines, start_mem_address, include_paths)
+}
+
+/// Like `assemble_with_debug_map`, for source already in memory. `name` is the file
+/// diagnostics say it came from, and what it includes is looked for next to it.
+pub fn assemble_source(source: &str, name: &str, start_mem_address: u16) -> Result<Program, Vec<Diagnostic>> {
+    let lines: Vec<String> = source.lines().map(String::from).collect();
+    assembler::assemble_lines(name, &lines, start_mem_address, &[])
+}
+
+/// One "address file line" entry per instruction, read by the emulator for coverage.
+pub fn write_debug_map(map_file_path: &str, debug_map: &[(u16, String, u16)]) -> io::Result<()> {
+    let mut map_file = File::create(map_file_path)?;
+    for (address, file, line_number) in debug_map {
+        writeln!(map_file, "{:04x} {} {}", address, file, line_number)?;
+    }
+    Ok(())
+}
+
+/// One "address label" entry per label, which the emulator loads with `--symbols`.
+pub fn write_symbols(symbol_file_path: &str, symbols: &[(String, u16)]) -> io::Result<()> {
+    let mut symbol_file = File::create(symbol_file_path)?;
+    for (label, address) in symbols {
+        writeln!(symbol_file, "{:04x} {}", address, label)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_simple() {
+        let bin_bytes = assemble("asm_code/simple.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa9, 0x01, 0x8d, 0x00, 0x02,
+                            0xa9, 0x05, 0x8d, 0x01, 0x02,
+                            0xa9, 0x08, 0x8d, 0x02, 0x02];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_registers() {
+        let bin_bytes = assemble("asm_code/registers.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa9, 0xc0, 0xaa, 0xe8, 0x69, 0xc4, 0x00];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_branching() {
+        let bin_bytes = assemble("asm_code/branching.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa2, 0x08, 0xca, 0x8e, 0x00,
+                            0x02, 0xe0, 0x03, 0xd0, 0xf8,
+                            0x8e, 0x01, 0x02, 0x00];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_indirect() {
+        let bin_bytes = assemble("asm_code/indirect.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa9, 0x01, 0x85, 0xf0, 0xa9,
+                            0xcc, 0x85, 0xf1, 0x6c, 0xf0, 0x00];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_indexed_indirect() {
+        let bin_bytes = assemble("asm_code/indexed_indirect.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa2, 0x01, 0xa9, 0x05, 0x85,
+                            0x01, 0xa9, 0x07, 0x85, 0x02,
+                            0xa0, 0x0a, 0x8c, 0x05, 0x07,
+                            0xa1, 0x00 ];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_indirect_index() {
+        let bin_bytes = assemble("asm_code/indirect_indexed.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa0, 0x01, 0xa9, 0x03, 0x85,
+                            0x01, 0xa9, 0x07, 0x85, 0x02,
+                            0xa2, 0x0a, 0x8e, 0x04, 0x07,
+                            0xb1, 0x01 ];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_stack() {
+        let bin_bytes = assemble("asm_code/stack.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa2, 0x00, 0xa0, 0x00, 0x8a,
+                            0x99, 0x00, 0x02, 0x48, 0xe8,
+                            0xc8, 0xc0, 0x10, 0xd0, 0xf5,
+                            0x68, 0x99, 0x00, 0x02, 0xc8,
+                            0xc0, 0x20, 0xd0, 0xf7];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_jsr() {
+        let bin_bytes = assemble("asm_code/jsr.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0x20, 0x09, 0x06, 0x20, 0x0c,
+                            0x06, 0x20, 0x12, 0x06, 0xa2,
+                            0x00, 0x60, 0xe8, 0xe0, 0x05,
+                            0xd0, 0xfb, 0x60, 0x00];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let bin_bytes = assemble("asm_code/preprocess.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa5, 0xfe, 0xa2, 0x0c];
+        assert_eq!(bin_bytes, expected);
+    }
+
+    #[test]
+    fn test_debug_map() {
+        let debug_map = assemble_with_debug_map("asm_code/jsr.6502asm".to_string(), 0x0600).unwrap().debug_map;
+        assert!(debug_map.iter().all(|(_, file, _)| file == "asm_code/jsr.6502asm"));
+        let debug_map: Vec<(u16, u16)> = debug_map.into_iter().map(|(address, _, line)| (address, line)).collect();
+        let expected = vec![(0x0600, 1), (0x0603, 2), (0x0606, 3),
+                            (0x0609, 6), (0x060b, 7),
+                            (0x060c, 10), (0x060d, 11), (0x060f, 12), (0x0611, 13),
+                            (0x0612, 16)];
+        assert_eq!(debug_map, expected);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = assemble_with_debug_map("asm_code/jsr.6502asm".to_string(), 0x0600).unwrap().symbols;
+        let expected = vec![("init".to_string(), 0x0609),
+                            ("loop".to_string(), 0x060c),
+                            ("end".to_string(), 0x0612)];
+        assert_eq!(symbols, expected);
+    }
+
+    #[test]
+    fn test_write_errors() {
+        // a file in a directory that isn't there can't be created
+        assert!(write_debug_map("asm_code/missing/out.map", &[(0x0600, "a.6502asm".to_string(), 1)]).is_err());
+        assert!(write_symbols("asm_code/missing/out.sym", &[("loop".to_string(), 0x0600)]).is_err());
+    }
+
+    #[test]
+    fn test_constants() {
+        // names are whole tokens, so `a` doesn't touch `lda`, and every one on a line applies
+        let source = "define a $01\nlo = $10\n.equ hi, lo * 2\nwide = $0020\n  lda a\n  asl a\n  ldx #lo + hi\n  .byte lo, hi\n\
+                      \x20 lda wide\n  sta later,x\nlater = 3\n";
+        let program = assemble_source(source, "test.6502asm", 0x0600).unwrap();
+        assert_eq!(program.bytes, vec![0xa5, 0x01, 0x0a, 0xa2, 0x30, 0x10, 0x20, 0xad, 0x20, 0x00, 0x95, 0x03]);
+        assert!(program.symbols.is_empty());
+
+        let source = "lo = 1\nlo = 2\nloop:\nloop = 3\n.equ lo2 lo\nnext = * + 1\nbad = loop\nempty =\n";
+        let diagnostics = assemble_source(source, "test.6502asm", 0x0600).unwrap_err();
+        let found: Vec<(usize, &str)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.location.as_ref().unwrap().line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (2, "constant `lo` is already defined"),
+            (4, "`loop` is already defined as a label"),
+            (5, "expected `,`, found `lo`"),
+            (6, "`*` can't be used in a constant"),
+            (7, "undefined label `loop`"),
+            (8, "expected a value for `empty`"),
+        ]);
+        assert_eq!(diagnostics[0].hint, Some("first defined on line 1".to_string()));
+        assert_eq!(diagnostics[4].hint, Some("constants can only use numbers and the constants defined before them".to_string()));
+    }
+
+    #[test]
+    fn test_source() {
+        let source = "define sysRandom $fe\n  lda sysRandom ; random\nloop:\n  jmp loop\n";
+        let program = assemble_source(source, "test.6502asm", 0x0800).unwrap();
+        assert_eq!(program, Program {
+            origin: 0x0800,
+            bytes: vec![0xa5, 0xfe, 0x4c, 0x02, 0x08],
+            debug_map: vec![(0x0800, "test.6502asm".to_string(), 2), (0x0802, "test.6502asm".to_string(), 4)],
+            symbols: vec![("loop".to_string(), 0x0802)],
+        });
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "table:\n  lda table+1,x\n  lda #<message\n  ldx #>message\n  sta $0200 + 2*%10\n\
+                      \x20 bne * + 2\n  lda #'A'\n  lda #-1\n  sta ($10 + 2),y\nmessage:\n";
+        let bin_bytes = assemble_source(source, "test.6502asm", 0x0600).unwrap().bytes;
+        assert_eq!(bin_bytes, vec![0xbd, 0x01, 0x06, 0xa9, 0x12, 0xa2, 0x06, 0x8d, 0x04, 0x02,
+                                   0xd0, 0x00, 0xa9, 0x41, 0xa9, 0xff, 0x91, 0x12]);
+    }
+
+    #[test]
+    fn test_zero_page_labels() {
+        let source = "  lda ptr,x\n  sta (ptr),y\n  lda.w ptr\n  ldx ptr+$100,y\n  stx.z ptr\nptr:\n";
+        let program = assemble_source(source, "test.6502asm", 0x0000).unwrap();
+        assert_eq!(program.bytes, vec![0xb5, 0x0c, 0x91, 0x0c, 0xad, 0x0c, 0x00, 0xbe, 0x0c, 0x01, 0x86, 0x0c]);
+        assert_eq!(program.symbols, vec![("ptr".to_string(), 0x000c)]);
+
+        // the last label only fits zero page while the loads are zero page, so they widen
+        let source = "  lda end\n  lda end\n  brk\nend:\n";
+        let program = assemble_source(source, "test.6502asm", 0x00fb).unwrap();
+        assert_eq!(program.bytes, vec![0xad, 0x02, 0x01, 0xad, 0x02, 0x01, 0x00]);
+        assert_eq!(program.symbols, vec![("end".to_string(), 0x0102)]);
+
+        let source = "  ldx.z $1234\n  lda.w #1\n  stx end,y\nend:\n";
+        let diagnostics = assemble_source(source, "test.6502asm", 0x0600).unwrap_err();
+        let found: Vec<(&str, Option<&str>)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.message.as_str(), diagnostic.hint.as_deref()))
+            .collect();
+        assert_eq!(found, vec![
+            ("`.w` only applies to zero page or absolute operands", None),
+            ("$1234 is not in zero page", Some("`.z` forces zero page")),
+            ("$604 is not in zero page", Some("STX only takes a zero page address here")),
+        ]);
+    }
+
+    #[test]
+    fn test_directives() {
+        let program = assemble_with_debug_map("asm_code/directives.6502asm".to_string(), 0x0600).unwrap();
+        assert_eq!(program.origin, 0x0800);
+        let expected = vec![0xa2, 0x00, 0xbd, 0x0f, 0x08, 0xf0, 0x07, 0x9d,
+                            0x00, 0x02, 0xe8, 0x4c, 0x02, 0x08, 0x00,
+                            0x48, 0x49, 0x00,
+                            0x01, 0x02, 0x03, 0x41, 0x12, 0x08,
+                            0x00, 0x08, 0x34, 0x12,
+                            0xea, 0xea, 0xea, 0xea,
+                            0xff, 0xff, 0xff,
+                            0x00, 0x00,
+                            0x22, 0x33, 0x44, 0x55];
+        assert_eq!(program.bytes, expected);
+        assert_eq!(program.debug_map.last(), Some(&(0x080e, "asm_code/directives.6502asm".to_string(), 12)));
+        assert_eq!(program.symbols.last(), Some(&("glyphs".to_string(), 0x0825)));
+
+        // gaps are filled in, but sections can't go backwards
+        let source = "  .org $0700\n  nop\n  .org * + 3\n  nop\n";
+        assert_eq!(assemble_source(source, "test.6502asm", 0x0600).unwrap().bytes, vec![0xea, 0x00, 0x00, 0x00, 0xea]);
+
+        let source = "  .org $0700\n  nop\n  .org $06ff\n  nop\n  nop\n  .blob 1\n  .fill\n  .word \"no\"\n  .incbin \"missing.bin\"\n";
+        let diagnostics = assemble_source(source, "asm_code/test.6502asm", 0x0600).unwrap_err();
+        let found: Vec<(usize, &str)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.location.as_ref().unwrap().line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (6, "unknown directive `.blob`"),
+            (7, "wrong number of arguments to `.fill`"),
+            (8, "expected a number, found a string"),
+            (9, "can't read `asm_code/missing.bin`: No such file or directory (os error 2)"),
+            (4, "this would go at $06ff, before the start at $0700"),
+            (5, "this would overwrite $0700, which is already assembled"),
+        ]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let program = assemble_with_debug_map("asm_code/macros.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![0xa9, 0x00, 0x85, 0x10, 0xa9, 0x02, 0x85, 0x11,
+                            0xe6, 0x10, 0xd0, 0x02, 0xe6, 0x11,
+                            0xe6, 0x10, 0xd0, 0x02, 0xe6, 0x11,
+                            0x4c, 0x08, 0x06];
+        assert_eq!(program.bytes, expected);
+        // instructions map to the lines of the macro bodies they came from
+        let lines: Vec<(u16, u16)> = program.debug_map[..5].iter().map(|&(address, _, line)| (address, line)).collect();
+        assert_eq!(lines, vec![(0x0600, 10), (0x0602, 11), (0x0604, 12), (0x0606, 13), (0x0608, 3)]);
+        let labels: Vec<&str> = program.symbols.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(labels, vec!["start", "again", "inc16.2.done", "inc16.3.done"]);
+
+        // errors point at the line in the body, then at each invocation it came from
+        let source = ".macro load value\n  lda.z value\n.endmacro\n.macro twice value\n  load value\n  load value\n.endmacro\n\
+                      \x20 twice $1234\n  load 1, 2\n.macro lda\n.endmacro\n.endmacro\n.macro twice\n";
+        let diagnostics = assemble_source(source, "test.6502asm", 0x0600).unwrap_err();
+        let found: Vec<(usize, &str)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.location.as_ref().unwrap().line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (9, "`load` takes 1 argument, found 2"),
+            (10, "`lda` is an instruction"),
+            (12, "`.endmacro` without `.macro`"),
+            (13, "macro `twice` is already defined"),
+            (13, "`.macro` without `.endmacro`"),
+            (2, "$1234 is not in zero page"),
+            (2, "$1234 is not in zero page"),
+        ]);
+        assert_eq!(diagnostics[5].to_string(), "test.6502asm:2:9: error: $1234 is not in zero page\n\
+                                                \x20     lda.z $1234\n\
+                                                \x20           ^^^^^\n\
+                                                \x20   = hint: `.z` forces zero page\n\
+                                                test.6502asm:5:3: note: in this expansion of `load`\n\
+                                                \x20     load $1234\n\
+                                                \x20     ^^^^\n\
+                                                test.6502asm:8:3: note: in this expansion of `twice`\n\
+                                                \x20     twice $1234\n\
+                                                \x20     ^^^^^");
+
+        let diagnostics = assemble_source(".macro forever\n  forever\n.endmacro\n  forever\n", "test.6502asm", 0x0600).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "macros are nested more than 32 deep");
+        assert_eq!(diagnostics[0].notes.len(), 32);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let include_paths = vec![PathBuf::from("asm_code/lib")];
+        let program = assemble_with_include_paths("asm_code/variants.6502asm".to_string(), 0x0600, &include_paths).unwrap();
+        let expected = vec![0xa9, 0x00, 0xa2, 0x00, 0x9d, 0x00, 0x02, 0xe8, 0xd0, 0xfa,
+                            0xa9, 0xff, 0x8d, 0x00, 0x03,
+                            0xa0, 0x02,
+                            0x00];
+        assert_eq!(program.bytes, expected);
+        // the macro's lines are in the file it came from
+        assert_eq!(program.debug_map[0], (0x0600, "asm_code/lib/screen.6502asm".to_string(), 4));
+        assert_eq!(program.debug_map[5], (0x060a, "asm_code/variants.6502asm".to_string(), 10));
+
+        let source = ".if 1\n.else\n  .byte 1, 2 3\n.else\n.endif\n.elseif 1\n.if nowhere\n.endif\n.ifdef\n.if 0\n";
+        let diagnostics = assemble_source(source, "test.6502asm", 0x0600).unwrap_err();
+        let found: Vec<(usize, &str)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.location.as_ref().unwrap().line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (4, "`.else` after `.else`"),
+            (6, "`.elseif` without `.if`"),
+            (7, "undefined label `nowhere`"),
+            (9, "expected a name, like `.ifdef DEBUG`"),
+            (9, "`.if` without `.endif`"),
+            (10, "`.if` without `.endif`"),
+        ]);
+        assert_eq!(diagnostics[0].hint, Some("the `.else` is on line 2".to_string()));
+        assert_eq!(diagnostics[2].hint, Some("conditions are worked out before labels have addresses, so they can only use constants".to_string()));
+    }
+
+    #[test]
+    fn test_includes() {
+        let source = "  .include \"lib/loop_a.6502asm\"\n  .include \"nowhere.6502asm\"\n  .include 1\n";
+        let diagnostics = assemble_source(source, "asm_code/test.6502asm", 0x0600).unwrap_err();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0].to_string(), "asm_code/lib/loop_b.6502asm:2:10: error: `loop_a.6502asm` is already being included\n\
+                                                \x20   .include \"loop_a.6502asm\"\n\
+                                                \x20            ^^^^^^^^^^^^^^^^\n\
+                                                \x20   = hint: files can't include themselves, even by way of other files\n\
+                                                asm_code/lib/loop_a.6502asm:1:10: note: `asm_code/lib/loop_b.6502asm` is included here\n\
+                                                \x20   .include \"loop_b.6502asm\"\n\
+                                                \x20            ^^^^^^^^^^^^^^^^\n\
+                                                asm_code/test.6502asm:1:12: note: `asm_code/lib/loop_a.6502asm` is included here\n\
+                                                \x20     .include \"lib/loop_a.6502asm\"\n\
+                                                \x20              ^^^^^^^^^^^^^^^^^^^^");
+        assert_eq!(diagnostics[1].message, "can't find `nowhere.6502asm`");
+        assert_eq!(diagnostics[1].hint, Some("looked in `asm_code`; add directories to look in with `-I`".to_string()));
+        assert_eq!(diagnostics[2].message, "expected a file name in quotes");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = "  ldx ($01),y\n  lda #$100\n  bne nowhere\n  ldq $10\nloop:\nLOOP: rts $10\n  lda ($10\n";
+        let diagnostics = assemble_source(source, "test.6502asm", 0x0600).unwrap_err();
+        let found: Vec<(usize, usize, &str)> = diagnostics.iter()
+            .map(|diagnostic| {
+                let location = diagnostic.location.as_ref().unwrap();
+                (location.line, location.column, diagnostic.message.as_str())
+            })
+            .collect();
+
+        // all of them, in order, with the syntax and mode errors from the first pass first
+        assert_eq!(found, vec![
+            (1, 7, "invalid addressing mode for LDX"),
+            (4, 3, "unknown instruction `ldq`"),
+            (6, 1, "label `LOOP` is already defined"),
+            (6, 11, "RTS does not take an operand"),
+            (7, 11, "expected `)`"),
+            (2, 8, "$100 does not fit in a byte"),
+            (3, 7, "undefined label `nowhere`"),
+        ]);
+        assert_eq!(diagnostics[0].hint, Some("LDX does not support (zp),Y; it supports #imm, zp, zp,Y, abs and abs,Y".to_string()));
+        assert_eq!(diagnostics[2].hint, Some("first defined on line 5".to_string()));
+        assert_eq!(diagnostics[0].to_string(), "test.6502asm:1:7: error: invalid addressing mode for LDX\n\
+                                                \x20     ldx ($01),y\n\
+                                                \x20         ^^^^^^^\n\
+                                                \x20   = hint: LDX does not support (zp),Y; it supports #imm, zp, zp,Y, abs and abs,Y");
+    }
+
+    #[test]
+    fn test_branch_range() {
+        let mut source = String::from("start:\n");
+        for _ in 0..64 {
+            source.push_str("  nop\n  nop\n");
+        }
+        source.push_str("  bne start\n");
+        let diagnostics = assemble_source(&source, "test.6502asm", 0x0600).unwrap_err();
+        assert_eq!(diagnostics[0].message, "branch target is out of range");
+        assert_eq!(diagnostics[0].hint, Some("branches reach 128 bytes back or 127 forward, this one is -130 bytes".to_string()));
+    }
+
+    #[test]
+    fn test_snake() {
+        let bin_bytes = assemble("asm_code/snake.6502asm".to_string(), 0x0600).unwrap();
+        let expected = vec![
+        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
+        0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
+        0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
+        0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
+        0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
+        0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
+        0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
+        0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
+        0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
+        0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
+        0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
+        0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
+        0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
+        0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
+        0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
+        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
+        0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
+        0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
+        0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa2, 0x00, 0xea,
+        0xea, 0xca, 0xd0, 0xfb, 0x60
+        ];
+
+        assert_eq!(bin_bytes, expected);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,176 @@
+use crate::diagnostic::LineError;
+use crate::encode;
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::parser::Span;
+
+/// A macro, from `.macro name a, b` up to `.endmacro`
+pub struct Macro {
+    // as written
+    pub name: String,
+    // the `.macro` line, in the assembler's lines
+    pub line: usize,
+    // in lower case
+    pub params: Vec<String>,
+    // the lines between `.macro` and `.endmacro`, by their index in the assembler's lines
+    pub body: Vec<usize>,
+    // labels defined in the body, in lower case, which are renamed in each expansion so
+    // invoking the macro twice doesn't define them twice
+    pub locals: Vec<String>,
+}
+
+/// The name and parameters on a `.macro` line, `tokens` being everything after `.macro`
+pub fn parse_definition(tokens: &[Token], directive: Span) -> Result<(String, Vec<String>), LineError> {
+    let usage = "`.macro name` or `.macro name param, param`".to_string();
+    let name = match tokens.first() {
+        Some(token @ Token { kind: TokenKind::Ident(name), .. }) => {
+            let span = Span::of(token);
+            if name.starts_with('.') {
+                return Err(span.error("macro names can't start with `.`").with_hint(usage));
+            }
+            if !encode::modes(&name.to_lowercase()).is_empty() {
+                return Err(span.error(&format!("`{}` is an instruction", name)));
+            }
+            name.clone()
+        },
+        Some(token) => return Err(Span::of(token).error(&format!("expected a macro name, found {}", token.kind)).with_hint(usage)),
+        None => return Err(directive.error("expected a macro name").with_hint(usage)),
+    };
+
+    let mut params: Vec<String> = Vec::new();
+    let mut rest = tokens[1..].iter();
+    while let Some(token) = rest.next() {
+        let param = match token.kind {
+            TokenKind::Ident(ref param) if !param.starts_with('.') => param.to_lowercase(),
+            _ => return Err(Span::of(token).error(&format!("expected a parameter name, found {}", token.kind)).with_hint(usage)),
+        };
+        if params.contains(&param) {
+            return Err(Span::of(token).error(&format!("parameter `{}` is already defined", param)));
+        }
+        params.push(param);
+        match rest.next() {
+            None => break,
+            Some(Token { kind: TokenKind::Comma, .. }) => (),
+            Some(token) => return Err(Span::of(token).error(&format!("expected `,`, found {}", token.kind)).with_hint(usage)),
+        }
+    }
+    Ok((name, params))
+}
+
+/// The text of each comma separated argument in `tokens`, everything after a macro's name.
+/// Commas inside parentheses don't count, so `(ptr,x)` is one argument.
+pub fn split_args(text: &str, tokens: &[Token]) -> Result<Vec<(String, Span)>, LineError> {
+    let mut args = Vec::new();
+    if tokens.is_empty() {
+        return Ok(args);
+    }
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen => depth -= 1,
+            TokenKind::Comma if depth == 0 => {
+                args.push(arg(text, &tokens[start..index], token)?);
+                start = index + 1;
+            },
+            _ => (),
+        }
+    }
+    let last = &tokens[tokens.len() - 1];
+    let end = Token { kind: TokenKind::Comma, column: last.column + last.length, length: 1 };
+    args.push(arg(text, &tokens[start..], &end)?);
+    Ok(args)
+}
+
+// One argument, which is an error when there's nothing before the comma `next`
+fn arg(text: &str, tokens: &[Token], next: &Token) -> Result<(String, Span), LineError> {
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => {
+            let span = Span::of(first).to(Span::of(last));
+            Ok((text[span.column..span.column + span.length].to_string(), span))
+        },
+        _ => Err(Span::of(next).error("expected an argument before this")),
+    }
+}
+
+impl Macro {
+    /// A line of the body with the parameters replaced by `args` and local labels renamed
+    /// for expansion number `expansion`. Only whole names are replaced, so a parameter `a`
+    /// leaves `lda` alone. A line that doesn't tokenize is left as it is, to be reported
+    /// when it's assembled.
+    pub fn expand(&self, text: &str, args: &[String], expansion: usize) -> String {
+        let tokens = match tokenize(text) {
+            Ok(tokens) => tokens,
+            Err(_) => return text.to_string(),
+        };
+        let mut expanded = String::new();
+        let mut copied = 0;
+        for token in tokens {
+            let name = match token.kind {
+                TokenKind::Ident(ref name) => name.to_lowercase(),
+                _ => continue,
+            };
+            let replacement = if let Some(index) = self.params.iter().position(|param| *param == name) {
+                args[index].clone()
+            } else if self.locals.contains(&name) {
+                format!("{}.{}.{}", self.name, expansion, &text[token.column..token.column + token.length])
+            } else {
+                continue;
+            };
+            expanded.push_str(&text[copied..token.column]);
+            expanded.push_str(&replacement);
+            copied = token.column + token.length;
+        }
+        expanded.push_str(&text[copied..]);
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn definition(line: &str) -> Result<(String, Vec<String>), LineError> {
+        let tokens = tokenize(line).unwrap();
+        parse_definition(&tokens[1..], Span::of(&tokens[0]))
+    }
+
+    #[test]
+    fn test_definition() {
+        assert_eq!(definition(".macro inc16 Ptr, by"), Ok(("inc16".to_string(), vec!["ptr".to_string(), "by".to_string()])));
+        assert_eq!(definition(".macro wait"), Ok(("wait".to_string(), vec![])));
+        assert_eq!(definition(".macro").unwrap_err().message, "expected a macro name");
+        assert_eq!(definition(".macro lda a").unwrap_err(), LineError::new(7, 3, "`lda` is an instruction"));
+        assert_eq!(definition(".macro m a, a").unwrap_err(), LineError::new(12, 1, "parameter `a` is already defined"));
+        assert_eq!(definition(".macro m a b").unwrap_err().message, "expected `,`, found `b`");
+    }
+
+    #[test]
+    fn test_args() {
+        let text = "  m (ptr,x), #<table + 1, \"a, b\"";
+        let tokens = tokenize(text).unwrap();
+        let args: Vec<String> = split_args(text, &tokens[1..]).unwrap().into_iter().map(|(arg, _)| arg).collect();
+        assert_eq!(args, vec!["(ptr,x)", "#<table + 1", "\"a, b\""]);
+
+        let text = "  m 1,,2";
+        let tokens = tokenize(text).unwrap();
+        assert_eq!(split_args(text, &tokens[1..]).unwrap_err(), LineError::new(6, 1, "expected an argument before this"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let m = Macro {
+            name: "inc16".to_string(),
+            line: 0,
+            params: vec!["a".to_string()],
+            body: vec![],
+            locals: vec!["skip".to_string()],
+        };
+        let args = vec!["ptr+1".to_string()];
+        assert_eq!(m.expand("  inc A ; a", &args, 3), "  inc ptr+1 ; a");
+        assert_eq!(m.expand("  bne Skip", &args, 3), "  bne inc16.3.Skip");
+        assert_eq!(m.expand("skip: lda (a),y", &args, 4), "inc16.4.skip: lda (ptr+1),y");
+        assert_eq!(m.expand("  .byte \"a\"", &args, 3), "  .byte \"a\"");
+    }
+}
@@ -0,0 +1,439 @@
+use crate::diagnostic::LineError;
+use crate::expr::{BinaryOp, Expr, ExprKind, UnaryOp};
+use crate::lexer::{Token, TokenKind};
+
+/// A byte offset and length on a line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    pub fn of(token: &Token) -> Span {
+        Span { column: token.column, length: token.length }
+    }
+
+    // from the start of this span to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span { column: self.column, length: other.column + other.length - self.column }
+    }
+
+    pub fn error(self, message: &str) -> LineError {
+        LineError::new(self.column, self.length, message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Index {
+    None,
+    X,
+    Y,
+}
+
+/// An operand by its syntax. Which addressing mode it ends up as depends on the
+/// instruction and the value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    None,
+    Immediate(Expr),
+    Direct(Expr, Index),
+    Indirect(Expr),
+    IndexedIndirect(Expr),
+    IndirectIndexed(Expr),
+}
+
+/// A suffix on a mnemonic saying how wide its address is, rather than leaving it to
+/// the value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    // `.z`
+    ZeroPage,
+    // `.w`
+    Absolute,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    // in lower case, without any suffix
+    pub mnemonic: String,
+    pub width: Option<Width>,
+    pub span: Span,
+    pub operand: Operand,
+    // the whole operand, or the mnemonic when there isn't one
+    pub operand_span: Span,
+}
+
+/// An argument to a directive
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Expr(Expr),
+    Str(String, Span),
+}
+
+impl Arg {
+    pub fn span(&self) -> Span {
+        match self {
+            Arg::Expr(expr) => expr.span,
+            Arg::Str(_, span) => *span,
+        }
+    }
+}
+
+/// A directive like `.byte 1, 2, 3`, with its comma separated arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    // in lower case, with the dot
+    pub name: String,
+    pub span: Span,
+    pub args: Vec<Arg>,
+}
+
+/// A line of source: an optional `label:`, then an optional instruction or directive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub label: Option<(String, Span)>,
+    pub instruction: Option<Instruction>,
+    pub directive: Option<Directive>,
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    next: usize,
+    // where the line ends, to point at when something is missing
+    end: Span,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a TokenKind> {
+        self.tokens.get(self.next).map(|token| &token.kind)
+    }
+
+    fn advance(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.next);
+        self.next += 1;
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.peek() == Some(kind) {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn span(&self) -> Span {
+        self.tokens.get(self.next).map_or(self.end, Span::of)
+    }
+
+    fn previous_span(&self) -> Span {
+        Span::of(&self.tokens[self.next - 1])
+    }
+
+    fn unexpected(&self, expected: &str) -> LineError {
+        match self.peek() {
+            Some(kind) => self.span().error(&format!("expected {}, found {}", expected, kind)),
+            None => self.span().error(&format!("expected {}", expected)),
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<(), LineError> {
+        if self.eat(&kind) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&kind.to_string()))
+        }
+    }
+
+    // `<` and `>` take the low and high byte of everything after them, so `#>table+1`
+    // is the high byte of table+1
+    fn expr(&mut self) -> Result<Expr, LineError> {
+        let span = self.span();
+        let op = match self.peek() {
+            Some(TokenKind::Less) => UnaryOp::LowByte,
+            Some(TokenKind::Greater) => UnaryOp::HighByte,
+            _ => return self.binary(0),
+        };
+        self.next += 1;
+        let operand = self.expr()?;
+        Ok(Expr { span: span.to(operand.span), kind: ExprKind::Unary(op, Box::new(operand)) })
+    }
+
+    // Operators of at least `min_precedence`, left to right
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, LineError> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.peek().and_then(binary_op) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.next += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Expr { span: left.span.to(right.span), kind: ExprKind::Binary(op, Box::new(left), Box::new(right)) };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, LineError> {
+        let span = self.span();
+        let kind = match self.peek() {
+            Some(TokenKind::Minus) => {
+                self.next += 1;
+                let operand = self.unary()?;
+                return Ok(Expr { span: span.to(operand.span), kind: ExprKind::Unary(UnaryOp::Negate, Box::new(operand)) });
+            },
+            Some(TokenKind::LParen) => {
+                self.next += 1;
+                let inner = self.expr()?;
+                self.expect(TokenKind::RParen)?;
+                return Ok(Expr { span: span.to(self.previous_span()), kind: inner.kind });
+            },
+            Some(TokenKind::Number { value, wide }) => ExprKind::Number { value: *value, wide: *wide },
+            Some(TokenKind::Ident(name)) => ExprKind::Label(name.clone()),
+            Some(TokenKind::Star) => ExprKind::Pc,
+            _ => return Err(self.unexpected("an address, a number or a label")),
+        };
+        self.next += 1;
+        Ok(Expr { kind, span })
+    }
+
+    // `x` or `y`, after a comma
+    fn index(&mut self) -> Result<Index, LineError> {
+        let index = match self.peek() {
+            Some(TokenKind::Ident(name)) if name.eq_ignore_ascii_case("x") => Index::X,
+            Some(TokenKind::Ident(name)) if name.eq_ignore_ascii_case("y") => Index::Y,
+            _ => return Err(self.unexpected("X or Y")),
+        };
+        self.next += 1;
+        Ok(index)
+    }
+
+    fn operand(&mut self) -> Result<Operand, LineError> {
+        if self.peek().is_none() {
+            return Ok(Operand::None);
+        }
+        if self.eat(&TokenKind::Hash) {
+            return Ok(Operand::Immediate(self.expr()?));
+        }
+        if self.peek() == Some(&TokenKind::LParen) {
+            let start = self.next;
+            self.next += 1;
+            let expr = self.expr()?;
+            if self.eat(&TokenKind::Comma) {
+                let index_span = self.span();
+                if self.index()? != Index::X {
+                    return Err(index_span.error("only X can index inside the parentheses")
+                        .with_hint("(zp,X) is indexed indirect, (zp),Y is indirect indexed".to_string()));
+                }
+                self.expect(TokenKind::RParen)?;
+                return Ok(Operand::IndexedIndirect(expr));
+            }
+            self.expect(TokenKind::RParen)?;
+            if self.eat(&TokenKind::Comma) {
+                let index_span = self.span();
+                if self.index()? != Index::Y {
+                    return Err(index_span.error("only Y can index after the parentheses")
+                        .with_hint("(zp,X) is indexed indirect, (zp),Y is indirect indexed".to_string()));
+                }
+                return Ok(Operand::IndirectIndexed(expr));
+            }
+            if self.peek().is_none() {
+                return Ok(Operand::Indirect(expr));
+            }
+            // the parentheses were only the start of an expression, like `(base+1)*2`
+            self.next = start;
+        }
+        let expr = self.expr()?;
+        let index = if self.eat(&TokenKind::Comma) { self.index()? } else { Index::None };
+        Ok(Operand::Direct(expr, index))
+    }
+
+    // Comma separated expressions and strings, up to the end of the line
+    fn args(&mut self) -> Result<Vec<Arg>, LineError> {
+        let mut args = Vec::new();
+        if self.peek().is_none() {
+            return Ok(args);
+        }
+        loop {
+            match self.peek() {
+                Some(TokenKind::Str(text)) => {
+                    args.push(Arg::Str(text.clone(), self.span()));
+                    self.next += 1;
+                },
+                _ => args.push(Arg::Expr(self.expr()?)),
+            }
+            if self.peek().is_none() {
+                return Ok(args);
+            }
+            self.expect(TokenKind::Comma)?;
+        }
+    }
+
+    fn line(&mut self) -> Result<Line, LineError> {
+        let mut line = Line { label: None, instruction: None, directive: None };
+
+        // `name:` defines a label
+        if let (Some(TokenKind::Ident(name)), Some(TokenKind::Colon)) = (self.peek(), self.tokens.get(1).map(|token| &token.kind)) {
+            line.label = Some((name.clone(), self.span()));
+            self.next += 2;
+        }
+
+        let span = self.span();
+        let mnemonic = match self.advance() {
+            None => return Ok(line),
+            Some(Token { kind: TokenKind::Ident(name), .. }) => name.to_lowercase(),
+            Some(token) => return Err(Span::of(token).error(&format!("expected an instruction, found {}", token.kind))),
+        };
+        if mnemonic.starts_with('.') {
+            line.directive = Some(Directive { name: mnemonic, span, args: self.args()? });
+            return Ok(line);
+        }
+        let (mnemonic, width) = match mnemonic.find('.') {
+            Some(dot) if dot > 0 => {
+                let width = match &mnemonic[dot + 1..] {
+                    "z" => Width::ZeroPage,
+                    "w" => Width::Absolute,
+                    suffix => {
+                        let error = span.error(&format!("unknown suffix `.{}`", suffix));
+                        return Err(error.with_hint("`.w` forces absolute addressing and `.z` zero page".to_string()));
+                    },
+                };
+                (mnemonic[..dot].to_string(), Some(width))
+            },
+            _ => (mnemonic, None),
+        };
+
+        let operand_start = self.span();
+        let operand = self.operand()?;
+        let operand_span = match operand {
+            Operand::None => span,
+            _ => operand_start.to(self.previous_span()),
+        };
+        if self.peek().is_some() {
+            return Err(self.unexpected("the end of the line"));
+        }
+
+        line.instruction = Some(Instruction {
+            mnemonic,
+            width,
+            span,
+            operand,
+            operand_span,
+        });
+        Ok(line)
+    }
+}
+
+// Which operator a token is, and how tightly it binds
+fn binary_op(kind: &TokenKind) -> Option<(BinaryOp, u8)> {
+    match kind {
+        TokenKind::Pipe => Some((BinaryOp::Or, 1)),
+        TokenKind::Caret => Some((BinaryOp::Xor, 2)),
+        TokenKind::Amp => Some((BinaryOp::And, 3)),
+        TokenKind::ShiftLeft => Some((BinaryOp::ShiftLeft, 4)),
+        TokenKind::ShiftRight => Some((BinaryOp::ShiftRight, 4)),
+        TokenKind::Plus => Some((BinaryOp::Add, 5)),
+        TokenKind::Minus => Some((BinaryOp::Subtract, 5)),
+        TokenKind::Star => Some((BinaryOp::Multiply, 6)),
+        TokenKind::Slash => Some((BinaryOp::Divide, 6)),
+        _ => None,
+    }
+}
+
+/// Parse the tokens of one line, `length` bytes long
+pub fn parse_line(tokens: &[Token], length: usize) -> Result<Line, LineError> {
+    let mut parser = Parser {
+        tokens,
+        next: 0,
+        end: Span { column: length, length: 1 },
+    };
+    parser.line()
+}
+
+/// Parse tokens that are just an expression
+pub fn parse_expr(tokens: &[Token], length: usize) -> Result<Expr, LineError> {
+    let mut parser = Parser {
+        tokens,
+        next: 0,
+        end: Span { column: length, length: 1 },
+    };
+    let expr = parser.expr()?;
+    match parser.peek() {
+        Some(_) => Err(parser.unexpected("the end of the expression")),
+        None => Ok(expr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn parse(line: &str) -> Result<Line, LineError> {
+        parse_line(&tokenize(line).unwrap(), line.len())
+    }
+
+    fn label(name: &str, column: usize, length: usize) -> Expr {
+        Expr { kind: ExprKind::Label(name.to_string()), span: Span { column, length } }
+    }
+
+    #[test]
+    fn test_parse() {
+        let line = parse("loop: LDA table,X").unwrap();
+        assert_eq!(line.label, Some(("loop".to_string(), Span { column: 0, length: 4 })));
+        let instruction = line.instruction.unwrap();
+        assert_eq!(instruction.mnemonic, "lda");
+        assert_eq!(instruction.width, None);
+        assert_eq!(instruction.operand, Operand::Direct(label("table", 10, 5), Index::X));
+        assert_eq!(instruction.operand_span, Span { column: 10, length: 7 });
+
+        let line = parse("  jmp (vector)").unwrap();
+        assert_eq!(line.label, None);
+        assert_eq!(line.instruction.unwrap().operand, Operand::Indirect(label("vector", 7, 6)));
+
+        assert_eq!(parse("sta (ptr,x)").unwrap().instruction.unwrap().operand, Operand::IndexedIndirect(label("ptr", 5, 3)));
+        assert_eq!(parse("sta (ptr),y").unwrap().instruction.unwrap().operand, Operand::IndirectIndexed(label("ptr", 5, 3)));
+        assert_eq!(parse("rts").unwrap().instruction.unwrap().operand, Operand::None);
+        let instruction = parse("LDA.W $10").unwrap().instruction.unwrap();
+        assert_eq!((instruction.mnemonic.as_str(), instruction.width), ("lda", Some(Width::Absolute)));
+
+        // parentheses that aren't indirection
+        let operand = parse("lda (base+1)*2,x").unwrap().instruction.unwrap().operand;
+        match operand {
+            Operand::Direct(Expr { kind: ExprKind::Binary(BinaryOp::Multiply, _, _), span }, Index::X) => {
+                assert_eq!(span, Span { column: 4, length: 10 });
+            },
+            other => panic!("expected a product indexed by X, got {:?}", other),
+        }
+        let operand = parse("lda (ptr+1),y").unwrap().instruction.unwrap().operand;
+        match operand {
+            Operand::IndirectIndexed(Expr { kind: ExprKind::Binary(BinaryOp::Add, _, _), .. }) => (),
+            other => panic!("expected (ptr+1),Y, got {:?}", other),
+        }
+        assert_eq!(parse("end:").unwrap().instruction, None);
+        assert_eq!(parse("").unwrap(), Line { label: None, instruction: None, directive: None });
+
+        let line = parse("message: .TEXT \"hi\", 0").unwrap();
+        assert_eq!(line.label.unwrap().0, "message");
+        let directive = line.directive.unwrap();
+        assert_eq!(directive.name, ".text");
+        assert_eq!(directive.args[0], Arg::Str("hi".to_string(), Span { column: 15, length: 4 }));
+        assert_eq!(directive.args[1].span(), Span { column: 21, length: 1 });
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("lda #").unwrap_err(), LineError::new(5, 1, "expected an address, a number or a label"));
+        assert_eq!(parse("lda $10,z").unwrap_err(), LineError::new(8, 1, "expected X or Y, found `z`"));
+        assert_eq!(parse("lda ($10),x").unwrap_err().message, "only Y can index after the parentheses");
+        assert_eq!(parse("lda ($10").unwrap_err(), LineError::new(8, 1, "expected `)`"));
+        assert_eq!(parse("lda #1+").unwrap_err(), LineError::new(7, 1, "expected an address, a number or a label"));
+        assert_eq!(parse("lda $10 $20").unwrap_err(), LineError::new(8, 3, "expected the end of the line, found a number"));
+        assert_eq!(parse("lda.q $10").unwrap_err(), LineError::new(0, 5, "unknown suffix `.q`")
+            .with_hint("`.w` forces absolute addressing and `.z` zero page".to_string()));
+        assert_eq!(parse(".byte 1 2").unwrap_err(), LineError::new(8, 1, "expected `,`, found a number"));
+        assert_eq!(parse("#$10").unwrap_err(), LineError::new(0, 1, "expected an instruction, found `#`"));
+    }
+}
@@ -0,0 +1,35 @@
+extern crate cbindgen;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Generate the C header from the C API in src/capi.rs into OUT_DIR, and warn when the copy
+// checked in as include/emulator_6502.h doesn't match it any more. The build never writes to
+// the source tree; updating the header is left to the command in the warning.
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=include/emulator_6502.h");
+
+    // only src/capi.rs, so the rest of the crate's public constants stay out of the header
+    let config = cbindgen::Config::from_root_or_default(&crate_dir);
+    let capi = Path::new(&crate_dir).join("src/capi.rs");
+    let bindings = match cbindgen::Builder::new().with_src(capi).with_config(config).generate() {
+        Ok(bindings) => bindings,
+        Err(error) => {
+            println!("cargo:warning=couldn't generate the C header: {}", error);
+            return;
+        },
+    };
+
+    let generated = Path::new(&out_dir).join("emulator_6502.h");
+    bindings.write_to_file(&generated);
+    let checked_in = Path::new(&crate_dir).join("include/emulator_6502.h");
+    if fs::read(&generated).ok() != fs::read(&checked_in).ok() {
+        println!("cargo:warning=include/emulator_6502.h is out of date with src/capi.rs, update it with: cp {} {}",
+                 generated.display(), checked_in.display());
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Drives the emulator through its C API: ROM, RAM and a memory mapped output port, running
+ * to a BRK, registers, breakpoints, errors and snapshots.
+ *
+ *   $ cargo build
+ *   $ cc -Iinclude c/test_capi.c -Ltarget/debug -lcpu_emulator -o target/test_capi
+ *   $ LD_LIBRARY_PATH=target/debug target/test_capi
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "emulator_6502.h"
+
+static int failures = 0;
+
+#define CHECK(condition) do { \
+        if (!(condition)) { \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #condition); \
+            failures++; \
+        } \
+    } while (0)
+
+/* An output port at $D000 that collects what's written to it */
+struct port {
+    char text[32];
+    size_t length;
+};
+
+static uint8_t port_read(void *user, uint16_t reg) {
+    struct port *port = user;
+    return reg == 1 ? (uint8_t)port->length : 0;
+}
+
+static void port_write(void *user, uint16_t reg, uint8_t value) {
+    struct port *port = user;
+    if (reg == 0 && port->length < sizeof(port->text) - 1) {
+        port->text[port->length++] = (char)value;
+    }
+}
+
+/*
+ * $FF00: ldx #0
+ * $FF02: lda $FF11,x / beq done / sta $D000 / inx / bne $FF02
+ * $FF0D: done: lda $D001 / brk
+ * $FF11: "hello", 0
+ */
+static const uint8_t program[] = {
+    0xA2, 0x00,
+    0xBD, 0x11, 0xFF, 0xF0, 0x06, 0x8D, 0x00, 0xD0, 0xE8, 0xD0, 0xF5,
+    0xAD, 0x01, 0xD0, 0x00,
+    'h', 'e', 'l', 'l', 'o', 0,
+};
+
+int main(void) {
+    uint8_t rom[0x100] = {0};
+    memcpy(rom, program, sizeof(program));
+    rom[0xFC] = 0x00;
+    rom[0xFD] = 0xFF;
+
+    struct port port = {{0}, 0};
+    EmuMachine *machine = emu_create();
+    CHECK(emu_map_memory(machine, 0x0000, 0x0800, false, NULL, 0) == EMU_OK);
+    CHECK(emu_map_memory(machine, 0xFF00, 0x0100, true, rom, sizeof(rom)) == EMU_OK);
+    CHECK(emu_map_io(machine, 0xD000, 2, port_read, port_write, &port) == EMU_OK);
+    CHECK(emu_map_memory(machine, 0x0700, 0x0200, false, NULL, 0) == EMU_ERR_OVERLAPPING);
+
+    /* run to the BRK */
+    CHECK(emu_reset(machine) == EMU_OK);
+    CHECK(emu_set_stop_on_brk(machine, true) == EMU_OK);
+    uint16_t stop_pc = 0;
+    CHECK(emu_run(machine, 10000, &stop_pc) == EMU_STOP_BRK);
+    CHECK(stop_pc == 0xFF10);
+    CHECK(strcmp(port.text, "hello") == 0);
+
+    EmuRegisters registers;
+    CHECK(emu_get_registers(machine, &registers) == EMU_OK);
+    CHECK(registers.a == 5);
+    CHECK(registers.x == 5);
+
+    /* a breakpoint at the sta, after setting the registers to skip the first letter */
+    port.length = 0;
+    memset(port.text, 0, sizeof(port.text));
+    registers.pc = 0xFF02;
+    registers.x = 1;
+    CHECK(emu_set_registers(machine, &registers) == EMU_OK);
+    CHECK(emu_add_breakpoint(machine, 0xFF07) == EMU_OK);
+    CHECK(emu_run(machine, 10000, &stop_pc) == EMU_STOP_BREAKPOINT);
+    CHECK(stop_pc == 0xFF07);
+    CHECK(emu_remove_breakpoint(machine, 0xFF07) == EMU_OK);
+
+    /* snapshot here, run to the end, and come back */
+    uint8_t value = 0;
+    CHECK(emu_write(machine, 0x0200, 0x42) == EMU_OK);
+    size_t size = emu_snapshot_size(machine);
+    uint8_t *snapshot = malloc(size);
+    CHECK(emu_snapshot(machine, snapshot, size) == EMU_OK);
+
+    CHECK(emu_run(machine, 10000, NULL) == EMU_STOP_BRK);
+    CHECK(strcmp(port.text, "ello") == 0);
+    CHECK(emu_write(machine, 0x0200, 0x00) == EMU_OK);
+
+    CHECK(emu_restore(machine, snapshot, size) == EMU_OK);
+    CHECK(emu_read(machine, 0x0200, &value) == EMU_OK);
+    CHECK(value == 0x42);
+    CHECK(emu_get_registers(machine, &registers) == EMU_OK);
+    CHECK(registers.pc == 0xFF07);
+    CHECK(registers.a == 'e');
+    CHECK(emu_restore(machine, snapshot, size - 1) == EMU_ERR_ARGUMENT);
+    free(snapshot);
+
+    /* errors */
+    CHECK(emu_write(machine, 0xFF00, 0xEA) == EMU_ERR_ROM_WRITE);
+    CHECK(strcmp(emu_last_error(machine), "attempted to write to readonly memory at $ff00") == 0);
+    CHECK(emu_read(machine, 0x4000, &value) == EMU_ERR_UNMAPPED);
+    CHECK(emu_step(NULL) == EMU_ERR_ARGUMENT);
+
+    emu_destroy(machine);
+
+    if (failures) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("all checks passed\n");
+    return 0;
+}
@@ -0,0 +1,303 @@
+/* The C API of the 6502 emulator, generated from src/capi.rs by cbindgen. Don't edit. */
+
+#ifndef EMULATOR_6502_H
+#define EMULATOR_6502_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define EMU_OK 0
+
+#define EMU_ERR_UNMAPPED -1
+
+#define EMU_ERR_ROM_WRITE -2
+
+#define EMU_ERR_INVALID_BLOCK -3
+
+#define EMU_ERR_OVERLAPPING -4
+
+#define EMU_ERR_ILLEGAL_OPCODE -5
+
+#define EMU_ERR_STACK_WRAP -6
+
+#define EMU_ERR_JAMMED -7
+
+#define EMU_ERR_ARGUMENT -8
+
+#define EMU_ERR_OTHER -9
+
+#define EMU_ERR_PANIC -10
+
+/**
+ * Why `emu_run` returned, see `StopReason`
+ */
+#define EMU_STOP_BUDGET 1
+
+#define EMU_STOP_FRAME 2
+
+#define EMU_STOP_BREAKPOINT 3
+
+#define EMU_STOP_BRK 4
+
+#define EMU_STOP_SELF_LOOP 5
+
+/**
+ * A 6502 and its memory map, only ever handled through a pointer
+ */
+typedef struct EmuMachine EmuMachine;
+
+/**
+ * Reads register `reg` (relative to where the device is mapped) of an I/O device
+ */
+typedef uint8_t (*EmuReadFn)(void *user, uint16_t reg);
+
+/**
+ * Writes register `reg` (relative to where the device is mapped) of an I/O device
+ */
+typedef void (*EmuWriteFn)(void *user, uint16_t reg, uint8_t value);
+
+/**
+ * The registers, and the cycles run so far
+ */
+typedef struct EmuRegisters {
+  uint16_t pc;
+  uint8_t a;
+  uint8_t x;
+  uint8_t y;
+  uint8_t s;
+  uint8_t p;
+  uint32_t cycles;
+} EmuRegisters;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * A machine with nothing mapped. Free it with `emu_destroy`.
+ */
+struct EmuMachine *emu_create(void);
+
+/**
+ * Free a machine, which may be NULL, saving battery-backed RAM first. Call `emu_flush`
+ * beforehand to find out if that fails.
+ *
+ * # Safety
+ *
+ * `machine` is NULL or came from `emu_create`, and isn't used again afterwards,
+ * including by another `emu_destroy`.
+ */
+void emu_destroy(struct EmuMachine *machine);
+
+/**
+ * What went wrong in the last call that failed. The string belongs to the machine, and
+ * lasts until the next failure.
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a machine from `emu_create` that hasn't been destroyed. The
+ * string is only valid until the next failing call on the machine or `emu_destroy`.
+ */
+const char *emu_last_error(const struct EmuMachine *machine);
+
+/**
+ * Map `length` bytes of RAM (or ROM if `readonly`) at `start`, filled from the first
+ * `data_length` bytes of `data`. `data` may be NULL to start out zeroed.
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `data` is NULL or points
+ * to at least `data_length` readable bytes.
+ */
+int32_t emu_map_memory(struct EmuMachine *machine,
+                       uint32_t start,
+                       uint32_t length,
+                       bool readonly,
+                       const uint8_t *data,
+                       uint32_t data_length);
+
+/**
+ * Map `length` bytes of battery-backed RAM at `start`, kept in the file at `path`: loaded
+ * from it if it exists, and saved to it as the cpu runs and by `emu_flush`
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `path` is NULL or a
+ * NUL terminated string.
+ */
+int32_t emu_map_battery_ram(struct EmuMachine *machine,
+                            uint32_t start,
+                            uint32_t length,
+                            const char *path);
+
+/**
+ * Save battery-backed RAM that has changed
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_flush(struct EmuMachine *machine);
+
+/**
+ * Map an I/O device taking up `length` addresses at `start`. Reads and writes there call
+ * `read` and `write` (either may be NULL) with `user` and the offset from `start`.
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`. `read` and `write` are called
+ * with `user` whenever the cpu touches the device, from whichever thread runs the
+ * machine, until it is destroyed, so `user` has to stay valid (and usable from that
+ * thread) for that long.
+ */
+int32_t emu_map_io(struct EmuMachine *machine,
+                   uint32_t start,
+                   uint32_t length,
+                   EmuReadFn read,
+                   EmuWriteFn write,
+                   void *user);
+
+/**
+ * Start the cpu from the reset vector at $FFFC, with interrupts masked
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_reset(struct EmuMachine *machine);
+
+/**
+ * Run one instruction
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, not being used on another
+ * thread. I/O callbacks may be called before it returns.
+ */
+int32_t emu_step(struct EmuMachine *machine);
+
+/**
+ * Run until `cycles` have passed or something else stops the cpu, giving back one of the
+ * `EMU_STOP_` reasons (or an error). With `stop_pc` not NULL, it is set to where the cpu
+ * stopped.
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, not being used on another
+ * thread, and `stop_pc` is NULL or points to a writable `uint16_t`.
+ */
+int32_t emu_run(struct EmuMachine *machine, uint32_t cycles, uint16_t *stop_pc);
+
+/**
+ * Make `emu_run` stop every `cycles` cycles, 0 for never
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_set_frame_cycles(struct EmuMachine *machine, uint32_t cycles);
+
+/**
+ * Make `emu_run` stop before running a BRK, rather than taking it through the IRQ vector
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_set_stop_on_brk(struct EmuMachine *machine, bool stop);
+
+/**
+ * Stop `emu_run` before the instruction at `addr`
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_add_breakpoint(struct EmuMachine *machine, uint16_t addr);
+
+/**
+ * Undo `emu_add_breakpoint`
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_remove_breakpoint(struct EmuMachine *machine, uint16_t addr);
+
+/**
+ * Copy the registers into `registers`
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `registers` is NULL or
+ * points to a writable `EmuRegisters`.
+ */
+int32_t emu_get_registers(struct EmuMachine *machine, struct EmuRegisters *registers);
+
+/**
+ * Set the registers, and the cycle count, from `registers`
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `registers` is NULL or
+ * points to an `EmuRegisters`.
+ */
+int32_t emu_set_registers(struct EmuMachine *machine, const struct EmuRegisters *registers);
+
+/**
+ * Read a byte the way the cpu would, so I/O devices see the read
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `value` is NULL or points
+ * to a writable byte.
+ */
+int32_t emu_read(struct EmuMachine *machine, uint16_t addr, uint8_t *value);
+
+/**
+ * Write a byte the way the cpu would, so ROM can't be written
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+int32_t emu_write(struct EmuMachine *machine, uint16_t addr, uint8_t value);
+
+/**
+ * How many bytes `emu_snapshot` needs, or 0 if `machine` is NULL
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`.
+ */
+size_t emu_snapshot_size(const struct EmuMachine *machine);
+
+/**
+ * Save the registers and all of memory (but not the state of I/O devices) into `buffer`,
+ * which holds `length` bytes
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `buffer` is NULL or
+ * points to `length` writable bytes.
+ */
+int32_t emu_snapshot(struct EmuMachine *machine, uint8_t *buffer, size_t length);
+
+/**
+ * Go back to a snapshot taken with `emu_snapshot`, from a machine with the same memory map
+ *
+ * # Safety
+ *
+ * `machine` is NULL or a live machine from `emu_create`, and `buffer` is NULL or
+ * points to `length` readable bytes.
+ */
+int32_t emu_restore(struct EmuMachine *machine, const uint8_t *buffer, size_t length);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* EMULATOR_6502_H */
@@ -0,0 +1,208 @@
+use device::Device;
+use serial::SerialPort;
+
+// register numbers, relative to where the ACIA is mapped
+const DATA: usize = 0x0;
+const STATUS: usize = 0x1;
+const COMMAND: usize = 0x2;
+const CONTROL: usize = 0x3;
+
+// status register bits
+pub const STATUS_IRQ: u8 = 0x80;
+pub const STATUS_TDRE: u8 = 0x10;
+pub const STATUS_RDRF: u8 = 0x08;
+
+// How often the port is checked for a new byte. At 1MHz this is about one character time at
+// 9600 baud, and it keeps the host side from being polled on every instruction.
+const POLL_CYCLES: u32 = 1000;
+
+/// A MOS 6551 Asynchronous Communications Interface Adapter, a serial port whose other end
+/// is a `SerialPort` on the host. Map it with `MMU::add_device`, it takes up 4 addresses.
+///
+/// Characters go out as soon as they are written, so the transmit register always reads as
+/// empty. Received characters wait on the host side until the last one has been read, so
+/// they are never overrun. The baud rate and frame format in the control register, and
+/// parity, are kept but don't change anything.
+///
+/// As on the real part the receiver only runs with DTR (command bit 0) set. It interrupts
+/// unless command bit 1 is set, the transmitter interrupts after each character when
+/// command bits 2-3 are 01, and command bit 4 echoes everything received.
+#[derive(Debug)]
+pub struct Acia {
+    pub port: Box<dyn SerialPort>,
+    pub received: u8,
+    pub command: u8,
+    pub control: u8,
+    receive_full: bool,
+    irq: bool,
+    poll_timer: u32,
+}
+
+impl Acia {
+    pub fn new(port: Box<dyn SerialPort>) -> Acia {
+        Acia {
+            port: port,
+            received: 0,
+            command: 0,
+            control: 0,
+            receive_full: false,
+            irq: false,
+            poll_timer: 0,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_TDRE;
+        if self.receive_full {
+            status |= STATUS_RDRF;
+        }
+        if self.irq {
+            status |= STATUS_IRQ;
+        }
+        status
+    }
+
+    fn poll(&mut self) {
+        if self.receive_full || self.command & 0x01 == 0 {
+            return;
+        }
+        if let Some(byte) = self.port.receive() {
+            self.received = byte;
+            self.receive_full = true;
+            if self.command & 0x10 != 0 {
+                self.port.send(byte);
+            }
+            if self.command & 0x02 == 0 {
+                self.irq = true;
+            }
+        }
+    }
+}
+
+impl Device for Acia {
+    fn length(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, reg: usize) -> u8 {
+        match reg {
+            DATA => {
+                self.receive_full = false;
+                self.received
+            },
+            // reading the status acknowledges the interrupt
+            STATUS => {
+                let status = self.status();
+                self.irq = false;
+                status
+            },
+            COMMAND => self.command,
+            CONTROL => self.control,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: usize, val: u8) {
+        match reg {
+            DATA => {
+                self.port.send(val);
+                if self.command & 0x0C == 0x04 {
+                    self.irq = true;
+                }
+            },
+            // a write to the status register is a programmed reset
+            STATUS => {
+                self.command &= 0xE0;
+                self.irq = false;
+            },
+            COMMAND => self.command = val,
+            CONTROL => self.control = val,
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if cycles >= self.poll_timer {
+            self.poll_timer = POLL_CYCLES;
+            self.poll();
+        } else {
+            self.poll_timer -= cycles;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use serial::ChannelPort;
+
+    #[test]
+    fn test_transmit() {
+        let (port, _, output) = ChannelPort::new();
+        let mut acia = Acia::new(Box::new(port));
+
+        assert_eq!(acia.read(STATUS), STATUS_TDRE);
+        acia.write(DATA, b'O');
+        acia.write(DATA, b'K');
+        assert_eq!(output.try_iter().collect::<Vec<u8>>(), b"OK".to_vec());
+        assert!(!acia.irq());
+
+        // transmit interrupts
+        acia.write(COMMAND, 0x05);
+        acia.write(DATA, b'!');
+        assert!(acia.irq());
+        assert_eq!(acia.read(STATUS), STATUS_TDRE | STATUS_IRQ);
+        assert!(!acia.irq());
+    }
+
+    #[test]
+    fn test_receive() {
+        let (port, input, _) = ChannelPort::new();
+        let mut acia = Acia::new(Box::new(port));
+        input.send(b'A').unwrap();
+        input.send(b'B').unwrap();
+
+        // nothing comes in until DTR is set
+        acia.tick(POLL_CYCLES);
+        assert_eq!(acia.read(STATUS), STATUS_TDRE);
+
+        // DTR, receive interrupts disabled
+        acia.write(COMMAND, 0x03);
+        acia.tick(POLL_CYCLES);
+        assert_eq!(acia.read(STATUS), STATUS_TDRE | STATUS_RDRF);
+        assert!(!acia.irq());
+
+        // B waits until A has been read, and until the next poll
+        acia.tick(POLL_CYCLES);
+        assert_eq!(acia.read(DATA), b'A');
+        assert_eq!(acia.read(STATUS), STATUS_TDRE);
+        acia.tick(POLL_CYCLES - 1);
+        assert_eq!(acia.read(STATUS), STATUS_TDRE);
+        acia.tick(1);
+        assert_eq!(acia.read(DATA), b'B');
+    }
+
+    #[test]
+    fn test_receive_interrupt_and_echo() {
+        let (port, input, output) = ChannelPort::new();
+        let mut acia = Acia::new(Box::new(port));
+
+        // DTR, receive interrupts, echo
+        acia.write(COMMAND, 0x11);
+        input.send(b'x').unwrap();
+        acia.tick(1);
+        assert!(acia.irq());
+        assert_eq!(output.try_recv(), Ok(b'x'));
+        assert_eq!(acia.read(STATUS), STATUS_TDRE | STATUS_RDRF | STATUS_IRQ);
+        assert_eq!(acia.read(DATA), b'x');
+
+        // programmed reset drops the low command bits
+        acia.write(STATUS, 0);
+        assert_eq!(acia.read(COMMAND), 0x00);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+
+use cpu::{CPU, StopReason};
+use device::Device;
+use error::EmuError;
+use mmu::{Block, MMU};
+use serial::{SerialPort, StdioPort};
+
+// register numbers, relative to $D010
+const KBD: usize = 0x0;
+const KBDCR: usize = 0x1;
+const DSP: usize = 0x2;
+const DSPCR: usize = 0x3;
+
+// control register bit 2 switches the data address between the port and its DDR
+const CR_PORT: u8 = 0x04;
+
+// how often the terminal is checked for a key press, see the ACIA
+const POLL_CYCLES: u32 = 1000;
+
+/// The Apple-1's 6820 PIA, with the keyboard on port A and the display on port B, both
+/// connected to a terminal on the host.
+///
+/// Keys arrive uppercased with bit 7 set, and KBDCR bit 7 says one is waiting until KBD is
+/// read. Characters written to DSP are shown straight away, so DSP bit 7 (display busy) is
+/// always clear. Only the parts of the PIA the Apple-1 uses are modelled, there are no
+/// interrupts.
+#[derive(Debug)]
+pub struct Apple1Pia {
+    pub terminal: Box<dyn SerialPort>,
+    pub key: u8,
+    pub key_ready: bool,
+    pub kbdcr: u8,
+    pub dspcr: u8,
+    pub ddra: u8,
+    pub ddrb: u8,
+    poll_timer: u32,
+}
+
+impl Apple1Pia {
+    pub fn new(terminal: Box<dyn SerialPort>) -> Apple1Pia {
+        Apple1Pia {
+            terminal: terminal,
+            key: 0,
+            key_ready: false,
+            kbdcr: 0,
+            dspcr: 0,
+            ddra: 0,
+            ddrb: 0,
+            poll_timer: 0,
+        }
+    }
+
+    fn display(&mut self, val: u8) {
+        match val & 0x7F {
+            // carriage return starts a new line
+            0x0D => {
+                self.terminal.send(b'\r');
+                self.terminal.send(b'\n');
+            },
+            // the display has no lower case or control characters
+            c @ 0x20..=0x5F => self.terminal.send(c),
+            _ => {},
+        }
+    }
+}
+
+impl Device for Apple1Pia {
+    fn length(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, reg: usize) -> u8 {
+        match reg {
+            KBD if self.kbdcr & CR_PORT != 0 => {
+                self.key_ready = false;
+                self.key
+            },
+            KBD => self.ddra,
+            KBDCR => if self.key_ready { self.kbdcr | 0x80 } else { self.kbdcr },
+            // bit 7 clear, the display is always ready
+            DSP if self.dspcr & CR_PORT != 0 => 0x00,
+            DSP => self.ddrb,
+            DSPCR => self.dspcr,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: usize, val: u8) {
+        match reg {
+            KBD if self.kbdcr & CR_PORT == 0 => self.ddra = val,
+            // the bits above 5 are status, and read only
+            KBDCR => self.kbdcr = val & 0x3F,
+            DSP if self.dspcr & CR_PORT != 0 => self.display(val),
+            DSP => self.ddrb = val,
+            DSPCR => self.dspcr = val & 0x3F,
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if cycles < self.poll_timer {
+            self.poll_timer -= cycles;
+            return;
+        }
+        self.poll_timer = POLL_CYCLES;
+
+        if !self.key_ready {
+            if let Some(byte) = self.terminal.receive() {
+                let byte = if byte == b'\n' { b'\r' } else { byte };
+                self.key = byte.to_ascii_uppercase() | 0x80;
+                self.key_ready = true;
+            }
+        }
+    }
+}
+
+// Construct an Apple-1 with 32K of RAM from $0000 plus 4K at $E000 (where Integer BASIC is
+// loaded), the 256 byte monitor ROM at $FF00, and the keyboard and display PIA at $D010
+// connected to `terminal`. The pc starts at the reset vector in the ROM.
+pub fn make_apple1_cpu(rom: Vec<u8>, terminal: Box<dyn SerialPort>) -> Result<CPU, EmuError> {
+        let mut mmu = MMU::new(&Vec::new())?;
+        // RAM
+        mmu.add_block(&Block::new(0x0000, 0x8000, false, None)?)?;
+        mmu.add_block(&Block::new(0xE000, 0x1000, false, None)?)?;
+        // ROM
+        mmu.add_block(&Block::new(0xFF00, 0x100, true, Some(rom))?)?;
+        // PIA
+        mmu.add_device(0xD010, Box::new(Apple1Pia::new(terminal)))?;
+
+        let mut cpu = CPU::new(mmu);
+        cpu.r.pc = cpu.mmu.read_word(0xFFFC)?;
+        Ok(cpu)
+}
+
+// Run an Apple-1 on this terminal, with the monitor ROM from `rom_file_path`. Each entry in
+// `images` is `file@address`, a program loaded into RAM before starting (ie
+// `basic.bin@E000`), which can then be run from the monitor. Returns what went wrong if it
+// couldn't start, or the cpu stopped with an error.
+pub fn run_apple1(rom_file_path: &str, images: &[String]) -> Result<(), String> {
+    let rom = fs::read(rom_file_path)
+        .map_err(|error| format!("could not load {}: {}", rom_file_path, error))?;
+    let mut cpu = make_apple1_cpu(rom, Box::new(StdioPort::new()))
+        .map_err(|error| format!("could not load {}: {}", rom_file_path, error))?;
+
+    for image in images {
+        let mut parts = image.splitn(2, '@');
+        let path = parts.next().unwrap_or_default();
+        let addr = parts.next()
+            .and_then(|addr| usize::from_str_radix(addr.trim_start_matches('$'), 16).ok())
+            .ok_or_else(|| format!("{} isn't file@address (ie basic.bin@E000)", image))?;
+
+        let data = fs::read(path)
+            .map_err(|error| format!("could not load {}: {}", path, error))?;
+        cpu.mmu.load(addr, &data)
+            .map_err(|error| format!("could not load {}: {}", path, error))?;
+    }
+
+    loop {
+        match cpu.run(u32::MAX) {
+            Ok(StopReason::Budget) => continue,
+            Ok(reason) => {
+                println!("\ncpu stopped: {}", reason);
+                return Ok(());
+            },
+            Err(error) => {
+                // off the line the program was printing on
+                println!();
+                return Err(format!("cpu stopped: {}", error));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use serial::ChannelPort;
+
+    // Set up the PIA the same way the Woz monitor does, then echo keys forever:
+    //
+    //   ldy #$7f / sty $d012 / lda #$a7 / sta $d011 / sta $d013
+    //   loop: lda $d011 / bpl loop / lda $d010
+    //   wait: bit $d012 / bmi wait / sta $d012 / jmp loop
+    fn make_rom() -> Vec<u8> {
+        let mut rom = vec![
+            0xA0, 0x7F, 0x8C, 0x12, 0xD0, 0xA9, 0xA7, 0x8D, 0x11, 0xD0, 0x8D, 0x13, 0xD0,
+            0xAD, 0x11, 0xD0, 0x10, 0xFB, 0xAD, 0x10, 0xD0,
+            0x2C, 0x12, 0xD0, 0x30, 0xFB, 0x8D, 0x12, 0xD0, 0x4C, 0x0D, 0xFF,
+        ];
+        rom.resize(0x100, 0);
+        // reset vector
+        rom[0xFC] = 0x00;
+        rom[0xFD] = 0xFF;
+        rom
+    }
+
+    #[test]
+    fn test_echo() {
+        let (terminal, keys, screen) = ChannelPort::new();
+        let mut cpu = make_apple1_cpu(make_rom(), Box::new(terminal)).unwrap();
+        assert_eq!(cpu.r.pc, 0xFF00);
+
+        keys.send(b'a').unwrap();
+        keys.send(b'1').unwrap();
+        keys.send(b'\n').unwrap();
+        for _ in 0..4 {
+            assert_eq!(cpu.run(POLL_CYCLES), Ok(StopReason::Budget));
+        }
+
+        // the DDR write during setup doesn't show up on screen
+        assert_eq!(screen.try_iter().collect::<Vec<u8>>(), b"A1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_keyboard() {
+        let (terminal, keys, _) = ChannelPort::new();
+        let mut pia = Apple1Pia::new(Box::new(terminal));
+        pia.write(KBDCR, 0xA7);
+
+        assert_eq!(pia.read(KBDCR), 0x27);
+        keys.send(b'z').unwrap();
+        pia.tick(1);
+        assert_eq!(pia.read(KBDCR), 0xA7);
+        assert_eq!(pia.read(KBD), 0xDA);
+        assert_eq!(pia.read(KBDCR), 0x27);
+    }
+
+    #[test]
+    fn test_make_apple1_cpu_rom_too_big() {
+        let (terminal, _, _) = ChannelPort::new();
+        assert!(make_apple1_cpu(vec![0; 0x101], Box::new(terminal)).is_err());
+    }
+}
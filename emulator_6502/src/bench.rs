@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::time::Instant;
+
+use cpu::{make_snake_cpu, CPU};
+use error::EmuError;
+use mmu::{Block, MMU};
+
+// Builds the cpu for one of the benchmarks from its program
+type MakeCpu = fn(&[u8]) -> Result<CPU, EmuError>;
+
+// Snake on the same machine as the snake window. Nothing presses keys, so it's just the
+// game loop.
+fn make_snake_bench_cpu(rom: &[u8]) -> Result<CPU, EmuError> {
+    make_snake_cpu(Some(rom.to_vec()))
+}
+
+// nestest in its automated mode, which starts at $C000 and needs no PPU. The 16K PRG ROM
+// after the iNES header is mirrored at $8000 and $C000, and everything below it is RAM.
+fn make_nestest_cpu(nes: &[u8]) -> Result<CPU, EmuError> {
+    if nes.len() < 0x4010 {
+        return Err(EmuError::InvalidImage("nestest is too short".to_string()));
+    }
+    let prg = nes[0x10..0x4010].to_vec();
+    let mut mmu = MMU::new(&Vec::new())?;
+    mmu.add_block(&Block::new(0, 0x8000, false, None)?)?;
+    mmu.add_block(&Block::new(0x8000, 0x4000, true, Some(prg.clone()))?)?;
+    mmu.add_block(&Block::new(0xC000, 0x4000, true, Some(prg))?)?;
+
+    let mut cpu = CPU::new(mmu);
+    cpu.r.pc = 0xC000;
+    Ok(cpu)
+}
+
+// Run `instructions` instructions, starting the program over whenever it stops with an
+// error (ie at an illegal opcode), and give back how many ran per second.
+fn measure(cpu: &mut CPU, instructions: u64) -> f64 {
+    let entry = cpu.r.pc;
+    let start = Instant::now();
+    for _ in 0..instructions {
+        if cpu.step().is_err() {
+            cpu.r.pc = entry;
+            cpu.r.s = 0xFD;
+        }
+    }
+    instructions as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Print instructions per second for snake and nestest, first fetching every instruction
+/// through the MMU and then with the decode cache. Build with `--release` for real numbers.
+pub fn run_benchmarks(snake_path: &str, nestest_path: &str, instructions: u64) {
+    let programs: [(&str, MakeCpu); 2] = [
+        (snake_path, make_snake_bench_cpu),
+        (nestest_path, make_nestest_cpu),
+    ];
+
+    for &(path, make) in programs.iter() {
+        let mut data = Vec::new();
+        if let Err(error) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+            println!("{}: {}", path, error);
+            continue;
+        }
+
+        let results = [false, true].iter()
+            .map(|&cached| make(&data).map(|mut cpu| {
+                if cached {
+                    cpu.enable_decode_cache();
+                }
+                measure(&mut cpu, instructions)
+            }))
+            .collect::<Result<Vec<f64>, EmuError>>();
+
+        match results {
+            Ok(results) => println!("{}: {:.0} instructions/s uncached, {:.0} cached ({:.2}x)",
+                path, results[0], results[1], results[1] / results[0]),
+            Err(error) => println!("{}: {}", path, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+
+    #[test]
+    fn test_cached_matches_uncached() {
+        let mut nes = vec![0; 0x4010];
+        // $C000: inc $10 / ldx $10 / jmp $C000
+        nes[0x10..0x18].copy_from_slice(&[0xE6, 0x10, 0xA6, 0x10, 0x4C, 0x00, 0xC0, 0x00]);
+
+        let mut plain = make_nestest_cpu(&nes).unwrap();
+        let mut cached = make_nestest_cpu(&nes).unwrap();
+        cached.enable_decode_cache();
+        measure(&mut plain, 100);
+        measure(&mut cached, 100);
+
+        assert_eq!(cached.r.x, plain.r.x);
+        assert_eq!(cached.r.cc, plain.r.cc);
+        assert_eq!(cached.r.pc, plain.r.pc);
+    }
+}
@@ -0,0 +1,736 @@
+//! The C API, built into the cdylib. `include/emulator_6502.h` is generated from this file
+//! by cbindgen (see build.rs), and `c/test_capi.c` shows it in use.
+//!
+//! Functions returning `int32_t` give `EMU_OK` or one of the negative `EMU_ERR_` codes,
+//! with the details in `emu_last_error`. Every pointer passed in must be valid, or NULL
+//! where a function says it may be. A panic in the emulator is caught before it can unwind
+//! into C, and returned as `EMU_ERR_PANIC`.
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use cpu::{CPU, StopReason};
+use device::Device;
+use error::EmuError;
+use mmu::{Block, MMU};
+
+pub const EMU_OK: i32 = 0;
+pub const EMU_ERR_UNMAPPED: i32 = -1;
+pub const EMU_ERR_ROM_WRITE: i32 = -2;
+pub const EMU_ERR_INVALID_BLOCK: i32 = -3;
+pub const EMU_ERR_OVERLAPPING: i32 = -4;
+pub const EMU_ERR_ILLEGAL_OPCODE: i32 = -5;
+pub const EMU_ERR_STACK_WRAP: i32 = -6;
+pub const EMU_ERR_JAMMED: i32 = -7;
+pub const EMU_ERR_ARGUMENT: i32 = -8;
+pub const EMU_ERR_OTHER: i32 = -9;
+pub const EMU_ERR_PANIC: i32 = -10;
+
+/// Why `emu_run` returned, see `StopReason`
+pub const EMU_STOP_BUDGET: i32 = 1;
+pub const EMU_STOP_FRAME: i32 = 2;
+pub const EMU_STOP_BREAKPOINT: i32 = 3;
+pub const EMU_STOP_BRK: i32 = 4;
+pub const EMU_STOP_SELF_LOOP: i32 = 5;
+
+// snapshots start with this, and a format version
+const SNAPSHOT_MAGIC: &[u8] = b"6502";
+const SNAPSHOT_VERSION: u8 = 1;
+const SNAPSHOT_HEADER: usize = 5 + 11;
+
+/// A 6502 and its memory map, only ever handled through a pointer
+pub struct EmuMachine {
+    cpu: CPU,
+    last_error: CString,
+}
+
+/// The registers, and the cycles run so far
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EmuRegisters {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub cycles: u32,
+}
+
+/// Reads register `reg` (relative to where the device is mapped) of an I/O device
+pub type EmuReadFn = Option<extern "C" fn(user: *mut c_void, reg: u16) -> u8>;
+
+/// Writes register `reg` (relative to where the device is mapped) of an I/O device
+pub type EmuWriteFn = Option<extern "C" fn(user: *mut c_void, reg: u16, value: u8)>;
+
+// I/O handled by the program embedding the emulator
+struct CallbackDevice {
+    length: usize,
+    read: EmuReadFn,
+    write: EmuWriteFn,
+    user: *mut c_void,
+}
+
+// Whoever registers the callbacks is responsible for `user` being safe to use from wherever
+// the machine is stepped
+unsafe impl Send for CallbackDevice {}
+
+impl fmt::Debug for CallbackDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CallbackDevice {{ length: {:#x} }}", self.length)
+    }
+}
+
+impl Device for CallbackDevice {
+    fn length(&self) -> usize {
+        self.length
+    }
+
+    fn read(&mut self, reg: usize) -> u8 {
+        match self.read {
+            Some(read) => read(self.user, reg as u16),
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, reg: usize, val: u8) {
+        if let Some(write) = self.write {
+            write(self.user, reg as u16, val);
+        }
+    }
+}
+
+impl EmuMachine {
+    // Keep the message for `emu_last_error`, and give back the error's code
+    fn fail(&mut self, error: EmuError) -> i32 {
+        self.last_error = CString::new(error.to_string()).unwrap_or_default();
+        match error {
+            EmuError::UnmappedAddress(_) => EMU_ERR_UNMAPPED,
+            EmuError::RomWrite(_) => EMU_ERR_ROM_WRITE,
+            EmuError::InvalidBlock { .. } => EMU_ERR_INVALID_BLOCK,
+            EmuError::OverlappingBlock { .. } => EMU_ERR_OVERLAPPING,
+            EmuError::IllegalOpcode { .. } => EMU_ERR_ILLEGAL_OPCODE,
+            EmuError::StackWrap { .. } => EMU_ERR_STACK_WRAP,
+            EmuError::Jammed { .. } => EMU_ERR_JAMMED,
+            _ => EMU_ERR_OTHER,
+        }
+    }
+
+    fn fail_argument(&mut self, reason: &str) -> i32 {
+        self.last_error = CString::new(reason).unwrap_or_default();
+        EMU_ERR_ARGUMENT
+    }
+
+    fn result(&mut self, result: Result<(), EmuError>) -> i32 {
+        match result {
+            Ok(()) => EMU_OK,
+            Err(error) => self.fail(error),
+        }
+    }
+
+    fn snapshot_size(&self) -> usize {
+        SNAPSHOT_HEADER + self.cpu.mmu.blocks.iter().map(|block| block.length).sum::<usize>()
+    }
+}
+
+// Run the body of an API call, giving `on_panic` if it panics: unwinding into the C caller
+// is undefined behaviour. The machine may be left half way through whatever panicked, but
+// it's still safe to destroy.
+fn guard<T, F: FnOnce() -> T>(on_panic: T, body: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(on_panic)
+}
+
+// The machine behind a pointer from C, or return EMU_ERR_ARGUMENT
+macro_rules! machine {
+    ($pointer:expr) => {
+        match $pointer.as_mut() {
+            Some(machine) => machine,
+            None => return EMU_ERR_ARGUMENT,
+        }
+    };
+}
+
+/// A machine with nothing mapped. Free it with `emu_destroy`.
+#[no_mangle]
+pub extern "C" fn emu_create() -> *mut EmuMachine {
+    guard(ptr::null_mut(), || {
+        let mmu = MMU::new(&Vec::new()).expect("an empty MMU is always valid");
+        Box::into_raw(Box::new(EmuMachine {
+            cpu: CPU::new(mmu),
+            last_error: CString::default(),
+        }))
+    })
+}
+
+/// Free a machine, which may be NULL, saving battery-backed RAM first. Call `emu_flush`
+/// beforehand to find out if that fails.
+///
+/// # Safety
+///
+/// `machine` is NULL or came from `emu_create`, and isn't used again afterwards,
+/// including by another `emu_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_destroy(machine: *mut EmuMachine) {
+    guard((), || {
+        if !machine.is_null() {
+            let mut machine = Box::from_raw(machine);
+            let _ = machine.cpu.mmu.flush();
+        }
+    })
+}
+
+/// What went wrong in the last call that failed. The string belongs to the machine, and
+/// lasts until the next failure.
+///
+/// # Safety
+///
+/// `machine` is NULL or a machine from `emu_create` that hasn't been destroyed. The
+/// string is only valid until the next failing call on the machine or `emu_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_last_error(machine: *const EmuMachine) -> *const c_char {
+    guard(ptr::null(), || {
+        match machine.as_ref() {
+            Some(machine) => machine.last_error.as_ptr(),
+            None => ptr::null(),
+        }
+    })
+}
+
+/// Map `length` bytes of RAM (or ROM if `readonly`) at `start`, filled from the first
+/// `data_length` bytes of `data`. `data` may be NULL to start out zeroed.
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `data` is NULL or points
+/// to at least `data_length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn emu_map_memory(machine: *mut EmuMachine, start: u32, length: u32, readonly: bool,
+                                        data: *const u8, data_length: u32) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let contents = if data.is_null() {
+            None
+        } else {
+            Some(slice::from_raw_parts(data, data_length as usize).to_vec())
+        };
+        let result = Block::new(start as usize, length as usize, readonly, contents)
+            .and_then(|block| machine.cpu.mmu.add_block(&block));
+        machine.result(result)
+    })
+}
+
+/// Map `length` bytes of battery-backed RAM at `start`, kept in the file at `path`: loaded
+/// from it if it exists, and saved to it as the cpu runs and by `emu_flush`
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `path` is NULL or a
+/// NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn emu_map_battery_ram(machine: *mut EmuMachine, start: u32, length: u32,
+                                             path: *const c_char) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        if path.is_null() {
+            return machine.fail_argument("path is NULL");
+        }
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => Path::new(path),
+            Err(_) => return machine.fail_argument("path isn't UTF-8"),
+        };
+        let result = Block::new(start as usize, length as usize, false, None)
+            .and_then(|block| block.with_battery(path))
+            .and_then(|block| machine.cpu.mmu.add_block(&block));
+        machine.result(result)
+    })
+}
+
+/// Save battery-backed RAM that has changed
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_flush(machine: *mut EmuMachine) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let result = machine.cpu.mmu.flush();
+        machine.result(result)
+    })
+}
+
+/// Map an I/O device taking up `length` addresses at `start`. Reads and writes there call
+/// `read` and `write` (either may be NULL) with `user` and the offset from `start`.
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`. `read` and `write` are called
+/// with `user` whenever the cpu touches the device, from whichever thread runs the
+/// machine, until it is destroyed, so `user` has to stay valid (and usable from that
+/// thread) for that long.
+#[no_mangle]
+pub unsafe extern "C" fn emu_map_io(machine: *mut EmuMachine, start: u32, length: u32,
+                                    read: EmuReadFn, write: EmuWriteFn, user: *mut c_void) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        if length == 0 {
+            return machine.fail_argument("an I/O device needs at least one address");
+        }
+        let device = CallbackDevice {
+            length: length as usize,
+            read: read,
+            write: write,
+            user: user,
+        };
+        let result = machine.cpu.mmu.add_device(start as usize, Box::new(device));
+        machine.result(result)
+    })
+}
+
+/// Start the cpu from the reset vector at $FFFC, with interrupts masked
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_reset(machine: *mut EmuMachine) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let result = machine.cpu.mmu.read_word(0xFFFC).map(|pc| {
+            machine.cpu.r.pc = pc;
+            machine.cpu.r.s = 0xFD;
+            machine.cpu.r.set_flag('I', true);
+        });
+        machine.result(result)
+    })
+}
+
+/// Run one instruction
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, not being used on another
+/// thread. I/O callbacks may be called before it returns.
+#[no_mangle]
+pub unsafe extern "C" fn emu_step(machine: *mut EmuMachine) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let result = machine.cpu.step();
+        machine.result(result)
+    })
+}
+
+/// Run until `cycles` have passed or something else stops the cpu, giving back one of the
+/// `EMU_STOP_` reasons (or an error). With `stop_pc` not NULL, it is set to where the cpu
+/// stopped.
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, not being used on another
+/// thread, and `stop_pc` is NULL or points to a writable `uint16_t`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_run(machine: *mut EmuMachine, cycles: u32, stop_pc: *mut u16) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let reason = match machine.cpu.run(cycles) {
+            Ok(reason) => reason,
+            Err(error) => return machine.fail(error),
+        };
+        if let Some(stop_pc) = stop_pc.as_mut() {
+            *stop_pc = machine.cpu.r.pc;
+        }
+        match reason {
+            StopReason::Budget => EMU_STOP_BUDGET,
+            StopReason::Frame => EMU_STOP_FRAME,
+            StopReason::Breakpoint(_) => EMU_STOP_BREAKPOINT,
+            StopReason::Brk(_) => EMU_STOP_BRK,
+            StopReason::SelfLoop(_) => EMU_STOP_SELF_LOOP,
+        }
+    })
+}
+
+/// Make `emu_run` stop every `cycles` cycles, 0 for never
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_set_frame_cycles(machine: *mut EmuMachine, cycles: u32) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        machine.cpu.frame_cycles = cycles;
+        EMU_OK
+    })
+}
+
+/// Make `emu_run` stop before running a BRK, rather than taking it through the IRQ vector
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_set_stop_on_brk(machine: *mut EmuMachine, stop: bool) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        machine.cpu.stop_on_brk = stop;
+        EMU_OK
+    })
+}
+
+/// Stop `emu_run` before the instruction at `addr`
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_add_breakpoint(machine: *mut EmuMachine, addr: u16) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        machine.cpu.breakpoints.insert(addr);
+        EMU_OK
+    })
+}
+
+/// Undo `emu_add_breakpoint`
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_remove_breakpoint(machine: *mut EmuMachine, addr: u16) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        machine.cpu.breakpoints.remove(&addr);
+        EMU_OK
+    })
+}
+
+/// Copy the registers into `registers`
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `registers` is NULL or
+/// points to a writable `EmuRegisters`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_get_registers(machine: *mut EmuMachine, registers: *mut EmuRegisters) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let registers = match registers.as_mut() {
+            Some(registers) => registers,
+            None => return machine.fail_argument("registers is NULL"),
+        };
+        let r = &machine.cpu.r;
+        *registers = EmuRegisters {
+            pc: r.pc,
+            a: r.a,
+            x: r.x,
+            y: r.y,
+            s: r.s as u8,
+            p: r.p,
+            cycles: r.cc,
+        };
+        EMU_OK
+    })
+}
+
+/// Set the registers, and the cycle count, from `registers`
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `registers` is NULL or
+/// points to an `EmuRegisters`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_set_registers(machine: *mut EmuMachine, registers: *const EmuRegisters) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let registers = match registers.as_ref() {
+            Some(registers) => *registers,
+            None => return machine.fail_argument("registers is NULL"),
+        };
+        let r = &mut machine.cpu.r;
+        r.pc = registers.pc;
+        r.a = registers.a;
+        r.x = registers.x;
+        r.y = registers.y;
+        r.s = registers.s as u16;
+        r.p = registers.p;
+        r.cc = registers.cycles;
+        EMU_OK
+    })
+}
+
+/// Read a byte the way the cpu would, so I/O devices see the read
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `value` is NULL or points
+/// to a writable byte.
+#[no_mangle]
+pub unsafe extern "C" fn emu_read(machine: *mut EmuMachine, addr: u16, value: *mut u8) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        if value.is_null() {
+            return machine.fail_argument("value is NULL");
+        }
+        match machine.cpu.mmu.read(addr as usize) {
+            Ok(byte) => {
+                *value = byte;
+                EMU_OK
+            },
+            Err(error) => machine.fail(error),
+        }
+    })
+}
+
+/// Write a byte the way the cpu would, so ROM can't be written
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_write(machine: *mut EmuMachine, addr: u16, value: u8) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        let result = machine.cpu.mmu.write(addr as usize, value);
+        machine.result(result)
+    })
+}
+
+/// How many bytes `emu_snapshot` needs, or 0 if `machine` is NULL
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn emu_snapshot_size(machine: *const EmuMachine) -> usize {
+    guard(0, || {
+        match machine.as_ref() {
+            Some(machine) => machine.snapshot_size(),
+            None => 0,
+        }
+    })
+}
+
+/// Save the registers and all of memory (but not the state of I/O devices) into `buffer`,
+/// which holds `length` bytes
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `buffer` is NULL or
+/// points to `length` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn emu_snapshot(machine: *mut EmuMachine, buffer: *mut u8, length: usize) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        if buffer.is_null() || length < machine.snapshot_size() {
+            return machine.fail_argument("the snapshot buffer is too small");
+        }
+        let buffer = slice::from_raw_parts_mut(buffer, length);
+
+        let r = &machine.cpu.r;
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.push(SNAPSHOT_VERSION);
+        snapshot.extend_from_slice(&[(r.pc & 0xFF) as u8, (r.pc >> 8) as u8, r.a, r.x, r.y, r.s as u8, r.p]);
+        snapshot.extend_from_slice(&[r.cc as u8, (r.cc >> 8) as u8, (r.cc >> 16) as u8, (r.cc >> 24) as u8]);
+        for block in &machine.cpu.mmu.blocks {
+            snapshot.extend_from_slice(&block.memory);
+        }
+        buffer[..snapshot.len()].copy_from_slice(&snapshot);
+        EMU_OK
+    })
+}
+
+/// Go back to a snapshot taken with `emu_snapshot`, from a machine with the same memory map
+///
+/// # Safety
+///
+/// `machine` is NULL or a live machine from `emu_create`, and `buffer` is NULL or
+/// points to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn emu_restore(machine: *mut EmuMachine, buffer: *const u8, length: usize) -> i32 {
+    guard(EMU_ERR_PANIC, || {
+        let machine = machine!(machine);
+        if buffer.is_null() || length != machine.snapshot_size() {
+            return machine.fail_argument("the snapshot doesn't match this machine's memory");
+        }
+        let snapshot = slice::from_raw_parts(buffer, length);
+        if &snapshot[..4] != SNAPSHOT_MAGIC || snapshot[4] != SNAPSHOT_VERSION {
+            return machine.fail_argument("not a snapshot");
+        }
+
+        let r = &mut machine.cpu.r;
+        let registers = &snapshot[5..SNAPSHOT_HEADER];
+        r.pc = registers[0] as u16 | (registers[1] as u16) << 8;
+        r.a = registers[2];
+        r.x = registers[3];
+        r.y = registers[4];
+        r.s = registers[5] as u16;
+        r.p = registers[6];
+        r.cc = registers[7] as u32 | (registers[8] as u32) << 8 | (registers[9] as u32) << 16 | (registers[10] as u32) << 24;
+
+        // loaded through the MMU, so anything the decode cache has from the old memory is dropped
+        let blocks = machine.cpu.mmu.blocks.iter()
+            .map(|block| (block.start, block.length))
+            .collect::<Vec<(usize, usize)>>();
+        let mut offset = SNAPSHOT_HEADER;
+        for (start, length) in blocks {
+            if let Err(error) = machine.cpu.mmu.load(start, &snapshot[offset..offset + length]) {
+                return machine.fail(error);
+            }
+            offset += length;
+        }
+        EMU_OK
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // counts writes in `user`, and reads back the count
+    extern "C" fn io_read(user: *mut c_void, reg: u16) -> u8 {
+        unsafe { *(user as *mut u8) + reg as u8 }
+    }
+
+    extern "C" fn io_write(user: *mut c_void, _reg: u16, value: u8) {
+        unsafe { *(user as *mut u8) += value }
+    }
+
+    // sta $d000 / lda $d001 / brk, reset vector at $fffc
+    unsafe fn make_machine() -> *mut EmuMachine {
+        let machine = emu_create();
+        let mut rom = vec![0; 0x100];
+        rom[..7].copy_from_slice(&[0x8D, 0x00, 0xD0, 0xAD, 0x01, 0xD0, 0x00]);
+        rom[0xFC] = 0x00;
+        rom[0xFD] = 0xFF;
+        assert_eq!(emu_map_memory(machine, 0x0000, 0x800, false, ptr::null(), 0), EMU_OK);
+        assert_eq!(emu_map_memory(machine, 0xFF00, 0x100, true, rom.as_ptr(), rom.len() as u32), EMU_OK);
+        machine
+    }
+
+    #[test]
+    fn test_run() {
+        unsafe {
+            let machine = make_machine();
+            let mut count: u8 = 0;
+            let user = &mut count as *mut u8 as *mut c_void;
+            assert_eq!(emu_map_io(machine, 0xD000, 2, Some(io_read), Some(io_write), user), EMU_OK);
+            assert_eq!(emu_reset(machine), EMU_OK);
+
+            let mut registers = EmuRegisters::default();
+            emu_get_registers(machine, &mut registers);
+            assert_eq!(registers.pc, 0xFF00);
+            registers.a = 5;
+            emu_set_registers(machine, &registers);
+
+            let mut stop_pc = 0;
+            assert_eq!(emu_set_stop_on_brk(machine, true), EMU_OK);
+            assert_eq!(emu_run(machine, 1000, &mut stop_pc), EMU_STOP_BRK);
+            assert_eq!(stop_pc, 0xFF06);
+            emu_get_registers(machine, &mut registers);
+            assert_eq!(registers.a, 6);
+            assert_eq!(registers.cycles, 8);
+            assert_eq!(count, 5);
+
+            emu_destroy(machine);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let machine = make_machine();
+            assert_eq!(emu_map_memory(machine, 0x0400, 0x800, false, ptr::null(), 0), EMU_ERR_OVERLAPPING);
+            assert_eq!(emu_write(machine, 0xFF00, 1), EMU_ERR_ROM_WRITE);
+            let message = CStr::from_ptr(emu_last_error(machine)).to_str().unwrap();
+            assert_eq!(message, "attempted to write to readonly memory at $ff00");
+
+            let mut value = 0;
+            assert_eq!(emu_read(machine, 0x1000, &mut value), EMU_ERR_UNMAPPED);
+            assert_eq!(emu_step(ptr::null_mut()), EMU_ERR_ARGUMENT);
+            emu_destroy(machine);
+
+            // a panic stops at the API rather than unwinding into the caller
+            assert_eq!(guard(EMU_ERR_PANIC, || panic!("in the emulator")), EMU_ERR_PANIC);
+        }
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let path = env::temp_dir().join(format!("capi_battery_{}.sav", ::std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let machine = make_machine();
+            assert_eq!(emu_map_battery_ram(machine, 0x6000, 0x10, c_path.as_ptr()), EMU_OK);
+            emu_write(machine, 0x6002, 0x77);
+            assert_eq!(emu_flush(machine), EMU_OK);
+            emu_write(machine, 0x6003, 0x78);
+            emu_destroy(machine);
+        }
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(&saved[..4], &[0, 0, 0x77, 0x78]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        unsafe {
+            let machine = make_machine();
+            emu_reset(machine);
+            emu_write(machine, 0x0010, 0xAA);
+
+            let mut snapshot = vec![0; emu_snapshot_size(machine)];
+            assert_eq!(snapshot.len(), SNAPSHOT_HEADER + 0x900);
+            assert_eq!(emu_snapshot(machine, snapshot.as_mut_ptr(), snapshot.len()), EMU_OK);
+
+            emu_write(machine, 0x0010, 0xBB);
+            emu_step(machine);
+            assert_eq!(emu_restore(machine, snapshot.as_ptr(), snapshot.len()), EMU_OK);
+
+            let mut value = 0;
+            emu_read(machine, 0x0010, &mut value);
+            assert_eq!(value, 0xAA);
+            let mut registers = EmuRegisters::default();
+            emu_get_registers(machine, &mut registers);
+            assert_eq!(registers.pc, 0xFF00);
+            assert_eq!(registers.s, 0xFD);
+
+            assert_eq!(emu_restore(machine, snapshot.as_ptr(), 10), EMU_ERR_ARGUMENT);
+            emu_destroy(machine);
+        }
+    }
+
+    #[test]
+    fn test_restore_code() {
+        unsafe {
+            // lda #$01 at $0200, then changed to lda #$02 after the snapshot
+            let machine = make_machine();
+            emu_write(machine, 0x0200, 0xA9);
+            emu_write(machine, 0x0201, 0x01);
+            let mut registers = EmuRegisters::default();
+            emu_get_registers(machine, &mut registers);
+            registers.pc = 0x0200;
+            emu_set_registers(machine, &registers);
+            let mut snapshot = vec![0; emu_snapshot_size(machine)];
+            emu_snapshot(machine, snapshot.as_mut_ptr(), snapshot.len());
+            emu_write(machine, 0x0201, 0x02);
+
+            // the restore has to drop the lda #$02 the decode cache kept
+            (*machine).cpu.enable_decode_cache();
+            assert_eq!(emu_step(machine), EMU_OK);
+            assert_eq!(emu_restore(machine, snapshot.as_ptr(), snapshot.len()), EMU_OK);
+            assert_eq!(emu_step(machine), EMU_OK);
+            emu_get_registers(machine, &mut registers);
+            assert_eq!(registers.a, 0x01);
+            emu_destroy(machine);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The NTSC NES and Atari 8-bit clock, 1.79 MHz
+pub const NTSC_HZ: u32 = 1_789_773;
+
+/// Frames per second. Hosts run the cpu a frame at a time, then draw and poll input.
+pub const FRAME_RATE: u32 = 60;
+
+// how many cycles make up a frame when there's no clock to divide up, or to run at a time
+// when fast forwarding
+const UNLIMITED_FRAME_CYCLES: u32 = 100_000;
+
+// how far behind the clock emulation can fall (when the host can't keep up) before the
+// missing time is written off, rather than run flat out to catch up
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+// how often the effective speed is measured
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How fast the emulated cpu runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Hz(u32),
+
+    /// As fast as the host can go
+    Unlimited,
+}
+
+impl Speed {
+    /// `ntsc`, `unlimited`, or a speed in MHz like `1` or `1.79mhz`
+    pub fn parse(text: &str) -> Option<Speed> {
+        let text = text.to_lowercase();
+        match text.as_str() {
+            "ntsc" => Some(Speed::Hz(NTSC_HZ)),
+            "unlimited" => Some(Speed::Unlimited),
+            _ => text.trim_end_matches("mhz").parse::<f64>().ok()
+                .filter(|mhz| *mhz > 0.0 && *mhz < 4000.0)
+                .map(|mhz| Speed::Hz(((mhz * 1e6) as u32).max(1))),
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Speed::Hz(hz) => write!(f, "{:.3} MHz", hz as f64 / 1e6),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+/// Paces emulation to a clock speed using the cycles the cpu reports.
+///
+/// Hosts either push, running a frame (`frame_cycles`, see `CPU::frame_cycles`) and then
+/// calling `sync` to sleep until it was due, or pull, asking for the `cycles_due` whenever
+/// they get the chance (like on a GUI update event). Both report what actually ran with `ran`.
+/// Fast forward runs flat out without forgetting the speed, and the effective speed is
+/// measured as it goes.
+#[derive(Debug)]
+pub struct Clock {
+    pub speed: Speed,
+    pub fast_forward: bool,
+
+    // cycles run since `origin`, which is reset whenever pacing starts over
+    origin: Instant,
+    cycles: u64,
+
+    stats_start: Instant,
+    stats_cycles: u64,
+
+    started: Instant,
+    total_cycles: u64,
+}
+
+impl Clock {
+    pub fn new(speed: Speed) -> Clock {
+        let now = Instant::now();
+        Clock {
+            speed: speed,
+            fast_forward: false,
+            origin: now,
+            cycles: 0,
+            stats_start: now,
+            stats_cycles: 0,
+            started: now,
+            total_cycles: 0,
+        }
+    }
+
+    fn hz(&self) -> Option<u32> {
+        match self.speed {
+            Speed::Hz(hz) if !self.fast_forward => Some(hz),
+            _ => None,
+        }
+    }
+
+    /// Cycles in one frame at this speed
+    pub fn frame_cycles(&self) -> u32 {
+        match self.speed {
+            Speed::Hz(hz) => (hz / FRAME_RATE).max(1),
+            Speed::Unlimited => UNLIMITED_FRAME_CYCLES,
+        }
+    }
+
+    pub fn toggle_fast_forward(&mut self) {
+        self.fast_forward = !self.fast_forward;
+        self.restart(Instant::now());
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.origin = now;
+        self.cycles = 0;
+    }
+
+    /// Count `cycles` run by the cpu
+    pub fn ran(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+        self.stats_cycles += cycles as u64;
+        self.total_cycles += cycles as u64;
+    }
+
+    /// How many cycles to run to catch up with the clock, or a chunk to run before checking
+    /// back when running flat out
+    pub fn cycles_due(&mut self) -> u32 {
+        self.cycles_due_at(Instant::now())
+    }
+
+    fn cycles_due_at(&mut self, now: Instant) -> u32 {
+        let hz = match self.hz() {
+            Some(hz) => hz as u64,
+            None => return UNLIMITED_FRAME_CYCLES,
+        };
+        let mut elapsed = now.duration_since(self.origin);
+        let lag = elapsed.checked_sub(self.time_for(self.cycles, hz)).unwrap_or_default();
+        if lag > MAX_LAG {
+            self.restart(now.checked_sub(MAX_LAG).unwrap_or(now));
+            elapsed = MAX_LAG;
+        }
+        let due = (elapsed.as_secs_f64() * hz as f64) as u64;
+        due.saturating_sub(self.cycles).min(u32::MAX as u64) as u32
+    }
+
+    /// Sleep until the cycles run so far were due
+    pub fn sync(&mut self) {
+        if let Some(ahead) = self.ahead_at(Instant::now()) {
+            thread::sleep(ahead);
+        }
+    }
+
+    // How far emulation is ahead of the clock at `now`. If it has fallen too far behind,
+    // start pacing over from here.
+    fn ahead_at(&mut self, now: Instant) -> Option<Duration> {
+        let hz = self.hz()? as u64;
+        let target = self.origin + self.time_for(self.cycles, hz);
+        if now > target + MAX_LAG {
+            self.restart(now);
+        }
+        target.checked_duration_since(now).filter(|ahead| !ahead.is_zero())
+    }
+
+    fn time_for(&self, cycles: u64, hz: u64) -> Duration {
+        Duration::from_secs(cycles / hz) + Duration::from_nanos((cycles % hz) * 1_000_000_000 / hz)
+    }
+
+    /// The speed the cpu actually ran at in MHz, measured about once a second. None until
+    /// the next measurement is ready.
+    pub fn effective_mhz(&mut self) -> Option<f64> {
+        self.effective_mhz_at(Instant::now())
+    }
+
+    /// The speed in MHz over everything run so far
+    pub fn average_mhz(&self) -> f64 {
+        self.total_cycles as f64 / self.started.elapsed().as_secs_f64() / 1e6
+    }
+
+    fn effective_mhz_at(&mut self, now: Instant) -> Option<f64> {
+        let elapsed = now.duration_since(self.stats_start);
+        if elapsed < STATS_INTERVAL {
+            return None;
+        }
+        let mhz = self.stats_cycles as f64 / elapsed.as_secs_f64() / 1e6;
+        self.stats_start = now;
+        self.stats_cycles = 0;
+        Some(mhz)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(Speed::parse("1"), Some(Speed::Hz(1_000_000)));
+        assert_eq!(Speed::parse("1.79MHz"), Some(Speed::Hz(1_790_000)));
+        assert_eq!(Speed::parse("NTSC"), Some(Speed::Hz(NTSC_HZ)));
+        assert_eq!(Speed::parse("unlimited"), Some(Speed::Unlimited));
+        assert_eq!(Speed::parse("0"), None);
+        assert_eq!(Speed::parse("fast"), None);
+        assert_eq!(Speed::Hz(NTSC_HZ).to_string(), "1.790 MHz");
+    }
+
+    #[test]
+    fn test_frame_cycles() {
+        assert_eq!(Clock::new(Speed::Hz(1_000_000)).frame_cycles(), 16_666);
+        assert_eq!(Clock::new(Speed::Unlimited).frame_cycles(), UNLIMITED_FRAME_CYCLES);
+    }
+
+    #[test]
+    fn test_pacing() {
+        let mut clock = Clock::new(Speed::Hz(1_000_000));
+        let start = clock.origin;
+
+        // 10ms in, 10000 cycles are due and none have run
+        assert_eq!(clock.cycles_due_at(start + Duration::from_millis(10)), 10_000);
+        clock.ran(10_000);
+        assert_eq!(clock.cycles_due_at(start + Duration::from_millis(10)), 0);
+        assert_eq!(clock.ahead_at(start + Duration::from_millis(10)), None);
+
+        // a frame run straight away is a frame ahead
+        clock.ran(16_666);
+        assert_eq!(clock.ahead_at(start + Duration::from_millis(10)), Some(Duration::from_micros(16_666)));
+
+        // fast forward never waits
+        clock.toggle_fast_forward();
+        clock.ran(1_000_000);
+        assert_eq!(clock.ahead_at(Instant::now()), None);
+        assert_eq!(clock.cycles_due(), UNLIMITED_FRAME_CYCLES);
+    }
+
+    #[test]
+    fn test_lag() {
+        let mut clock = Clock::new(Speed::Hz(1_000_000));
+        let start = clock.origin;
+
+        // a second behind only catches up on the last 250ms
+        assert_eq!(clock.cycles_due_at(start + Duration::from_secs(1)), 250_000);
+        clock.ran(250_000);
+        assert_eq!(clock.cycles_due_at(start + Duration::from_secs(1)), 0);
+
+        // pushing, falling behind starts the pacing over
+        clock.ahead_at(start + Duration::from_secs(2));
+        clock.ran(1_000);
+        assert_eq!(clock.ahead_at(start + Duration::from_secs(2)), Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_effective_mhz() {
+        let mut clock = Clock::new(Speed::Unlimited);
+        let start = clock.stats_start;
+        clock.ran(500_000);
+        assert_eq!(clock.effective_mhz_at(start + Duration::from_millis(500)), None);
+        clock.ran(1_500_000);
+        assert_eq!(clock.effective_mhz_at(start + Duration::from_secs(2)), Some(1.0));
+        assert_eq!(clock.effective_mhz_at(start + Duration::from_secs(2)), None);
+    }
+}
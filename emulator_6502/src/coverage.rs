@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashMap};
+
+use error::EmuError;
+use mmu::MMU;
+
+/// Maps instruction addresses back to the source line they were assembled from.
+///
+/// This is read from the debug map the assembler writes next to a binary, which has one
+/// `address file line` entry per instruction, for example `0600 asm_code/snake.6502asm 1`.
+#[derive(Clone, Debug)]
+pub struct DebugMap {
+    pub entries: Vec<(u16, String, u32)>,
+}
+
+impl DebugMap {
+    /// Read a debug map, failing on the first line that isn't a valid entry. Blank lines are
+    /// skipped.
+    pub fn parse(text: &str) -> Result<DebugMap, EmuError> {
+        let mut entries = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let tokens = line.split_whitespace().collect::<Vec<&str>>();
+            if tokens.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| EmuError::InvalidDebugMap(format!("line {}: {} in `{}`", number + 1, reason, line));
+            if tokens.len() < 3 {
+                return Err(invalid("expected an address, a file and a line number"));
+            }
+
+            // the file name sits between the address and the line number, and may hold spaces
+            let address = u16::from_str_radix(tokens[0], 16)
+                .map_err(|_| invalid("the address isn't hex"))?;
+            let line_number = tokens[tokens.len() - 1].parse::<u32>()
+                .map_err(|_| invalid("the line number isn't a number"))?;
+            let file = tokens[1..tokens.len() - 1].join(" ");
+
+            entries.push((address, file, line_number));
+        }
+        Ok(DebugMap {
+            entries: entries,
+        })
+    }
+}
+
+/// Branch outcomes for a single branch instruction
+#[derive(Clone, Debug, Default)]
+pub struct BranchStats {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// This records which instructions ran, and which way every branch went, while
+/// `CPU::enable_coverage` is on.
+#[derive(Debug)]
+pub struct Coverage {
+    /// Execution count keyed by instruction address
+    pub executed: HashMap<u16, u64>,
+
+    /// Outcomes keyed by the address of the branch instruction
+    pub branches: HashMap<u16, BranchStats>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            executed: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    /// Record one executed instruction, `taken` being whether it was a branch that was taken.
+    pub fn record(&mut self, pc: u16, opcode: u8, taken: bool) {
+        *self.executed.entry(pc).or_insert(0) += 1;
+
+        if is_branch(opcode) {
+            let stats = self.branches.entry(pc).or_default();
+            if taken {
+                stats.taken += 1;
+            } else {
+                stats.not_taken += 1;
+            }
+        }
+    }
+
+    /// Coverage in the lcov tracefile format (as read by genhtml and most CI tools), with
+    /// one record per source file in the debug map. `memory` holds the program, to find the
+    /// branches that never ran.
+    pub fn lcov(&self, map: &DebugMap, memory: &MMU) -> String {
+        // file -> line -> instruction addresses on that line
+        let mut files: BTreeMap<&str, BTreeMap<u32, Vec<u16>>> = BTreeMap::new();
+        for &(address, ref file, line) in &map.entries {
+            files.entry(file.as_str())
+                .or_default()
+                .entry(line)
+                .or_default()
+                .push(address);
+        }
+
+        let mut out = String::new();
+        for (file, lines) in files {
+            out.push_str("TN:\n");
+            out.push_str(&format!("SF:{}\n", file));
+
+            let mut lines_hit = 0;
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            let mut branch_data = String::new();
+
+            for (line, addresses) in &lines {
+                let hits: u64 = addresses.iter()
+                    .map(|address| self.executed.get(address).cloned().unwrap_or(0))
+                    .sum();
+                if hits > 0 {
+                    lines_hit += 1;
+                }
+                out.push_str(&format!("DA:{},{}\n", line, hits));
+
+                for (block, address) in addresses.iter().enumerate() {
+                    let counts = match self.branches.get(address) {
+                        Some(stats) => [Some(stats.taken), Some(stats.not_taken)],
+                        // a branch that never ran, which lcov shows as `-` both ways
+                        None if memory.peek(*address as usize).is_some_and(is_branch) => [None, None],
+                        None => continue,
+                    };
+                    for (branch, count) in counts.iter().enumerate() {
+                        branches_found += 1;
+                        let count = match *count {
+                            Some(count) => {
+                                if count > 0 {
+                                    branches_hit += 1;
+                                }
+                                count.to_string()
+                            },
+                            None => "-".to_string(),
+                        };
+                        branch_data.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, count));
+                    }
+                }
+            }
+
+            out.push_str(&branch_data);
+            out.push_str(&format!("BRF:{}\n", branches_found));
+            out.push_str(&format!("BRH:{}\n", branches_hit));
+            out.push_str(&format!("LF:{}\n", lines.len()));
+            out.push_str(&format!("LH:{}\n", lines_hit));
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+// the eight conditional branches all sit in column $x0 of the odd rows
+fn is_branch(opcode: u8) -> bool {
+    opcode & 0x1F == 0x10
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use cpu::make_cpu;
+
+    // ldx #$02 / dex / bne $fd (back to the dex) / jmp $1005 / beq $00 (never reached)
+    fn make_program() -> Vec<u8> {
+        vec![0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0x4C, 0x05, 0x10, 0xF0, 0x00]
+    }
+
+    fn make_map() -> DebugMap {
+        DebugMap::parse("1000 loop.6502asm 1\n\
+                         1002 loop.6502asm 3\n\
+                         1003 loop.6502asm 4\n\
+                         1005 loop.6502asm 6\n\
+                         1008 loop.6502asm 7\n").unwrap()
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map = DebugMap::parse("0600 my file.6502asm 12\n\n").unwrap();
+        assert_eq!(map.entries, vec![(0x0600, "my file.6502asm".to_string(), 12)]);
+
+        assert_eq!(DebugMap::parse("0600 a.6502asm 1\n0603 a.6502asm\n").unwrap_err(),
+                   EmuError::InvalidDebugMap("line 2: expected an address, a file and a line number in `0603 a.6502asm`".to_string()));
+        assert_eq!(DebugMap::parse("zz a.6502asm 1").unwrap_err(),
+                   EmuError::InvalidDebugMap("line 1: the address isn't hex in `zz a.6502asm 1`".to_string()));
+        assert_eq!(DebugMap::parse("0600 a.6502asm one").unwrap_err(),
+                   EmuError::InvalidDebugMap("line 1: the line number isn't a number in `0600 a.6502asm one`".to_string()));
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut cpu = make_cpu(Some(make_program())).unwrap();
+        cpu.enable_coverage();
+
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+
+        let coverage = cpu.coverage.as_ref().unwrap();
+        assert_eq!(coverage.executed[&0x1002], 2);
+        assert_eq!(coverage.executed[&0x1005], 2);
+        assert_eq!(coverage.executed.get(&0x1008), None);
+        assert_eq!(coverage.branches[&0x1003].taken, 1);
+        assert_eq!(coverage.branches[&0x1003].not_taken, 1);
+    }
+
+    #[test]
+    fn test_branch_to_next() {
+        // beq $00 / bne $00, which both end up at the next instruction either way
+        let mut cpu = make_cpu(Some(vec![0xF0, 0x00, 0xD0, 0x00])).unwrap();
+        cpu.enable_coverage();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        let coverage = cpu.coverage.as_ref().unwrap();
+        assert_eq!(coverage.branches[&0x1000].taken, 0);
+        assert_eq!(coverage.branches[&0x1000].not_taken, 1);
+        assert_eq!(coverage.branches[&0x1002].taken, 1);
+        assert_eq!(coverage.branches[&0x1002].not_taken, 0);
+    }
+
+    #[test]
+    fn test_lcov() {
+        let mut cpu = make_cpu(Some(make_program())).unwrap();
+        cpu.enable_coverage();
+
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+
+        let lcov = cpu.coverage.as_ref().unwrap().lcov(&make_map(), &cpu.mmu);
+        assert_eq!(lcov, "TN:\n\
+                          SF:loop.6502asm\n\
+                          DA:1,1\n\
+                          DA:3,2\n\
+                          DA:4,2\n\
+                          DA:6,2\n\
+                          DA:7,0\n\
+                          BRDA:4,0,0,1\n\
+                          BRDA:4,0,1,1\n\
+                          BRDA:7,0,0,-\n\
+                          BRDA:7,0,1,-\n\
+                          BRF:4\n\
+                          BRH:2\n\
+                          LF:5\n\
+                          LH:4\n\
+                          end_of_record\n");
+    }
+}
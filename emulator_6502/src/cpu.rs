@@ -1,11 +1,21 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
 
 use mmu::{MMU, Block};
 use registers::Registers;
+use profiler::{Profiler, PageHeat};
+use coverage::Coverage;
+use symbols::SymbolTable;
+use sanitizer::Sanitizer;
+use decode_cache::{CodePages, DecodeCache, Decoded};
+use error::EmuError;
+use ops::{CYCLES, op_table};
 
+// how many instructions back `run` looks for the cpu getting into the same state again
+const LOOP_WINDOW: usize = 8;
 
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Instr {
     pub addr: fn(&mut CPU) -> u16,
     pub code: fn(&mut CPU, u16),
@@ -20,6 +30,39 @@ impl Instr {
     }
 }
 
+/// Why `CPU::run` handed control back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// The cycle budget given to `run` was used up
+    Budget,
+
+    /// The cycle counter crossed a multiple of `frame_cycles`
+    Frame,
+
+    /// The pc reached one of the `breakpoints`, the instruction there hasn't run yet
+    Breakpoint(u16),
+
+    /// The pc reached a BRK at this address, which isn't run. Only with `stop_on_brk` set.
+    Brk(u16),
+
+    /// The program is going round a loop through this address that it can never leave:
+    /// an instruction jumping or branching to itself, or a few instructions getting back to
+    /// the same registers without writing anything
+    SelfLoop(u16),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Budget => write!(f, "cycle budget used up"),
+            StopReason::Frame => write!(f, "end of frame"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at ${:04x}", pc),
+            StopReason::Brk(pc) => write!(f, "BRK at ${:04x}", pc),
+            StopReason::SelfLoop(pc) => write!(f, "infinite loop at ${:04x}", pc),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CPU {
     /// The MMU, modeled here as "owned" by the CPU
@@ -28,6 +71,58 @@ pub struct CPU {
     /// The registers of the CPU
     pub r: Registers,
 
+    /// The op table used to decode each instruction, shared between CPUs
+    pub ops: &'static [Instr; 256],
+
+    /// Addresses `run` stops at before executing
+    pub breakpoints: HashSet<u16>,
+
+    /// Have `run` stop before a BRK instead of running it, for programs without an
+    /// interrupt handler that use it to mean they have finished
+    pub stop_on_brk: bool,
+
+    /// Length of a frame in cycles, `run` stops every time one passes. Zero means no frames.
+    pub frame_cycles: u32,
+
+    /// Print the registers and opcode of every instruction as it runs
+    pub trace: bool,
+
+    /// Names for addresses, shown in the trace
+    pub symbols: SymbolTable,
+
+    /// Execution profile, only collected after `enable_profiler` is called
+    pub profiler: Option<Profiler>,
+
+    /// Executed addresses and branch outcomes, only collected after `enable_coverage`
+    pub coverage: Option<Coverage>,
+
+    /// Memory and stack misuse reports, only collected after `enable_sanitizer`
+    pub sanitizer: Option<Sanitizer>,
+
+    /// Instructions decoded the first time they ran, only kept after `enable_decode_cache`
+    pub decode_cache: Option<DecodeCache>,
+
+    // Operand bytes of a cached instruction, handed out by `next_byte` in place of memory
+    prefetch: u16,
+    prefetched: u16,
+
+    /// The address of the instruction currently being executed, for error reports
+    pub op_pc: u16,
+
+    /// The first error raised while executing the current instruction. The ops can't
+    /// return errors themselves, so this is held until `step` returns it.
+    pub fault: Option<EmuError>,
+
+    /// Set when the current instruction is a branch that was taken, for coverage
+    pub branch_taken: bool,
+
+    /// `(address, value, "read" | "write")` for every memory access, in order, only kept
+    /// after `enable_bus_log`
+    pub bus_log: Option<Vec<(u16, u8, &'static str)>>,
+
+    // Writes made by the ops so far, so `run` can tell a loop that changes memory from one
+    // that doesn't
+    writes: u32,
 }
 
 impl CPU {
@@ -38,29 +133,295 @@ impl CPU {
         let mut cpu = CPU {
             mmu: mmu,
             r: Registers::new(),
+            ops: op_table(),
+            breakpoints: HashSet::new(),
+            stop_on_brk: false,
+            frame_cycles: 0,
+            trace: false,
+            symbols: SymbolTable::new(),
+            profiler: None,
+            coverage: None,
+            sanitizer: None,
+            decode_cache: None,
+            prefetch: 0,
+            prefetched: 0,
+            op_pc: 0,
+            fault: None,
+            branch_taken: false,
+            bus_log: None,
+            writes: 0,
         };
         cpu
     }
 
+    /// Start collecting per instruction, per subroutine and per memory page statistics.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+        self.mmu.heat = Some(PageHeat::new());
+    }
+
+    /// Start recording which instructions run and which way each branch goes.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    /// Start checking for uninitialized reads and stack misuse. Everything in RAM counts as
+    /// uninitialized from here on, until it is written.
+    pub fn enable_sanitizer(&mut self) {
+        self.sanitizer = Some(Sanitizer::new(&self.mmu));
+    }
+
+    /// Keep each instruction decoded after it first runs, which saves fetching it through
+    /// the MMU again. Fetches from the cache don't count towards page heat or the sanitizer.
+    pub fn enable_decode_cache(&mut self) {
+        self.decode_cache = Some(DecodeCache::new());
+        self.mmu.code_pages = Some(CodePages::new());
+    }
+
+    /// Start logging every memory access the ops make. Fetches from the decode cache
+    /// aren't logged.
+    pub fn enable_bus_log(&mut self) {
+        self.bus_log = Some(Vec::new());
+    }
+
     // 1) read the byte pointed to by the program counter (instruction)
     // 2) decode using optable to give op function
     // 3) get argument using addressing mode if applicable
     // 4) execute op
-    pub fn step(&mut self, ops: [Instr; 256]) {
-        println!("pc: {:x}, a: {:x}, sp: {:x}", self.r.pc, self.r.a, self.r.s);
-        let opcode = self.next_byte();
-        let src = (ops[opcode as usize].addr)(self);
-        println!("opcode: {:x}, src: {:x}", opcode, src);
-        println!("p: {:#b}", self.r.p);
-        println!("---------------------------");
-        (ops[opcode as usize].code)(self, src);
+    // 5) count the cycles it took
+    //
+    // If anything goes wrong the instruction is abandoned where it is and the error returned,
+    // the cpu can still be stepped again afterwards.
+    pub fn step(&mut self) -> Result<(), EmuError> {
+        // a device asserting IRQ takes the place of the next instruction
+        if self.mmu.irq() && !self.r.get_flag('I') {
+            self.op_pc = self.r.pc;
+            self.fault = None;
+            self.irq();
+            self.r.cc = self.r.cc.wrapping_add(7);
+            self.mmu.tick(7);
+            return match self.fault.take() {
+                Some(error) => Err(error),
+                None => Ok(()),
+            };
+        }
 
-        // println!("did instruction");
+        if self.trace {
+            match self.symbols.name(self.r.pc) {
+                Some(name) => println!("pc: {:x} <{}>, a: {:x}, sp: {:x}", self.r.pc, name, self.r.a, self.r.s),
+                None => println!("pc: {:x}, a: {:x}, sp: {:x}", self.r.pc, self.r.a, self.r.s),
+            }
+        }
+        let pc = self.r.pc;
+        let cc = self.r.cc;
+        self.op_pc = pc;
+        self.fault = None;
+        self.branch_taken = false;
+
+        let decoded = self.cached_instruction(pc);
+        let (opcode, instr, cycles) = match decoded {
+            Some(decoded) => {
+                self.r.pc = pc.wrapping_add(1);
+                self.prefetch = decoded.operands;
+                self.prefetched = decoded.length - 1;
+                (decoded.opcode, decoded.instr, decoded.cycles)
+            },
+            None => {
+                let opcode = self.next_byte();
+                if let Some(error) = self.fault.take() {
+                    self.r.pc = pc;
+                    return Err(error);
+                }
+                (opcode, self.ops[opcode as usize], CYCLES[opcode as usize])
+            },
+        };
+        let src = (instr.addr)(self);
+        if decoded.is_none() && self.decode_cache.is_some() {
+            self.decode(pc, opcode, instr);
+        }
+        if self.trace {
+            println!("opcode: {:x}, src: {:x}", opcode, src);
+            println!("p: {:#b}", self.r.p);
+            println!("---------------------------");
+        }
+        (instr.code)(self, src);
+        self.r.cc = self.r.cc.wrapping_add(cycles as u32);
+        let cycles = self.r.cc.wrapping_sub(cc);
+        self.mmu.tick(cycles);
+
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, opcode, cycles, self.r.pc);
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record(pc, opcode, self.branch_taken);
+        }
+
+        match self.fault.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    // The decoded instruction at `pc`, if the cache is on and has it. Pages written since
+    // the last instruction are dropped from the cache first.
+    fn cached_instruction(&mut self, pc: u16) -> Option<Decoded> {
+        let cache = match self.decode_cache {
+            Some(ref mut cache) => cache,
+            None => return None,
+        };
+        if let Some(ref mut code_pages) = self.mmu.code_pages {
+            for page in code_pages.written.drain(..) {
+                cache.invalidate(page);
+            }
+        }
+        cache.get(pc)
+    }
+
+    // Cache the instruction at `pc`, just run through its addressing mode, unless some of it
+    // came from a device
+    fn decode(&mut self, pc: u16, opcode: u8, instr: Instr) {
+        if self.fault.is_some() {
+            return;
+        }
+        let length = self.r.pc.wrapping_sub(pc);
+        let mut operands = 0;
+        for i in (1..length).rev() {
+            match self.mmu.peek(pc.wrapping_add(i) as usize) {
+                Some(byte) => operands = (operands << 8) | byte as u16,
+                None => return,
+            }
+        }
+        if self.mmu.peek(pc as usize).is_none() {
+            return;
+        }
+
+        if let Some(ref mut code_pages) = self.mmu.code_pages {
+            code_pages.watch(pc);
+            code_pages.watch(pc.wrapping_add(length - 1));
+        }
+        if let Some(ref mut cache) = self.decode_cache {
+            cache.insert(pc, Decoded {
+                instr: instr,
+                opcode: opcode,
+                operands: operands,
+                length: length,
+                cycles: CYCLES[opcode as usize],
+            });
+        }
+    }
+
+    /// Run instructions until `budget` cycles have passed or something else stops the cpu,
+    /// see `StopReason`.
+    ///
+    /// A breakpoint at the starting pc is ignored, so calling `run` again after stopping at a
+    /// breakpoint carries on past it. A BRK only stops it with `stop_on_brk` set, otherwise
+    /// it runs like any other instruction.
+    pub fn run(&mut self, budget: u32) -> Result<StopReason, EmuError> {
+        let start = self.r.cc;
+        let mut first = true;
+        let mut recent = [None; LOOP_WINDOW];
+        let mut ran = 0;
+
+        loop {
+            let pc = self.r.pc;
+            if !first && self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            if self.stop_on_brk && self.mmu.peek(pc as usize) == Some(0x00) {
+                return Ok(StopReason::Brk(pc));
+            }
+            first = false;
+
+            let cc = self.r.cc;
+            self.step()?;
+
+            // an interrupt could still get a program out of a loop, unless they are masked
+            // or there is nothing to raise one
+            let interruptible = !self.mmu.devices.is_empty() && !self.r.get_flag('I');
+            if self.r.pc == pc && !interruptible {
+                return Ok(StopReason::SelfLoop(pc));
+            }
+            // Without devices nothing but the program changes memory, so getting back to a
+            // state from the last few instructions with nothing written in between means it
+            // will go round the same way forever
+            if self.mmu.devices.is_empty() {
+                let state = Some(self.loop_state());
+                if recent.contains(&state) {
+                    return Ok(StopReason::SelfLoop(self.r.pc));
+                }
+                recent[ran % LOOP_WINDOW] = state;
+                ran += 1;
+            }
+            if self.frame_cycles > 0 && cc / self.frame_cycles != self.r.cc / self.frame_cycles {
+                return Ok(StopReason::Frame);
+            }
+            if self.r.cc.wrapping_sub(start) >= budget {
+                return Ok(StopReason::Budget);
+            }
+        }
+    }
+
+    // Everything the next instruction depends on, besides memory
+    fn loop_state(&self) -> (u16, u8, u8, u8, u8, u16, u32) {
+        (self.r.pc, self.r.a, self.r.x, self.r.y, self.r.p, self.r.s, self.writes)
+    }
+
+    /// Keep the first error raised during an instruction, see `fault`.
+    pub fn raise(&mut self, error: EmuError) {
+        if self.fault.is_none() {
+            self.fault = Some(error);
+        }
+    }
+
+    // The ops go through these rather than the MMU directly, so a bad access is raised as a
+    // fault and the op carries on with a zero.
+    pub fn read(&mut self, addr: usize) -> u8 {
+        match self.mmu.read(addr) {
+            Ok(val) => {
+                if let Some(ref mut sanitizer) = self.sanitizer {
+                    sanitizer.read(self.op_pc, addr);
+                }
+                if let Some(ref mut bus_log) = self.bus_log {
+                    bus_log.push((addr as u16, val, "read"));
+                }
+                val
+            },
+            Err(error) => {
+                self.raise(error);
+                0
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, val: u8) {
+        match self.mmu.write(addr, val) {
+            Ok(()) => {
+                self.writes = self.writes.wrapping_add(1);
+                if let Some(ref mut sanitizer) = self.sanitizer {
+                    sanitizer.write(addr, 1);
+                }
+                if let Some(ref mut bus_log) = self.bus_log {
+                    bus_log.push((addr as u16, val, "write"));
+                }
+            },
+            Err(error) => self.raise(error),
+        }
+    }
+
+    pub fn read_word(&mut self, addr: usize) -> u16 {
+        ((self.read(addr + 1) as u16) << 8) + (self.read(addr) as u16)
     }
 
     pub fn next_byte(&mut self) -> u8 {
-        let val = self.mmu.read(self.r.pc as usize);
-        self.r.pc += 1;
+        if self.prefetched > 0 {
+            let val = self.prefetch as u8;
+            self.prefetch >>= 8;
+            self.prefetched -= 1;
+            self.r.pc = self.r.pc.wrapping_add(1);
+            return val;
+        }
+        let val = self.read(self.r.pc as usize);
+        self.r.pc = self.r.pc.wrapping_add(1);
         val
     }
 
@@ -72,12 +433,23 @@ impl CPU {
         (high << 8) + low
     }
 
+    // the stack pointer is an offset into the stack page ($0100-$01FF)
+    fn stack_address(&self, s: u16) -> usize {
+        (self.r.stack_page << 8) | (s as usize & 0xFF)
+    }
+
     pub fn stack_push(&mut self, val: u8) {
-        self.mmu.write(self.r.s as usize, val);
+        let addr = self.stack_address(self.r.s);
+        self.write(addr, val);
 
         // Note: rust will panic instead of wrapping (too safe for school)
         if self.r.s == 0 {
             self.r.s = 255;
+            let pc = self.op_pc;
+            if let Some(ref mut sanitizer) = self.sanitizer {
+                sanitizer.stack_wrap(pc);
+            }
+            self.raise(EmuError::StackWrap { pc: pc });
         } else {
             self.r.s = (self.r.s - 1) & 0xFF;
         }
@@ -89,7 +461,15 @@ impl CPU {
     }
 
     pub fn stack_pop(&mut self) -> u8 {
-        let val = self.mmu.read(((self.r.s as usize + 1) & 0xFF));
+        if self.r.s == 0xFF {
+            let pc = self.op_pc;
+            if let Some(ref mut sanitizer) = self.sanitizer {
+                sanitizer.stack_wrap(pc);
+            }
+            self.raise(EmuError::StackWrap { pc: pc });
+        }
+        let addr = self.stack_address(self.r.s + 1);
+        let val = self.read(addr);
         self.r.s = (self.r.s + 1) & 0xFF;
         val
     }
@@ -110,8 +490,24 @@ impl CPU {
         ((val as i16) & 0x7F) - ((val as i16) & 0x80)
     }
 
+    /// Take an interrupt request: push the pc and flags (with B clear, unlike BRK) and jump
+    /// through the IRQ vector with further interrupts masked.
+    pub fn irq(&mut self) {
+        let pc = self.r.pc;
+        self.stack_push_word(pc);
+        if let Some(ref mut sanitizer) = self.sanitizer {
+            sanitizer.interrupt(pc);
+        }
+
+        let p = self.r.p & !0x10;
+        self.stack_push(p);
+        self.r.set_flag('I', true);
+        self.r.pc = self.interrupt_address("IRQ".to_string());
+    }
+
     pub fn interrupt_address(&mut self, interrupt: String) -> u16 {
-        self.mmu.read_word(self.r.interrupts[&interrupt])
+        let vector = self.r.interrupts[&interrupt];
+        self.read_word(vector)
     }
 
     // ---- addressing modes ----
@@ -126,11 +522,11 @@ impl CPU {
     }
 
     pub fn zx_a(&mut self) -> u16 {
-        ((self.next_byte() + self.r.x) & 0xFF) as u16
+        self.next_byte().wrapping_add(self.r.x) as u16
     }
 
     pub fn zy_a(&mut self) -> u16 {
-        ((self.next_byte() + self.r.y) & 0xFF) as u16
+        self.next_byte().wrapping_add(self.r.y) as u16
     }
 
     // absolute addressing
@@ -140,7 +536,7 @@ impl CPU {
 
     pub fn ax_a(&mut self) -> u16 {
         let op = self.next_word();
-        let a = op + (self.r.x as u16);
+        let a = op.wrapping_add(self.r.x as u16);
 
         if op / 0xFF != a / 0xFF {
             self.r.cc += 1;
@@ -151,7 +547,7 @@ impl CPU {
 
     pub fn ay_a(&mut self) -> u16 {
         let op = self.next_word();
-        let a = op + (self.r.y as u16);
+        let a = op.wrapping_add(self.r.y as u16);
 
         if op / 0xFF != a / 0xFF {
             self.r.cc += 1;
@@ -173,23 +569,23 @@ impl CPU {
             j = i + 1;
         }
 
-        (((self.mmu.read(j as usize) as u16) << 8) + self.mmu.read(i as usize) as u16) & 0xFFFF
+        ((self.read(j as usize) as u16) << 8) + self.read(i as usize) as u16
     }
 
 
     pub fn ix_a(&mut self) -> u16 {
-        let i = (self.next_byte() + self.r.x) & 0xFF;
-        let u = self.mmu.read(((i + 1) & 0xff) as usize);
-        let l = self.mmu.read(i as usize);
+        let i = self.next_byte().wrapping_add(self.r.x);
+        let u = self.read(i.wrapping_add(1) as usize);
+        let l = self.read(i as usize);
         (((u as u16) << 8) + l as u16) & 0xffff
     }
 
     pub fn iy_a(&mut self) -> u16 {
         let i = self.next_byte();
-        let u = self.mmu.read((i as usize + 1) & 0xFF);
-        let l = self.mmu.read(i as usize);
+        let u = self.read((i as usize + 1) & 0xFF);
+        let l = self.read(i as usize);
         let o = ((u as u16) << 8) + (l as u16);
-        let a = o + (self.r.y as u16);
+        let a = o.wrapping_add(self.r.y as u16);
 
         if o / 0xFF != a / 0xFF {
             self.r.cc += 1;
@@ -216,34 +612,34 @@ impl CPU {
     // led to the loss of usefulness of zero page addressing.
     pub fn z(&mut self) -> u16 {
         let addr = self.z_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     pub fn zx(&mut self) -> u16 {
         let addr = self.zx_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     pub fn zy(&mut self) -> u16 {
         let addr = self.zy_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     // absolute addressing
     // The full memory location (16 bits) is used as an address to the argument byte.
     pub fn a(&mut self) -> u16 {
         let addr = self.a_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     pub fn ax(&mut self) -> u16 {
         let addr = self.ax_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     pub fn ay(&mut self) -> u16 {
         let addr = self.ay_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     // indirect addressing
@@ -251,17 +647,17 @@ impl CPU {
     // which contains the location of the argument byte.
     pub fn i(&mut self) -> u16 {
         let addr = self.i_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     pub fn ix(&mut self) -> u16 {
         let addr = self.ix_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 
     pub fn iy(&mut self) -> u16 {
         let addr = self.iy_a();
-        self.mmu.read(addr as usize) as u16
+        self.read(addr as usize) as u16
     }
 }
 
@@ -269,16 +665,32 @@ impl CPU {
 // set the pc to point to the first byte in ROM.
 // TODO: expand to allow video RAM, and static program RAM, and static program ROM 
 // (static data).
-pub fn make_cpu(rom_init: Option<Vec<u8>>) -> CPU {
-        let mut mmu = MMU::new(&Vec::new());
+// This fails if the ROM doesn't fit.
+pub fn make_cpu(rom_init: Option<Vec<u8>>) -> Result<CPU, EmuError> {
+        let mut mmu = MMU::new(&Vec::new())?;
         // RAM
-        mmu.add_block(&Block::new(0, 0x200, false, None));
+        mmu.add_block(&Block::new(0, 0x200, false, None)?)?;
         // ROM
-        mmu.add_block(&Block::new(0x1000, 0x100, true, rom_init));
+        mmu.add_block(&Block::new(0x1000, 0x100, true, rom_init)?)?;
 
         let mut cpu = CPU::new(mmu);
         cpu.r.pc = 0x1000;
-        cpu
+        Ok(cpu)
+}
+
+// Construct the cpu the snake game runs on: RAM below $0600 (the screen, keys and random
+// number are all in there) and the program in ROM from $0600, where the pc starts.
+// This fails if the ROM doesn't fit.
+pub fn make_snake_cpu(rom_init: Option<Vec<u8>>) -> Result<CPU, EmuError> {
+        let mut mmu = MMU::new(&Vec::new())?;
+        // RAM
+        mmu.add_block(&Block::new(0, 0x600, false, None)?)?;
+        // ROM
+        mmu.add_block(&Block::new(0x600, 0x1000, true, rom_init)?)?;
+
+        let mut cpu = CPU::new(mmu);
+        cpu.r.pc = 0x600;
+        Ok(cpu)
 }
 
 
@@ -289,7 +701,7 @@ mod tests {
 
     #[test]
     fn test_construct_cpu() {
-        let mmu = MMU::new(&Vec::new());
+        let mmu = MMU::new(&Vec::new()).unwrap();
         let cpu = CPU::new(mmu);
     }
 
@@ -297,7 +709,7 @@ mod tests {
 
     #[test]
     fn test_to_bcd() {
-        let mut cpu = make_cpu(Some(vec![]));
+        let cpu = make_cpu(Some(vec![])).unwrap();
 
         assert_eq!(cpu.to_bcd(0), 0);
         assert_eq!(cpu.to_bcd(5), 0x05);
@@ -307,7 +719,7 @@ mod tests {
 
     #[test]
     fn test_from_bcd() {
-        let mut cpu = make_cpu(None);
+        let cpu = make_cpu(None).unwrap();
 
         assert_eq!(cpu.from_bcd(0), 0);
         assert_eq!(cpu.from_bcd(0x05), 5);
@@ -317,7 +729,7 @@ mod tests {
 
     #[test]
     fn test_from_twos_com() {
-        let mut cpu = make_cpu(None);
+        let cpu = make_cpu(None).unwrap();
 
         assert_eq!(cpu.from_twos_com(0x00), 0);
         assert_eq!(cpu.from_twos_com(0x01), 1);
@@ -328,7 +740,7 @@ mod tests {
 
     #[test]
     fn test_next_byte() {
-        let mut cpu = make_cpu(Some(vec![1, 2, 3]));
+        let mut cpu = make_cpu(Some(vec![1, 2, 3])).unwrap();
 
         assert_eq!(cpu.next_byte(), 1);
         assert_eq!(cpu.next_byte(), 2);
@@ -338,7 +750,7 @@ mod tests {
 
     #[test]
     fn test_next_word() {
-        let mut cpu = make_cpu(Some(vec![1, 2, 3, 4, 5, 9, 10]));
+        let mut cpu = make_cpu(Some(vec![1, 2, 3, 4, 5, 9, 10])).unwrap();
 
         assert_eq!(cpu.next_word(), 0x0201);
         cpu.next_byte();
@@ -348,7 +760,7 @@ mod tests {
 
     #[test]
     fn test_stack() {
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
 
         cpu.stack_push(0x10);
         assert_eq!(cpu.stack_pop(), 0x10);
@@ -359,12 +771,19 @@ mod tests {
         cpu.stack_push_word(0x0510);
         assert_eq!(cpu.stack_pop(), 0x10);
         assert_eq!(cpu.stack_pop(), 0x05);
+
+        // the stack lives in page $01, growing down from $01FF
+        cpu.r.s = 0xFF;
+        cpu.stack_push(0x42);
+        assert_eq!(cpu.mmu.read(0x1FF), Ok(0x42));
+        assert_eq!(cpu.mmu.read(0xFF), Ok(0x00));
+        assert_eq!(cpu.r.s, 0xFE);
     }
 
     // ----- test addressing modes -----
     #[test]
     fn test_zeropage_addressing() {
-        let mut cpu = make_cpu(Some(vec![1, 2, 3, 4, 5]));
+        let mut cpu = make_cpu(Some(vec![1, 2, 3, 4, 5])).unwrap();
         assert_eq!(cpu.z_a(), 1);
 
         cpu.r.x = 0;
@@ -382,7 +801,7 @@ mod tests {
     fn test_absolute_addressing() {
         let mut cpu = make_cpu(
             Some(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10])
-        );
+        ).unwrap();
         assert_eq!(cpu.a_a(), 0x0201);
 
         cpu.r.x = 0;
@@ -414,22 +833,187 @@ mod tests {
                 0x00, 0x00,
                 0xF0, 0x00,
             ])
-        );
+        ).unwrap();
 
         assert_eq!(cpu.i_a(), 0x00F0);
         assert_eq!(cpu.i_a(), 0x0600);
 
         cpu.r.y = 0x05;
-        cpu.mmu.write(0x00, 0x21);
-        cpu.mmu.write(0x01, 0x43);
+        cpu.mmu.write(0x00, 0x21).unwrap();
+        cpu.mmu.write(0x01, 0x43).unwrap();
         assert_eq!(cpu.iy_a(), 0x4326);
 
         cpu.r.x = 0x02;
-        cpu.mmu.write(0x02, 0x34);
-        cpu.mmu.write(0x03, 0x12);
+        cpu.mmu.write(0x02, 0x34).unwrap();
+        cpu.mmu.write(0x03, 0x12).unwrap();
         assert_eq!(cpu.ix_a(), 0x1234);
     }
     
+    #[test]
+    fn test_stack_wrap() {
+        let mut cpu = make_cpu(None).unwrap();
+        cpu.op_pc = 0x1234;
+
+        cpu.stack_pop();
+        assert_eq!(cpu.fault.take(), Some(EmuError::StackWrap { pc: 0x1234 }));
+
+        cpu.r.s = 0;
+        cpu.stack_push(0x01);
+        assert_eq!(cpu.r.s, 0xFF);
+        assert_eq!(cpu.fault.take(), Some(EmuError::StackWrap { pc: 0x1234 }));
+    }
+
+    // ----- test step errors -----
+
+    #[test]
+    fn test_step_illegal_opcode() {
+        let mut cpu = make_cpu(Some(vec![0x18, 0xFF])).unwrap();
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(EmuError::IllegalOpcode { opcode: 0xFF, pc: 0x1001 }));
+        // the pc is left on the bad opcode
+        assert_eq!(cpu.r.pc, 0x1001);
+    }
+
+    #[test]
+    fn test_step_jammed() {
+        let mut cpu = make_cpu(Some(vec![0x02])).unwrap();
+
+        assert_eq!(cpu.step(), Err(EmuError::Jammed { pc: 0x1000 }));
+        assert_eq!(cpu.step(), Err(EmuError::Jammed { pc: 0x1000 }));
+    }
+
+    #[test]
+    fn test_step_memory_errors() {
+        // sta $1000 / lda $8000 / jmp $2000
+        let mut cpu = make_cpu(Some(vec![0x8D, 0x00, 0x10, 0xAD, 0x00, 0x80, 0x4C, 0x00, 0x20])).unwrap();
+
+        assert_eq!(cpu.step(), Err(EmuError::RomWrite(0x1000)));
+        assert_eq!(cpu.step(), Err(EmuError::UnmappedAddress(0x8000)));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(EmuError::UnmappedAddress(0x2000)));
+        assert_eq!(cpu.r.pc, 0x2000);
+    }
+
+    #[test]
+    fn test_make_cpu_rom_too_big() {
+        assert!(make_cpu(Some(vec![0; 0x101])).is_err());
+    }
+
+    // ----- test run -----
+
+    // inx / jmp $1000, 5 cycles a time round, which keeps changing X rather than looping in
+    // the same state
+    fn make_loop() -> Vec<u8> {
+        vec![0xE8, 0x4C, 0x00, 0x10]
+    }
+
+    #[test]
+    fn test_run_budget() {
+        let mut cpu = make_cpu(Some(make_loop())).unwrap();
+
+        assert_eq!(cpu.run(12), Ok(StopReason::Budget));
+        assert_eq!(cpu.r.cc, 12);
+        assert_eq!(cpu.r.pc, 0x1001);
+
+        // the budget is counted from where run was called
+        assert_eq!(cpu.run(1), Ok(StopReason::Budget));
+        assert_eq!(cpu.r.cc, 15);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut cpu = make_cpu(Some(make_loop())).unwrap();
+        cpu.frame_cycles = 4;
+
+        assert_eq!(cpu.run(100), Ok(StopReason::Frame));
+        assert_eq!(cpu.r.cc, 5);
+        assert_eq!(cpu.run(100), Ok(StopReason::Frame));
+        assert_eq!(cpu.r.cc, 10);
+    }
+
+    #[test]
+    fn test_run_breakpoint() {
+        let mut cpu = make_cpu(Some(make_loop())).unwrap();
+        cpu.breakpoints.insert(0x1001);
+
+        assert_eq!(cpu.run(100), Ok(StopReason::Breakpoint(0x1001)));
+        assert_eq!(cpu.r.cc, 2);
+
+        // carries on past the breakpoint it is sitting on
+        assert_eq!(cpu.run(100), Ok(StopReason::Breakpoint(0x1001)));
+        assert_eq!(cpu.r.cc, 7);
+    }
+
+    #[test]
+    fn test_run_brk() {
+        let mut cpu = make_cpu(Some(vec![0x18, 0x00])).unwrap();
+        cpu.stop_on_brk = true;
+
+        assert_eq!(cpu.run(100), Ok(StopReason::Brk(0x1001)));
+        assert_eq!(cpu.r.pc, 0x1001);
+        assert_eq!(cpu.run(100), Ok(StopReason::Brk(0x1001)));
+
+        // otherwise it runs, through a vector that isn't mapped here
+        cpu.stop_on_brk = false;
+        assert_eq!(cpu.run(100), Err(EmuError::UnmappedAddress(0xFFFF)));
+    }
+
+    #[test]
+    fn test_run_self_loop() {
+        // lda #$01 / jmp $1002
+        let mut cpu = make_cpu(Some(vec![0xA9, 0x01, 0x4C, 0x02, 0x10])).unwrap();
+
+        assert_eq!(cpu.run(100), Ok(StopReason::SelfLoop(0x1002)));
+        assert_eq!(cpu.r.a, 1);
+        assert_eq!(cpu.r.cc, 5);
+    }
+
+    #[test]
+    fn test_run_loop() {
+        // nop / clc / jmp $1000
+        let mut cpu = make_cpu(Some(vec![0xEA, 0x18, 0x4C, 0x00, 0x10])).unwrap();
+
+        assert_eq!(cpu.run(100), Ok(StopReason::SelfLoop(0x1001)));
+        assert_eq!(cpu.r.cc, 9);
+
+        // inc $10 / jmp $1000 comes back to the same registers, but has written memory
+        let mut cpu = make_cpu(Some(vec![0xE6, 0x10, 0x4C, 0x00, 0x10])).unwrap();
+        assert_eq!(cpu.run(100), Ok(StopReason::Budget));
+    }
+
+    #[test]
+    fn test_run_infinite_loop_bin() {
+        // nop / nop / jmp $1000
+        let mut cpu = make_cpu(Some(include_bytes!("../infinite_loop.bin").to_vec())).unwrap();
+
+        assert_eq!(cpu.run(100), Ok(StopReason::SelfLoop(0x1001)));
+    }
+
+    #[test]
+    fn test_run_error() {
+        let mut cpu = make_cpu(Some(vec![0x18, 0xFF])).unwrap();
+
+        assert_eq!(cpu.run(100), Err(EmuError::IllegalOpcode { opcode: 0xFF, pc: 0x1001 }));
+    }
+
+    #[test]
+    fn test_bus_log() {
+        // lda #$07 / sta $10
+        let mut cpu = make_cpu(Some(vec![0xA9, 0x07, 0x85, 0x10])).unwrap();
+        cpu.enable_bus_log();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.bus_log, Some(vec![
+            (0x1000, 0xA9, "read"),
+            (0x1001, 0x07, "read"),
+            (0x1002, 0x85, "read"),
+            (0x1003, 0x10, "read"),
+            (0x0010, 0x07, "write"),
+        ]));
+    }
+
     // see ops.rs for implementation and unit tests of ops
 
     // ----- comprehensive tests -----
@@ -0,0 +1,170 @@
+use cpu::Instr;
+
+/// An instruction as decoded the first time it ran, so the next time it doesn't have to be
+/// fetched through the MMU and looked up in the op table again
+#[derive(Clone, Copy, Debug)]
+pub struct Decoded {
+    pub instr: Instr,
+    pub opcode: u8,
+
+    /// The bytes after the opcode, low byte first
+    pub operands: u16,
+
+    /// Opcode and operands
+    pub length: u16,
+
+    /// Cycles before any page crossing penalty
+    pub cycles: u8,
+}
+
+/// Decoded instructions keyed by address, kept while `CPU::enable_decode_cache` is on.
+///
+/// Only instructions in memory blocks are cached, devices are fetched from every time. Each
+/// page an instruction came from is watched through the MMU's `CodePages`, and everything
+/// decoded from a page is dropped once it is written to, so self-modifying code still sees
+/// its own changes.
+#[derive(Debug)]
+pub struct DecodeCache {
+    entries: Vec<Option<Decoded>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            entries: vec![None; 0x10000],
+        }
+    }
+
+    pub fn get(&self, pc: u16) -> Option<Decoded> {
+        self.entries[pc as usize]
+    }
+
+    pub fn insert(&mut self, pc: u16, decoded: Decoded) {
+        self.entries[pc as usize] = Some(decoded);
+    }
+
+    /// Forget every instruction with a byte in `page`
+    pub fn invalidate(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        // up to two bytes before the page, for instructions that run over into it
+        for i in 0..0x102 {
+            self.entries[start.wrapping_add(i).wrapping_sub(2) as usize] = None;
+        }
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new()
+    }
+}
+
+/// The pages the decode cache has instructions from. The MMU notes down when one of these
+/// is written, for the CPU to invalidate before its next instruction.
+#[derive(Clone, Debug)]
+pub struct CodePages {
+    pub watched: Vec<bool>,
+
+    /// Watched pages written since the CPU last looked
+    pub written: Vec<u8>,
+}
+
+impl CodePages {
+    pub fn new() -> CodePages {
+        CodePages {
+            watched: vec![false; 256],
+            written: Vec::new(),
+        }
+    }
+
+    pub fn watch(&mut self, addr: u16) {
+        self.watched[(addr >> 8) as usize] = true;
+    }
+
+    /// Called by the MMU for every write
+    pub fn touch(&mut self, addr: usize) {
+        let page = (addr >> 8) & 0xFF;
+        if self.watched[page] {
+            self.watched[page] = false;
+            self.written.push(page as u8);
+        }
+    }
+}
+
+impl Default for CodePages {
+    fn default() -> CodePages {
+        CodePages::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use cpu::CPU;
+    use mmu::{Block, MMU};
+
+    // lda #$01 / inc $0201 / jmp $0200, where the inc changes what the lda loads
+    fn make_self_modifying_cpu() -> CPU {
+        let mut program = vec![0; 0x200];
+        program.extend(vec![0xA9, 0x01, 0xEE, 0x01, 0x02, 0x4C, 0x00, 0x02]);
+        let mmu = MMU::new(&vec![Block::new(0, 0x1000, false, Some(program)).unwrap()]).unwrap();
+        let mut cpu = CPU::new(mmu);
+        cpu.r.pc = 0x0200;
+        cpu
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut plain = make_self_modifying_cpu();
+        let mut cached = make_self_modifying_cpu();
+        cached.enable_decode_cache();
+
+        for _ in 0..3 {
+            for _ in 0..3 {
+                plain.step().unwrap();
+                cached.step().unwrap();
+                assert_eq!(cached.r.pc, plain.r.pc);
+                assert_eq!(cached.r.a, plain.r.a);
+                assert_eq!(cached.r.cc, plain.r.cc);
+            }
+        }
+        assert_eq!(cached.r.a, 3);
+        assert!(cached.decode_cache.as_ref().unwrap().get(0x0205).is_some());
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let mut cpu = make_self_modifying_cpu();
+        cpu.enable_decode_cache();
+        cpu.step().unwrap();
+
+        let decoded = cpu.decode_cache.as_ref().unwrap().get(0x0200).unwrap();
+        assert_eq!(decoded.opcode, 0xA9);
+        assert_eq!(decoded.operands, 0x01);
+        assert_eq!(decoded.length, 2);
+        assert_eq!(decoded.cycles, 2);
+
+        // a write elsewhere leaves it alone
+        cpu.mmu.write(0x0500, 0xFF).unwrap();
+        cpu.r.pc = 0x0200;
+        cpu.step().unwrap();
+        assert!(cpu.decode_cache.as_ref().unwrap().get(0x0200).is_some());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = DecodeCache::new();
+        let decoded = make_self_modifying_cpu().ops[0xEA];
+        for &pc in &[0x01FE, 0x01FF, 0x0200, 0x02FF, 0x0300] {
+            cache.insert(pc, Decoded { instr: decoded, opcode: 0xEA, operands: 0, length: 1, cycles: 2 });
+        }
+
+        cache.invalidate(0x02);
+        assert!(cache.get(0x01FE).is_none());
+        assert!(cache.get(0x01FF).is_none());
+        assert!(cache.get(0x0200).is_none());
+        assert!(cache.get(0x02FF).is_none());
+        assert!(cache.get(0x0300).is_some());
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+/// Memory mapped hardware that sits on the bus in place of plain memory, like the 6522 VIA.
+///
+/// The MMU hands reads and writes in the device's range to it, with the address made
+/// relative to where it is mapped, so `reg` is the register number. Every instruction the
+/// CPU runs is passed on to `tick` as a cycle count, which is what drives timers.
+pub trait Device: fmt::Debug + Send {
+    /// How many addresses the device takes up
+    fn length(&self) -> usize;
+
+    fn read(&mut self, reg: usize) -> u8;
+
+    fn write(&mut self, reg: usize, val: u8);
+
+    /// Let `cycles` clock cycles pass
+    fn tick(&mut self, _cycles: u32) {}
+
+    /// If the device is pulling the IRQ line low
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+/// A device and where it sits in the address space
+#[derive(Debug)]
+pub struct MappedDevice {
+    pub start: usize,
+    pub length: usize,
+    pub device: Box<dyn Device>,
+}
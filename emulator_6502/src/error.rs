@@ -0,0 +1,74 @@
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong while setting up or running the emulator.
+///
+/// These are returned from `CPU::step` and the MMU rather than panicking, so whatever is
+/// hosting the emulator (the snake window, a test harness, a debugger) can report the
+/// problem and carry on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmuError {
+    /// Nothing is mapped at this address
+    UnmappedAddress(usize),
+
+    /// Attempted to write to a readonly block
+    RomWrite(usize),
+
+    /// A block with no length, or initial memory that doesn't fit in it
+    InvalidBlock { start: usize, length: usize },
+
+    /// A block that overlaps one already in the MMU
+    OverlappingBlock { start: usize, length: usize },
+
+    /// An opcode that isn't implemented, and the address it was fetched from
+    IllegalOpcode { opcode: u8, pc: u16 },
+
+    /// The stack pointer wrapped around the stack page
+    StackWrap { pc: u16 },
+
+    /// One of the JAM (aka KIL) opcodes was executed, which halts a real 6502
+    Jammed { pc: u16 },
+
+    /// A machine description that can't be read or built
+    InvalidMachine(String),
+
+    /// A program image (Intel HEX, S-record, PRG) that can't be parsed
+    InvalidImage(String),
+
+    /// The file behind battery-backed RAM can't be read or written
+    BatteryRam(String),
+
+    /// A line of an assembler debug map that can't be read
+    InvalidDebugMap(String),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmuError::UnmappedAddress(addr) =>
+                write!(f, "no memory mapped at ${:04x}", addr),
+            EmuError::RomWrite(addr) =>
+                write!(f, "attempted to write to readonly memory at ${:04x}", addr),
+            EmuError::InvalidBlock { start, length } =>
+                write!(f, "illegal memory block at ${:04x} (length {:#x})", start, length),
+            EmuError::OverlappingBlock { start, length } =>
+                write!(f, "memory block at ${:04x} (length {:#x}) overlaps another block", start, length),
+            EmuError::IllegalOpcode { opcode, pc } =>
+                write!(f, "illegal opcode ${:02x} at ${:04x}", opcode, pc),
+            EmuError::StackWrap { pc } =>
+                write!(f, "stack pointer wrapped around at ${:04x}", pc),
+            EmuError::Jammed { pc } =>
+                write!(f, "cpu jammed at ${:04x}", pc),
+            EmuError::InvalidMachine(ref reason) =>
+                write!(f, "invalid machine description: {}", reason),
+            EmuError::InvalidImage(ref reason) =>
+                write!(f, "invalid program image: {}", reason),
+            EmuError::BatteryRam(ref reason) =>
+                write!(f, "battery-backed RAM: {}", reason),
+            EmuError::InvalidDebugMap(ref reason) =>
+                write!(f, "invalid debug map: {}", reason),
+        }
+    }
+}
+
+impl Error for EmuError {}
@@ -0,0 +1,443 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+
+use cpu::{CPU, StopReason};
+use error::EmuError;
+
+// signals sent back to the debugger in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// how many cycles run between checks for a ctrl-c from the debugger
+const POLL_CYCLES: u32 = 4000;
+
+/// What the server loop should do after a packet has been handled
+enum Action {
+    Reply(String),
+    Continue,
+    Detach,
+    Kill,
+}
+
+/// A GDB remote serial protocol stub, so gdb (or any other front-end that talks RSP) can
+/// drive the CPU over TCP.
+///
+/// There is no 6502 target in gdb, so the register layout is our own: A, X, Y, P and S are
+/// one byte each, followed by the two byte PC, all in target (little endian) order. That is
+/// register numbers 0-4 and 5 for `p`/`P`.
+///
+/// Software and hardware breakpoints both go in the cpu's `breakpoints` rather than being
+/// patched into memory, since most programs run from ROM.
+///
+/// Waits for a debugger to connect to `listener`, then serves it until it detaches, kills
+/// the target or hangs up.
+pub fn serve(cpu: &mut CPU, listener: TcpListener) -> io::Result<()> {
+    let (mut stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    loop {
+        let packet = match read_packet(&mut stream)? {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+
+        match handle_packet(cpu, &packet) {
+            Action::Reply(reply) => write_packet(&mut stream, &reply)?,
+            Action::Continue => {
+                let signal = resume(cpu, &mut stream)?;
+                write_packet(&mut stream, &stop_reply(signal))?;
+            },
+            Action::Detach => {
+                write_packet(&mut stream, "OK")?;
+                return Ok(());
+            },
+            Action::Kill => return Ok(()),
+        }
+    }
+}
+
+fn handle_packet(cpu: &mut CPU, packet: &str) -> Action {
+    let (command, args) = packet.split_at(packet.len().min(1));
+
+    let reply = match command {
+        "?" => stop_reply(SIGTRAP),
+        "g" => read_registers(cpu),
+        "G" => write_registers(cpu, args),
+        "p" => read_register(cpu, args),
+        "P" => write_register(cpu, args),
+        "m" => read_memory(cpu, args),
+        "M" => write_memory(cpu, args),
+        "c" => {
+            set_pc(cpu, args);
+            return Action::Continue;
+        },
+        "s" => {
+            set_pc(cpu, args);
+            match cpu.step() {
+                Ok(()) => stop_reply(SIGTRAP),
+                Err(error) => stop_reply(error_signal(&error)),
+            }
+        },
+        "Z" | "z" => breakpoint(cpu, command == "Z", args),
+        "D" => return Action::Detach,
+        "k" => return Action::Kill,
+        "H" => "OK".to_string(),
+        "q" if args.starts_with("Supported") => "PacketSize=1000".to_string(),
+        "q" if args == "Attached" => "1".to_string(),
+        // anything else is unsupported, which the protocol says to answer with nothing
+        _ => String::new(),
+    };
+    Action::Reply(reply)
+}
+
+// Z/z type,addr,kind - only types 0 (software) and 1 (hardware) are supported
+fn breakpoint(cpu: &mut CPU, insert: bool, args: &str) -> String {
+    let fields = args.split(',').collect::<Vec<&str>>();
+    if fields.len() < 2 || (fields[0] != "0" && fields[0] != "1") {
+        return String::new();
+    }
+    let addr = match u16::from_str_radix(fields[1], 16) {
+        Ok(addr) => addr,
+        Err(_) => return "E01".to_string(),
+    };
+
+    if insert {
+        cpu.breakpoints.insert(addr);
+    } else {
+        cpu.breakpoints.remove(&addr);
+    }
+    "OK".to_string()
+}
+
+// Run until a breakpoint is hit, the cpu faults or the debugger sends a ctrl-c, returning
+// the signal to report. `CPU::run` steps off a breakpoint it starts on, so continuing from
+// one works; after the first run a breakpoint where the last one left off still counts.
+// Loops the cpu can't leave just keep it running, as they would on the real thing.
+fn resume(cpu: &mut CPU, stream: &mut TcpStream) -> io::Result<u8> {
+    let mut first = true;
+    loop {
+        if !first && cpu.breakpoints.contains(&cpu.r.pc) {
+            return Ok(SIGTRAP);
+        }
+        first = false;
+
+        match cpu.run(POLL_CYCLES) {
+            Ok(StopReason::Breakpoint(_)) | Ok(StopReason::Brk(_)) => return Ok(SIGTRAP),
+            Ok(_) => {},
+            Err(error) => return Ok(error_signal(&error)),
+        }
+        if interrupted(stream)? {
+            return Ok(SIGINT);
+        }
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+// the closest unix signal to each emulator error
+fn error_signal(error: &EmuError) -> u8 {
+    match *error {
+        EmuError::IllegalOpcode { .. } | EmuError::Jammed { .. } => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+fn read_registers(cpu: &CPU) -> String {
+    let pc = cpu.r.pc;
+    to_hex(&[cpu.r.a, cpu.r.x, cpu.r.y, cpu.r.p, cpu.r.s as u8, pc as u8, (pc >> 8) as u8])
+}
+
+fn write_registers(cpu: &mut CPU, args: &str) -> String {
+    match from_hex(args) {
+        Some(ref bytes) if bytes.len() == 7 => {
+            cpu.r.a = bytes[0];
+            cpu.r.x = bytes[1];
+            cpu.r.y = bytes[2];
+            cpu.r.p = bytes[3];
+            cpu.r.s = bytes[4] as u16;
+            cpu.r.pc = (bytes[5] as u16) + ((bytes[6] as u16) << 8);
+            "OK".to_string()
+        },
+        _ => "E01".to_string(),
+    }
+}
+
+fn read_register(cpu: &CPU, args: &str) -> String {
+    let pc = cpu.r.pc;
+    match usize::from_str_radix(args, 16) {
+        Ok(0) => to_hex(&[cpu.r.a]),
+        Ok(1) => to_hex(&[cpu.r.x]),
+        Ok(2) => to_hex(&[cpu.r.y]),
+        Ok(3) => to_hex(&[cpu.r.p]),
+        Ok(4) => to_hex(&[cpu.r.s as u8]),
+        Ok(5) => to_hex(&[pc as u8, (pc >> 8) as u8]),
+        _ => "E01".to_string(),
+    }
+}
+
+// P n=value
+fn write_register(cpu: &mut CPU, args: &str) -> String {
+    let fields = args.split('=').collect::<Vec<&str>>();
+    if fields.len() != 2 {
+        return "E01".to_string();
+    }
+    let bytes = match from_hex(fields[1]) {
+        Some(ref bytes) if !bytes.is_empty() => bytes.clone(),
+        _ => return "E01".to_string(),
+    };
+
+    match usize::from_str_radix(fields[0], 16) {
+        Ok(0) => cpu.r.a = bytes[0],
+        Ok(1) => cpu.r.x = bytes[0],
+        Ok(2) => cpu.r.y = bytes[0],
+        Ok(3) => cpu.r.p = bytes[0],
+        Ok(4) => cpu.r.s = bytes[0] as u16,
+        Ok(5) if bytes.len() == 2 => cpu.r.pc = (bytes[0] as u16) + ((bytes[1] as u16) << 8),
+        _ => return "E01".to_string(),
+    }
+    "OK".to_string()
+}
+
+// parse "addr,length" as used by the memory packets
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let fields = args.split(',').collect::<Vec<&str>>();
+    if fields.len() != 2 {
+        return None;
+    }
+    let addr = usize::from_str_radix(fields[0], 16).ok()?;
+    let length = usize::from_str_radix(fields[1], 16).ok()?;
+    if addr + length > 0x10000 {
+        return None;
+    }
+    Some((addr, length))
+}
+
+// Peeked rather than read, so looking at memory doesn't change it or count towards the
+// profile. Devices can't be looked at without reading them, so they're an error too.
+fn read_memory(cpu: &CPU, args: &str) -> String {
+    let (addr, length) = match parse_range(args) {
+        Some(range) => range,
+        None => return "E01".to_string(),
+    };
+
+    match (addr..addr + length).map(|a| cpu.mmu.peek(a)).collect::<Option<Vec<u8>>>() {
+        Some(bytes) => to_hex(&bytes),
+        None => "E01".to_string(),
+    }
+}
+
+// M addr,length:bytes - checked up front so a bad write doesn't leave half the bytes written
+fn write_memory(cpu: &mut CPU, args: &str) -> String {
+    let fields = args.split(':').collect::<Vec<&str>>();
+    let (addr, length) = match parse_range(fields[0]) {
+        Some(range) => range,
+        None => return "E01".to_string(),
+    };
+    let bytes = match fields.get(1).and_then(|data| from_hex(data)) {
+        Some(bytes) => bytes,
+        None => return "E01".to_string(),
+    };
+
+    let writable = (addr..addr + length).all(|a| match cpu.mmu.block_at(a) {
+        Some(block) => !block.readonly,
+        None => false,
+    });
+    if bytes.len() != length || !writable {
+        return "E01".to_string();
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+        if cpu.mmu.write(addr + i, *byte).is_err() {
+            return "E01".to_string();
+        }
+    }
+    "OK".to_string()
+}
+
+// `c` and `s` may carry an address to resume from
+fn set_pc(cpu: &mut CPU, args: &str) {
+    if let Ok(addr) = u16::from_str_radix(args, 16) {
+        cpu.r.pc = addr;
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn read_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut buf = [0; 1];
+    match stream.read(&mut buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(buf[0])),
+    }
+}
+
+// Read one `$data#checksum` packet, acking it. Acks from the debugger and anything else
+// outside of a packet are skipped. Returns None once the debugger hangs up.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    loop {
+        match read_byte(stream)? {
+            Some(b'$') => {},
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+                None => return Ok(None),
+            }
+        }
+
+        let mut sent = String::new();
+        for _ in 0..2 {
+            match read_byte(stream)? {
+                Some(byte) => sent.push(byte as char),
+                None => return Ok(None),
+            }
+        }
+
+        if u8::from_str_radix(&sent, 16).ok() == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        // ask for a retransmit
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+    stream.write_all(packet.as_bytes())
+}
+
+// check, without blocking, whether the debugger sent a ctrl-c (or hung up)
+fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+    let mut buf = [0; 1];
+    stream.set_nonblocking(true)?;
+    let result = stream.read(&mut buf);
+    stream.set_nonblocking(false)?;
+
+    match result {
+        Ok(0) => Ok(true),
+        Ok(_) => Ok(buf[0] == 0x03),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use std::thread;
+    use cpu::make_cpu;
+
+    // a minimal scripted debugger: send a packet and return the reply
+    fn request(client: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        client.write_all(packet.as_bytes()).unwrap();
+        read_reply(client)
+    }
+
+    fn read_reply(client: &mut TcpStream) -> String {
+        let mut reply = Vec::new();
+        let mut in_packet = false;
+        loop {
+            let byte = read_byte(client).unwrap().expect("server hung up");
+            if !in_packet {
+                in_packet = byte == b'$';
+            } else if byte == b'#' {
+                break;
+            } else {
+                reply.push(byte);
+            }
+        }
+        // checksum
+        read_byte(client).unwrap();
+        read_byte(client).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // lda #$05 / tax / jmp $1003
+        let server = thread::spawn(move || {
+            let mut cpu = make_cpu(Some(vec![0xA9, 0x05, 0xAA, 0x4C, 0x03, 0x10])).unwrap();
+            serve(&mut cpu, listener).unwrap();
+            cpu
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(request(&mut client, "qSupported:multiprocess+"), "PacketSize=1000");
+        assert_eq!(request(&mut client, "?"), "S05");
+        assert_eq!(request(&mut client, "g"), "00000024ff0010");
+
+        // single step
+        assert_eq!(request(&mut client, "s"), "S05");
+        assert_eq!(request(&mut client, "p5"), "0210");
+        assert_eq!(request(&mut client, "p0"), "05");
+
+        // continue to a breakpoint
+        assert_eq!(request(&mut client, "Z0,1003,1"), "OK");
+        assert_eq!(request(&mut client, "c"), "S05");
+        assert_eq!(request(&mut client, "g"), "05050024ff0310");
+
+        // memory
+        assert_eq!(request(&mut client, "m1000,3"), "a905aa");
+        assert_eq!(request(&mut client, "M10,2:beef"), "OK");
+        assert_eq!(request(&mut client, "m10,2"), "beef");
+        assert_eq!(request(&mut client, "M1000,1:00"), "E01");
+        assert_eq!(request(&mut client, "m8000,1"), "E01");
+
+        // registers
+        assert_eq!(request(&mut client, "P0=42"), "OK");
+        assert_eq!(request(&mut client, "G42010224fd0310"), "OK");
+        assert_eq!(request(&mut client, "g"), "42010224fd0310");
+
+        // continue forever, then halt with a ctrl-c
+        assert_eq!(request(&mut client, "z0,1003,1"), "OK");
+        client.write_all(b"$c#63").unwrap();
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(read_reply(&mut client), "S02");
+
+        client.write_all(b"$k#6b").unwrap();
+        let mut cpu = server.join().unwrap();
+        assert_eq!(cpu.r.a, 0x42);
+        assert_eq!(cpu.r.pc, 0x1003);
+        assert_eq!(cpu.mmu.read(0x10), Ok(0xbe));
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(checksum(b"?"), 0x3f);
+    }
+}
@@ -0,0 +1,25 @@
+//! The emulator core, for embedding: as a Rust library, or through the C API in `capi`
+//! built into a cdylib.
+
+pub mod cpu;
+pub mod mmu;
+pub mod registers;
+pub mod ops;
+pub mod profiler;
+pub mod coverage;
+pub mod gdb;
+pub mod error;
+pub mod single_step;
+pub mod device;
+pub mod via;
+pub mod serial;
+pub mod acia;
+pub mod apple1;
+pub mod machine;
+pub mod loader;
+pub mod symbols;
+pub mod sanitizer;
+pub mod decode_cache;
+pub mod bench;
+pub mod clock;
+pub mod capi;
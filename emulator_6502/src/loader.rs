@@ -0,0 +1,399 @@
+extern crate asm6502;
+
+use std::path::Path;
+
+use self::asm6502::assemble_source;
+
+use cpu::CPU;
+use error::EmuError;
+use mmu::{Block, MMU};
+
+/// Program image formats that say where their contents go, unlike a raw binary
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Intel HEX, `:LLAAAATT...` records
+    IntelHex,
+
+    /// Motorola S-records, `S1`/`S2`/`S3` data with an `S9`/`S8`/`S7` terminator
+    SRecord,
+
+    /// A Commodore program file, the load address followed by the data
+    Prg,
+
+    /// 6502 assembly source, run through the assembler
+    Assembly,
+}
+
+/// Where assembly sources are assembled to unless the machine says otherwise, the same
+/// place the assembler's own binary puts them
+pub const ASSEMBLY_ORIGIN: u16 = 0x0600;
+
+/// Work out the format of an image from its file extension, or failing that its contents.
+/// PRG files and assembly sources can only be told apart by extension. None means a raw
+/// binary.
+pub fn detect_format(path: &Path, data: &[u8]) -> Option<Format> {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("hex") | Some("ihx") | Some("ihex") => return Some(Format::IntelHex),
+        Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => return Some(Format::SRecord),
+        Some("prg") => return Some(Format::Prg),
+        Some("6502asm") | Some("asm") => return Some(Format::Assembly),
+        _ => {},
+    }
+
+    // both text formats are all printable records, starting with the record mark
+    if !data.iter().all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace()) {
+        return None;
+    }
+    let text = String::from_utf8_lossy(data);
+    let mut first = text.trim_start().chars();
+    match (first.next(), first.next()) {
+        (Some(':'), _) => Some(Format::IntelHex),
+        (Some('S'), Some(digit)) if digit.is_ascii_digit() => Some(Format::SRecord),
+        _ => None,
+    }
+}
+
+/// A run of bytes and the address it starts at
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub start: usize,
+    pub data: Vec<u8>,
+}
+
+/// The contents of a program image, where to start it if the image says, and the labels
+/// from an assembly source
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+    pub symbols: Vec<(String, u16)>,
+}
+
+impl Image {
+    fn new() -> Image {
+        Image {
+            segments: Vec::new(),
+            entry: None,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Parse an image read from `path`. Assembly sources are assembled to `origin`, the other
+    /// formats say where everything goes themselves.
+    pub fn parse(format: Format, path: &str, data: &[u8], origin: u16) -> Result<Image, EmuError> {
+        match format {
+            Format::IntelHex => parse_intel_hex(&String::from_utf8_lossy(data)),
+            Format::SRecord => parse_srecord(&String::from_utf8_lossy(data)),
+            Format::Prg => parse_prg(data),
+            Format::Assembly => assemble(&String::from_utf8_lossy(data), path, origin),
+        }
+    }
+
+    /// Put the segments in memory, point the pc at the entry if there is one, and add the
+    /// labels to the cpu's symbols
+    pub fn load(&self, cpu: &mut CPU) -> Result<(), EmuError> {
+        for segment in &self.segments {
+            cpu.mmu.load(segment.start, &segment.data)?;
+            if let Some(ref mut sanitizer) = cpu.sanitizer {
+                sanitizer.write(segment.start, segment.data.len());
+            }
+        }
+        if let Some(entry) = self.entry {
+            cpu.r.pc = entry;
+        }
+        for &(ref name, address) in &self.symbols {
+            cpu.symbols.insert(name, address);
+        }
+        Ok(())
+    }
+
+    // Add bytes at `addr`, joining them onto the last segment when they follow on from it
+    fn add(&mut self, addr: usize, data: &[u8]) {
+        if let Some(last) = self.segments.last_mut() {
+            if last.start + last.data.len() == addr {
+                last.data.extend_from_slice(data);
+                return;
+            }
+        }
+        if !data.is_empty() {
+            self.segments.push(Segment {
+                start: addr,
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+// Construct a cpu with all 64K as RAM, for images to be loaded into
+pub fn make_ram_cpu() -> Result<CPU, EmuError> {
+    let mut mmu = MMU::new(&Vec::new())?;
+    mmu.add_block(&Block::new(0, 0x10000, false, None)?)?;
+    Ok(CPU::new(mmu))
+}
+
+fn error(line: usize, reason: &str) -> EmuError {
+    EmuError::InvalidImage(format!("line {}: {}", line + 1, reason))
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn be_value(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as usize)
+}
+
+/// Parse Intel HEX. Extended segment (02) and linear (04) address records move the base,
+/// and either start record (03, 05) gives the entry.
+pub fn parse_intel_hex(text: &str) -> Result<Image, EmuError> {
+    let mut image = Image::new();
+    let mut base = 0;
+
+    for (line, record) in text.lines().enumerate() {
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        if !record.starts_with(':') {
+            return Err(error(line, "record doesn't start with ':'"));
+        }
+        let bytes = parse_hex_bytes(&record[1..]).ok_or_else(|| error(line, "not hex"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error(line, "wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(error(line, "bad checksum"));
+        }
+
+        let addr = be_value(&bytes[1..3]);
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => image.add(base + addr, data),
+            0x01 => break,
+            0x02 if data.len() == 2 => base = be_value(data) << 4,
+            0x04 if data.len() == 2 => base = be_value(data) << 16,
+            // CS:IP
+            0x03 if data.len() == 4 => image.entry = Some(((be_value(&data[..2]) << 4) + be_value(&data[2..])) as u16),
+            0x05 if data.len() == 4 => image.entry = Some(be_value(data) as u16),
+            _ => return Err(error(line, "unknown record type")),
+        }
+    }
+    Ok(image)
+}
+
+/// Parse Motorola S-records. The terminator's address is the entry, except that 0 is what
+/// tools write when there isn't one.
+pub fn parse_srecord(text: &str) -> Result<Image, EmuError> {
+    let mut image = Image::new();
+
+    for (line, record) in text.lines().enumerate() {
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        if !record.starts_with('S') || record.len() < 2 || !record.is_ascii() {
+            return Err(error(line, "record doesn't start with S"));
+        }
+        let bytes = parse_hex_bytes(&record[2..]).ok_or_else(|| error(line, "not hex"))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error(line, "wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xFF {
+            return Err(error(line, "bad checksum"));
+        }
+
+        let address_length = match &record[1..2] {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(error(line, "unknown record type")),
+        };
+        if bytes.len() < address_length + 2 {
+            return Err(error(line, "record too short"));
+        }
+        let addr = be_value(&bytes[1..address_length + 1]);
+        let data = &bytes[address_length + 1..bytes.len() - 1];
+        match &record[1..2] {
+            "1" | "2" | "3" => image.add(addr, data),
+            "7" | "8" | "9" => {
+                if addr != 0 {
+                    image.entry = Some(addr as u16);
+                }
+                break;
+            },
+            // the header and record counts
+            _ => {},
+        }
+    }
+    Ok(image)
+}
+
+// Assembled in memory, at `origin` unless the source says otherwise with `.org`, starting
+// at the first instruction. Diagnostics name `path`, and `.include` looks next to it. Every
+// error the assembler finds goes into the one returned.
+fn assemble(source: &str, path: &str, origin: u16) -> Result<Image, EmuError> {
+    let program = assemble_source(source, path, origin).map_err(|diagnostics| {
+        let reports: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+        EmuError::InvalidImage(format!("assembly failed\n{}", reports.join("\n")))
+    })?;
+    let mut image = Image::new();
+    image.add(program.origin as usize, &program.bytes);
+    image.entry = Some(program.debug_map.first().map_or(program.origin, |&(address, _, _)| address));
+    image.symbols = program.symbols;
+    Ok(image)
+}
+
+/// Parse a PRG, which is the little endian load address followed by the program
+pub fn parse_prg(data: &[u8]) -> Result<Image, EmuError> {
+    if data.len() < 2 {
+        return Err(EmuError::InvalidImage("no load address".to_string()));
+    }
+    let mut image = Image::new();
+    image.add(data[0] as usize | (data[1] as usize) << 8, &data[2..]);
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(Path::new("a.HEX"), b""), Some(Format::IntelHex));
+        assert_eq!(detect_format(Path::new("a.s19"), b""), Some(Format::SRecord));
+        assert_eq!(detect_format(Path::new("a.prg"), b"\x01\x08"), Some(Format::Prg));
+        assert_eq!(detect_format(Path::new("snake.6502asm"), b"lda #$01"), Some(Format::Assembly));
+        assert_eq!(detect_format(Path::new("a.txt"), b"\n:00000001FF\n"), Some(Format::IntelHex));
+        assert_eq!(detect_format(Path::new("a"), b"S9030000FC"), Some(Format::SRecord));
+        assert_eq!(detect_format(Path::new("a.bin"), b"S\x00"), None);
+        assert_eq!(detect_format(Path::new("a.bin"), &[0xA9, 0x01]), None);
+    }
+
+    #[test]
+    fn test_parse_intel_hex() {
+        let image = parse_intel_hex("\
+            :0406000001020304EC\n\
+            :02060400050Ae5\n\
+            :020000040000FA\n\
+            :02100000FFEE01\n\
+            :0400000500000600F1\n\
+            :00000001FF\n\
+            :01200000AA35\n").unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { start: 0x0600, data: vec![1, 2, 3, 4, 5, 0x0A] },
+            Segment { start: 0x1000, data: vec![0xFF, 0xEE] },
+        ]);
+        assert_eq!(image.entry, Some(0x0600));
+
+        assert!(parse_intel_hex(":0406000001020304ED").is_err());
+        assert!(parse_intel_hex(":05060000010203040A").is_err());
+        assert!(parse_intel_hex("0406000001020304EC").is_err());
+        assert!(parse_intel_hex(":00000006FA").is_err());
+    }
+
+    #[test]
+    fn test_parse_srecord() {
+        let image = parse_srecord("\
+            S00600004844521B\n\
+            S1080600A9018D0002B8\n\
+            S5030001FB\n\
+            S9030600F6\n").unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { start: 0x0600, data: vec![0xA9, 0x01, 0x8D, 0x00, 0x02] },
+        ]);
+        assert_eq!(image.entry, Some(0x0600));
+
+        assert_eq!(parse_srecord("S9030000FC").unwrap().entry, None);
+        assert!(parse_srecord("S1080600A9018D0002B9").is_err());
+        assert!(parse_srecord("S4030000FC").is_err());
+    }
+
+    #[test]
+    fn test_parse_prg() {
+        let image = parse_prg(&[0x01, 0x08, 0x0B, 0x08]).unwrap();
+        assert_eq!(image.segments, vec![Segment { start: 0x0801, data: vec![0x0B, 0x08] }]);
+        assert_eq!(image.entry, None);
+        assert!(parse_prg(&[0x01]).is_err());
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = b"  lda #$01\nloop:\n  sta $0200\n  jmp loop\n";
+        let image = Image::parse(Format::Assembly, "test.6502asm", source, 0x0800).unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { start: 0x0800, data: vec![0xA9, 0x01, 0x8D, 0x00, 0x02, 0x4C, 0x02, 0x08] },
+        ]);
+        assert_eq!(image.entry, Some(0x0800));
+
+        let mut cpu = make_ram_cpu().unwrap();
+        image.load(&mut cpu).unwrap();
+        assert_eq!(cpu.symbols.lookup("loop"), Some(0x0802));
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.r.pc, 0x0802);
+        assert_eq!(cpu.mmu.read(0x0200), Ok(0x01));
+
+        // data first, and somewhere else
+        let source = b"  .org $0900\nmessage:\n  .byte 1, 2\n  lda message\n";
+        let image = Image::parse(Format::Assembly, "test.6502asm", source, 0x0800).unwrap();
+        assert_eq!(image.segments, vec![Segment { start: 0x0900, data: vec![0x01, 0x02, 0xAD, 0x00, 0x09] }]);
+        assert_eq!(image.entry, Some(0x0902));
+
+        match Image::parse(Format::Assembly, "test.6502asm", b"  ldq $10\n", 0x0800) {
+            Err(EmuError::InvalidImage(reason)) => assert!(reason.contains("test.6502asm:1:3: error: unknown instruction `ldq`")),
+            other => panic!("expected an assembly error, got {:?}", other.map(|image| image.segments)),
+        }
+    }
+
+    #[test]
+    fn test_assemble_include() {
+        // the include is found next to the source, wherever the emulator was started from
+        let dir = env::temp_dir().join(format!("loader_test_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.6502asm"), "  .byte 7\n").unwrap();
+        let path = dir.join("main.6502asm");
+        let path = path.to_str().unwrap();
+
+        let image = Image::parse(Format::Assembly, path, b"  lda #$01\n  .include \"data.6502asm\"\n", 0x0800).unwrap();
+        assert_eq!(image.segments, vec![Segment { start: 0x0800, data: vec![0xA9, 0x01, 0x07] }]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_make_ram_cpu() {
+        let image = parse_srecord("S1080600A9018D0002B8\nS9030600F6").unwrap();
+        let mut cpu = make_ram_cpu().unwrap();
+        cpu.enable_sanitizer();
+        image.load(&mut cpu).unwrap();
+        assert_eq!(cpu.r.pc, 0x0600);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.mmu.read(0x0200), Ok(0x01));
+        // the loaded program counts as initialized
+        assert!(cpu.sanitizer.as_ref().unwrap().reports.is_empty());
+    }
+
+    #[test]
+    fn test_load_into_rom() {
+        let mut cpu = ::cpu::make_cpu(None).unwrap();
+        let image = parse_intel_hex(":0210000018EAEC\n:0400000500001001E6\n").unwrap();
+        image.load(&mut cpu).unwrap();
+        assert_eq!(cpu.mmu.read(0x1001), Ok(0xEA));
+        assert_eq!(cpu.r.pc, 0x1001);
+
+        let image = parse_prg(&[0x00, 0x30, 0xEA]).unwrap();
+        assert_eq!(image.load(&mut cpu), Err(EmuError::UnmappedAddress(0x3000)));
+    }
+}
@@ -0,0 +1,311 @@
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use self::serde::Deserialize;
+
+use acia::Acia;
+use apple1::Apple1Pia;
+use cpu::CPU;
+use device::Device;
+use error::EmuError;
+use mmu::{Block, MMU};
+use serial::{open_port, SerialPort};
+use via::Via;
+
+/// A whole machine: its memory map, the devices on the bus and how the cpu starts up.
+///
+/// It is read from TOML (or JSON, with the same layout), for example
+///
+/// ```toml
+/// pc = "$0400"        # optional, the reset vector at $FFFC is used otherwise
+/// sp = "$FF"          # optional
+/// origin = "$0800"    # optional, where assembly sources given with --load go
+///
+/// [[memory]]
+/// start = "$0000"
+/// length = "$8000"
+///
+/// [[memory]]
+/// start = "$FF00"
+/// length = "$100"
+/// readonly = true
+/// image = "monitor.bin"   # relative to the description file
+/// offset = 0              # where in the block the image goes
+///
+/// [[memory]]
+/// start = "$6000"
+/// length = "$2000"
+/// battery = "save.ram"    # battery-backed, kept in this file between runs
+///
+/// [[devices]]
+/// type = "acia"           # via, acia or apple1-pia
+/// base = "$D010"
+/// serial = "pty"          # for acia and apple1-pia, stdio by default
+/// ```
+///
+/// Addresses and lengths are numbers, or hex strings starting with $ or 0x.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MachineDescription {
+    #[serde(default)]
+    pub memory: Vec<MemoryDescription>,
+
+    #[serde(default)]
+    pub devices: Vec<DeviceDescription>,
+
+    /// Where to start, instead of the reset vector
+    pub pc: Option<Address>,
+
+    /// The initial stack pointer
+    pub sp: Option<Address>,
+
+    /// Where assembly sources loaded into the machine are assembled to, instead of
+    /// `ASSEMBLY_ORIGIN`
+    pub origin: Option<Address>,
+}
+
+/// A RAM or ROM block, optionally filled from an image file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryDescription {
+    pub start: Address,
+    pub length: Address,
+
+    #[serde(default)]
+    pub readonly: bool,
+
+    pub image: Option<String>,
+
+    /// How far into the block the image is loaded
+    #[serde(default)]
+    pub offset: Address,
+
+    /// For battery-backed RAM, the file its contents are kept in (relative to the
+    /// description), which once it exists is loaded instead of the image
+    pub battery: Option<String>,
+}
+
+/// A device and the address its registers start at
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum DeviceDescription {
+    Via { base: Address },
+    Acia { base: Address, serial: Option<String> },
+    Apple1Pia { base: Address, serial: Option<String> },
+}
+
+/// An address or length, given in a description as a number or a hex string
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(try_from = "Number")]
+pub struct Address(pub usize);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Int(u64),
+    Text(String),
+}
+
+impl TryFrom<Number> for Address {
+    type Error = String;
+
+    fn try_from(number: Number) -> Result<Address, String> {
+        match number {
+            Number::Int(value) => Ok(Address(value as usize)),
+            Number::Text(text) => {
+                let digits = match text.strip_prefix('$')
+                    .or_else(|| text.strip_prefix("0x"))
+                    .or_else(|| text.strip_prefix("0X")) {
+                    Some(digits) => digits,
+                    None => return Err(format!("{} should be a number, or hex starting with $ or 0x", text)),
+                };
+                usize::from_str_radix(digits, 16)
+                    .map(Address)
+                    .map_err(|_| format!("{} is not a hex number", text))
+            },
+        }
+    }
+}
+
+impl MachineDescription {
+    pub fn from_toml(text: &str) -> Result<MachineDescription, EmuError> {
+        toml::from_str(text).map_err(|error| EmuError::InvalidMachine(error.to_string()))
+    }
+
+    pub fn from_json(text: &str) -> Result<MachineDescription, EmuError> {
+        serde_json::from_str(text).map_err(|error| EmuError::InvalidMachine(error.to_string()))
+    }
+
+    /// Read a description, as JSON if the file ends in .json and TOML otherwise
+    pub fn load(path: &Path) -> Result<MachineDescription, EmuError> {
+        let text = read_file(path)?;
+        let text = String::from_utf8(text)
+            .map_err(|_| EmuError::InvalidMachine(format!("{} is not text", path.display())))?;
+        match path.extension() {
+            Some(extension) if extension == "json" => MachineDescription::from_json(&text),
+            _ => MachineDescription::from_toml(&text),
+        }
+    }
+
+    /// Put together the machine, loading images relative to `dir`
+    pub fn build(&self, dir: &Path) -> Result<CPU, EmuError> {
+        let mut mmu = MMU::new(&Vec::new())?;
+
+        for memory in &self.memory {
+            let (start, length, offset) = (memory.start.0, memory.length.0, memory.offset.0);
+            let mut contents = vec![0; offset];
+            if let Some(ref image) = memory.image {
+                contents.extend(read_file(&dir.join(image))?);
+                if contents.len() > length {
+                    return Err(EmuError::InvalidMachine(format!(
+                        "{} doesn't fit in the block at ${:04x}", image, start)));
+                }
+            }
+            let mut block = Block::new(start, length, memory.readonly, Some(contents))?;
+            if let Some(ref battery) = memory.battery {
+                block = block.with_battery(&dir.join(battery))?;
+            }
+            mmu.add_block(&block)?;
+        }
+
+        for device in &self.devices {
+            let (base, device): (usize, Box<dyn Device>) = match *device {
+                DeviceDescription::Via { base } => (base.0, Box::new(Via::new())),
+                DeviceDescription::Acia { base, ref serial } =>
+                    (base.0, Box::new(Acia::new(open_serial(serial)?))),
+                DeviceDescription::Apple1Pia { base, ref serial } =>
+                    (base.0, Box::new(Apple1Pia::new(open_serial(serial)?))),
+            };
+            mmu.add_device(base, device)?;
+        }
+
+        let mut cpu = CPU::new(mmu);
+        if let Some(sp) = self.sp {
+            cpu.r.s = (sp.0 & 0xFF) as u16;
+        }
+        cpu.r.pc = match self.pc {
+            Some(pc) => pc.0 as u16,
+            None => cpu.mmu.read_word(0xFFFC)?,
+        };
+        Ok(cpu)
+    }
+}
+
+// Load the machine described in `path`
+pub fn load_machine(path: &Path) -> Result<CPU, EmuError> {
+    let description = MachineDescription::load(path)?;
+    description.build(path.parent().unwrap_or(Path::new("")))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, EmuError> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|error| EmuError::InvalidMachine(format!("{}: {}", path.display(), error)))?;
+    Ok(data)
+}
+
+fn open_serial(spec: &Option<String>) -> Result<Box<dyn SerialPort>, EmuError> {
+    let spec = spec.as_deref().unwrap_or("stdio");
+    open_port(spec).map_err(|error| EmuError::InvalidMachine(format!("serial port {}: {}", spec, error)))
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    const DESCRIPTION: &str = r#"
+        sp = "$FD"
+
+        [[memory]]
+        start = 0
+        length = "$1000"
+
+        [[memory]]
+        start = "0xF000"
+        length = "$1000"
+        readonly = true
+        image = "rom.bin"
+        offset = "$FFC"
+
+        [[memory]]
+        start = "$4000"
+        length = 2
+        battery = "save.ram"
+
+        [[devices]]
+        type = "via"
+        base = "$6000"
+    "#;
+
+    #[test]
+    fn test_parse_toml() {
+        let description = MachineDescription::from_toml(DESCRIPTION).unwrap();
+        assert_eq!(description.memory.len(), 3);
+        assert_eq!(description.memory[2].battery, Some("save.ram".to_string()));
+        assert_eq!(description.memory[0].start, Address(0));
+        assert_eq!(description.memory[0].offset, Address(0));
+        assert!(description.memory[1].readonly);
+        assert_eq!(description.memory[1].start, Address(0xF000));
+        assert_eq!(description.memory[1].offset, Address(0xFFC));
+        assert_eq!(description.devices, vec![DeviceDescription::Via { base: Address(0x6000) }]);
+        assert_eq!(description.pc, None);
+        assert_eq!(description.sp, Some(Address(0xFD)));
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let description = MachineDescription::from_json(
+            r#"{"memory": [{"start": "$200", "length": 256}], "pc": 512, "origin": "$300"}"#).unwrap();
+        assert_eq!(description.memory[0].start, Address(0x200));
+        assert_eq!(description.memory[0].length, Address(0x100));
+        assert_eq!(description.pc, Some(Address(0x200)));
+        assert_eq!(description.origin, Some(Address(0x300)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(MachineDescription::from_toml("pc = \"1234\"").is_err());
+        assert!(MachineDescription::from_toml("pc = \"$12G4\"").is_err());
+        assert!(MachineDescription::from_toml("[[devices]]\ntype = \"sid\"\nbase = 0").is_err());
+        assert!(MachineDescription::from_toml("[[memory]]\nstart = 0\nlenght = 1").is_err());
+    }
+
+    #[test]
+    fn test_load_machine() {
+        let dir = env::temp_dir().join(format!("machine_test_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // the reset vector, and the IRQ vector
+        fs::write(dir.join("rom.bin"), [0x00, 0x02, 0x34, 0x12]).unwrap();
+        fs::write(dir.join("machine.toml"), DESCRIPTION).unwrap();
+
+        let mut cpu = load_machine(&dir.join("machine.toml")).unwrap();
+        assert_eq!(cpu.r.pc, 0x0200);
+        assert_eq!(cpu.r.s, 0xFD);
+        assert_eq!(cpu.mmu.read_word(0xFFFE), Ok(0x1234));
+        assert_eq!(cpu.mmu.write(0xF000, 0), Err(EmuError::RomWrite(0xF000)));
+        assert_eq!(cpu.mmu.devices[0].start, 0x6000);
+
+        // battery-backed RAM comes back from its file
+        cpu.mmu.write(0x4001, 0x55).unwrap();
+        cpu.mmu.flush().unwrap();
+        let mut cpu = load_machine(&dir.join("machine.toml")).unwrap();
+        assert_eq!(cpu.mmu.read(0x4001), Ok(0x55));
+
+        // an image past the end of its block
+        fs::write(dir.join("rom.bin"), [0; 5]).unwrap();
+        assert!(load_machine(&dir.join("machine.toml")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(load_machine(&dir.join("machine.toml")).is_err());
+    }
+}
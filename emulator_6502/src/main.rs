@@ -1,46 +1,358 @@
+extern crate cpu_emulator;
+#[cfg(unix)]
+extern crate libc;
 
-mod cpu;
-mod mmu;
-mod registers;
-mod ops;
 mod snake;
 
+use cpu_emulator::{cpu, error, coverage, gdb, serial, acia, apple1, machine, loader, single_step, bench, clock};
+
 use std::env;
 use std::io;
 use std::io::prelude::*;
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::Path;
+use std::net::TcpListener;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use ops::make_op_table;
-use cpu::make_cpu;
+use cpu::{make_cpu, StopReason, CPU};
 use snake::play_snake;
+use coverage::DebugMap;
+use serial::open_port;
+use acia::Acia;
+use apple1::run_apple1;
+use machine::MachineDescription;
+use loader::{detect_format, make_ram_cpu, Image, ASSEMBLY_ORIGIN};
+use error::EmuError;
+use clock::{Clock, Speed};
+
+// how many cycles the cpu runs between checks for a ctrl-c
+const SLICE_CYCLES: u32 = 1_000_000;
+
+// Set by the first ctrl-c once the cpu is running, so it stops like it would for any other
+// reason and battery-backed RAM still gets saved
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 fn main() {
 
     // read rom from file
     let args: Vec<String> = env::args().collect();
-    let rom_file_path = args.get(1).expect("usage: $ cargo run <rom_file.bin>");
+    let machine_path = flag_value(&args, "--machine");
+    let rom_file_path = match machine_path {
+        Some(ref path) => path,
+        None => args.get(1).expect("usage: $ cargo run <rom_file.bin> or $ cargo run --machine <machine.toml>"),
+    };
 
     if (rom_file_path == "snake") {
-        play_snake();
+        play_snake(flag_value(&args, "--speed").and_then(|speed| Speed::parse(&speed)));
+    }
+
+    // `apple1 <monitor rom> [file@address...]` runs an Apple-1 on this terminal
+    if rom_file_path == "apple1" {
+        let monitor_path = args.get(2).expect("usage: $ cargo run apple1 <monitor_rom.bin> [file@address...]");
+        if let Err(message) = run_apple1(monitor_path, &args[3..]) {
+            exit_with_error(message);
+        }
+        return;
+    }
+
+    // `bench [instructions]` times the interpreter with and without the decode cache
+    if rom_file_path == "bench" {
+        let instructions = args.get(2)
+            .map(|count| count.parse::<u64>().expect("usage: $ cargo run --release bench [instructions]"))
+            .unwrap_or(10_000_000);
+        bench::run_benchmarks("snake.bin", "nestest_mod.nes", instructions);
+        return;
     }
 
-    let mut rom_file = File::open(rom_file_path).unwrap();
+    // `single-step <dir or file.json>` runs the per-opcode JSON test vectors instead
+    if rom_file_path == "single-step" {
+        let tests_path = args.get(2).expect("usage: $ cargo run single-step <tests_dir>");
+        let (passed, failed) = match single_step::run_suite(Path::new(tests_path)) {
+            Ok(totals) => totals,
+            Err(error) => exit_with_error(format!("could not run the tests in {}: {}", tests_path, error)),
+        };
+        println!("{} passed, {} failed", passed, failed);
+        if failed > 0 {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // optional flags: `--cycles <n>` stops after n cycles, `--trace` prints every
+    // instruction, and `--profile <name>` writes <name>.txt and <name>.folded once the
+    // cpu stops
+    let max_cycles = flag_value(&args, "--cycles")
+        .map(|cycles| cycles.parse::<u32>().expect("--cycles takes a number"));
+    let profile_name = flag_value(&args, "--profile");
 
-    let mut buffer = Vec::new();
-    rom_file.read_to_end(&mut buffer).unwrap();
+    // `--coverage <file.map>` takes the assembler's debug map and writes lcov coverage
+    // to <rom_file>.info
+    let coverage_map = flag_value(&args, "--coverage");
 
-    // init CPU and ops
-    let ops = make_op_table();
-    let mut cpu = make_cpu(Some(buffer));
+    // `--sanitize` reports reads of uninitialized memory and stack misuse once the cpu stops
+    let sanitize = args.iter().any(|arg| arg == "--sanitize");
+
+    // init CPU
+    let loaded = load_program(rom_file_path, machine_path.as_ref(), flag_value(&args, "--load"), sanitize);
+    let mut cpu = match loaded {
+        Ok(cpu) => cpu,
+        Err(error) => exit_with_error(format!("could not load {}: {}", rom_file_path, error)),
+    };
+    cpu.trace = args.iter().any(|arg| arg == "--trace");
+    // `--stop-on-brk` stops before a BRK, for programs that end with one rather than having
+    // an interrupt handler
+    cpu.stop_on_brk = args.iter().any(|arg| arg == "--stop-on-brk");
+
+    // `--symbols <file>` names addresses in the trace, profile and breakpoints, and
+    // `--break <name or hex address>,...` stops there
+    if let Some(symbol_file_path) = flag_value(&args, "--symbols") {
+        match fs::read_to_string(&symbol_file_path) {
+            Ok(symbol_text) => cpu.symbols.read(&symbol_text),
+            Err(error) => exit_with_error(format!("could not read {}: {}", symbol_file_path, error)),
+        }
+    }
+    if let Some(breaks) = flag_value(&args, "--break") {
+        for name in breaks.split(',') {
+            match cpu.symbols.lookup(name).or_else(|| parse_address(name).map(|addr| addr as u16)) {
+                Some(addr) => {
+                    cpu.breakpoints.insert(addr);
+                },
+                None => exit_with_error(format!("{} is not a symbol or an address", name)),
+            }
+        }
+    }
+    if profile_name.is_some() {
+        cpu.enable_profiler();
+    }
+    if coverage_map.is_some() {
+        cpu.enable_coverage();
+    }
+    // `--decode-cache` keeps instructions decoded between runs of them, which is faster
+    if args.iter().any(|arg| arg == "--decode-cache") {
+        cpu.enable_decode_cache();
+    }
+
+    // `--acia <addr>` maps a 6551 serial port there, connected to `--serial <port>` which is
+    // one of stdio (the default), pty or tcp:<port number>
+    if let Some(addr) = flag_value(&args, "--acia") {
+        let addr = parse_address(&addr).expect("--acia takes a hex address");
+        let spec = flag_value(&args, "--serial").unwrap_or("stdio".to_string());
+        let port = match open_port(&spec) {
+            Ok(port) => port,
+            Err(error) => exit_with_error(format!("could not open serial port {}: {}", spec, error)),
+        };
+        if let Err(error) = cpu.mmu.add_device(addr, Box::new(Acia::new(port))) {
+            exit_with_error(format!("could not map the ACIA: {}", error));
+        }
+    }
+
+    // `--gdb <port>` hands the cpu over to a debugger instead of running it
+    if let Some(port) = flag_value(&args, "--gdb") {
+        let listener = match TcpListener::bind(format!("127.0.0.1:{}", port)) {
+            Ok(listener) => listener,
+            Err(error) => exit_with_error(format!("could not listen on port {}: {}", port, error)),
+        };
+        println!("Waiting for gdb on port {}...", port);
+        let served = gdb::serve(&mut cpu, listener);
+        if let Err(error) = cpu.mmu.flush() {
+            eprintln!("Error, {}", error);
+        }
+        if let Err(error) = served {
+            exit_with_error(format!("lost the connection to gdb: {}", error));
+        }
+        return;
+    }
+
+    // `--speed <MHz, ntsc or unlimited>` paces the cpu to that clock a frame at a time,
+    // instead of running it flat out
+    let mut clock = match flag_value(&args, "--speed") {
+        Some(speed) => match Speed::parse(&speed) {
+            Some(speed) => Some(Clock::new(speed)),
+            None => exit_with_error("--speed takes a speed in MHz, ntsc or unlimited".to_string()),
+        },
+        None => None,
+    };
+    if let Some(ref clock) = clock {
+        if clock.speed != Speed::Unlimited {
+            cpu.frame_cycles = clock.frame_cycles();
+        }
+    }
 
     // run program
     println!("Program initialized, starting cpu...");
 
-    while(true) {
-        cpu.step(ops);
+    // without a limit keep going until the program stops itself, or a ctrl-c stops it
+    catch_ctrl_c();
+    let start = cpu.r.cc;
+    let mut frame_start = cpu.r.cc;
+    loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            println!("cpu stopped: interrupted");
+            break;
+        }
+        let remaining = max_cycles.map_or(u32::MAX, |max| max.saturating_sub(cpu.r.cc.wrapping_sub(start)));
+        let budget = remaining.min(SLICE_CYCLES);
+        let result = if budget == 0 { Ok(StopReason::Budget) } else { cpu.run(budget) };
+        if let Some(ref mut clock) = clock {
+            clock.ran(cpu.r.cc.wrapping_sub(frame_start));
+            frame_start = cpu.r.cc;
+        }
+        if let Some(error) = cpu.mmu.battery_error.take() {
+            eprintln!("Error, {}", error);
+        }
+        match result {
+            Ok(StopReason::Frame) => {
+                if let Some(ref mut clock) = clock {
+                    clock.sync();
+                }
+            },
+            Ok(StopReason::Budget) if budget < remaining => continue,
+            Ok(reason) => {
+                println!("cpu stopped: {}", reason);
+                if let Some(name) = cpu.symbols.name(cpu.r.pc) {
+                    println!("pc is at {}", name);
+                }
+                break;
+            },
+            Err(error) => {
+                println!("Error, cpu stopped: {}", error);
+                break;
+            },
+        }
+    }
+    println!("cpu: {:?}", cpu.r);
+    if let Err(error) = cpu.mmu.flush() {
+        eprintln!("Error, {}", error);
+    }
+    if let Some(ref clock) = clock {
+        println!("ran at {:.3} MHz ({})", clock.average_mhz(), clock.speed);
+    }
+
+    if let Some(ref sanitizer) = cpu.sanitizer {
+        for report in &sanitizer.reports {
+            println!("sanitizer: {}: {}", cpu.symbols.describe(report.pc), report.issue);
+        }
+    }
 
-        // print cpu state for debugging
-        println!("opcode: {:x}", cpu.mmu.read(cpu.r.pc as usize));
-        println!("cpu: {:?}", cpu.r)
+    if let (Some(name), Some(profiler)) = (profile_name, cpu.profiler.as_ref()) {
+        write_report(&format!("{}.txt", name), &profiler.report(cpu.mmu.heat.as_ref(), &cpu.symbols));
+        write_report(&format!("{}.folded", name), &profiler.folded(&cpu.symbols));
     }
+
+    if let (Some(map_file_path), Some(coverage)) = (coverage_map, cpu.coverage.as_ref()) {
+        let map_text = match fs::read_to_string(&map_file_path) {
+            Ok(map_text) => map_text,
+            Err(error) => exit_with_error(format!("could not read {}: {}", map_file_path, error)),
+        };
+        let map = match DebugMap::parse(&map_text) {
+            Ok(map) => map,
+            Err(error) => exit_with_error(format!("could not read {}: {}", map_file_path, error)),
+        };
+
+        write_report(&format!("{}.info", rom_file_path), &coverage.lcov(&map, &cpu.mmu));
+    }
+}
+
+// Init the cpu, either the one described by `machine_path` (with the `load_path` image put
+// into it), or the default machine with the rom loaded into it. Intel HEX, S-record and PRG
+// images say where they go, so they get all 64K as RAM instead, as do assembly sources
+// (assembled to the machine's `origin`, or $0600). With `sanitize` the
+// sanitizer is started before any image goes into RAM, so the image counts as initialized.
+fn load_program(rom_file_path: &str, machine_path: Option<&String>, load_path: Option<String>,
+                sanitize: bool) -> Result<CPU, EmuError> {
+    let (mut cpu, image) = match machine_path {
+        Some(path) => {
+            let path = Path::new(path);
+            let description = MachineDescription::load(path)?;
+            let origin = description.origin.map_or(ASSEMBLY_ORIGIN, |origin| origin.0 as u16);
+            let image = match load_path {
+                Some(image_path) => {
+                    let data = read_image(&image_path)?;
+                    let format = detect_format(Path::new(&image_path), &data)
+                        .ok_or_else(|| EmuError::InvalidImage(format!("{} isn't HEX, S-record, PRG or assembly", image_path)))?;
+                    Some(Image::parse(format, &image_path, &data, origin)?)
+                },
+                None => None,
+            };
+            (description.build(path.parent().unwrap_or(Path::new("")))?, image)
+        },
+        None => {
+            let buffer = read_image(rom_file_path)?;
+            match detect_format(Path::new(rom_file_path), &buffer) {
+                Some(format) => {
+                    let mut image = Image::parse(format, rom_file_path, &buffer, ASSEMBLY_ORIGIN)?;
+                    // without a start record, start at the first byte loaded
+                    if image.entry.is_none() {
+                        image.entry = image.segments.first().map(|segment| segment.start as u16);
+                    }
+                    (make_ram_cpu()?, Some(image))
+                },
+                None => (make_cpu(Some(buffer))?, None),
+            }
+        },
+    };
+
+    if sanitize {
+        cpu.enable_sanitizer();
+    }
+    if let Some(image) = image {
+        image.load(&mut cpu)?;
+    }
+    Ok(cpu)
+}
+
+fn read_image(path: &str) -> Result<Vec<u8>, EmuError> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|error| EmuError::InvalidImage(format!("{}: {}", path, error)))?;
+    Ok(data)
+}
+
+#[cfg(unix)]
+extern "C" fn interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // a second ctrl-c kills the emulator as usual, in case it's stuck
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+// Have a ctrl-c set INTERRUPTED instead of killing the emulator
+#[cfg(unix)]
+fn catch_ctrl_c() {
+    unsafe {
+        libc::signal(libc::SIGINT, interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_ctrl_c() {}
+
+// Report an error the emulator can't carry on from, and exit with a failure status
+fn exit_with_error(message: String) -> ! {
+    eprintln!("Error, {}", message);
+    process::exit(1);
+}
+
+// Write one of the reports (profile, flamegraph, lcov) given on the command line
+fn write_report(path: &str, report: &str) {
+    if let Err(error) = fs::write(path, report) {
+        exit_with_error(format!("could not write {}: {}", path, error));
+    }
+}
+
+// the value following a `--flag` on the command line, if the flag was given
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+// an address in hex, with or without a leading $ or 0x
+fn parse_address(text: &str) -> Option<usize> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    usize::from_str_radix(digits, 16).ok()
 }
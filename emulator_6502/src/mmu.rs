@@ -1,3 +1,11 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use profiler::PageHeat;
+use decode_cache::CodePages;
+use device::{Device, MappedDevice};
+use error::EmuError;
 
 /// This represents one block of memory
 #[derive(Clone, Debug)]
@@ -14,21 +22,39 @@ pub struct Block {
 
     /// The memory is stored as a vector of bytes
     pub memory: Vec<u8>,
+
+    /// Where battery-backed RAM is kept between runs, see `with_battery`
+    pub battery: Option<Battery>,
+}
+
+/// The host file behind a block of battery-backed RAM
+#[derive(Clone, Debug, PartialEq)]
+pub struct Battery {
+    pub path: PathBuf,
+
+    /// Written to since it was last saved
+    pub dirty: bool,
 }
 
+/// How often the MMU saves battery-backed RAM that has changed, about a second at 1 MHz
+pub const BATTERY_FLUSH_CYCLES: u32 = 1_000_000;
+
 impl Block {
     /// The constructor allocates and initializes an empy block of the given specifications.
     /// If you want to use an existing block. IE for loading a ROM, then that can be optionally
     /// supplied, and it will be checked for proper sizing.
-    pub fn new(start: usize, length: usize, readonly: bool, memory: Option<Vec<u8>>) -> Block {
+    pub fn new(start: usize, length: usize, readonly: bool, memory: Option<Vec<u8>>) -> Result<Block, EmuError> {
 
         if length == 0 {
-            panic!("Error, tried to initialize illegal memory block.");
+            return Err(EmuError::InvalidBlock { start: start, length: length });
         }
         // check memory is exists and/or is right size
         let new_memory: Vec<u8> = match memory {
             Some(mut existing_memory) => {
                 let existing_len = existing_memory.len();
+                if existing_len > length {
+                    return Err(EmuError::InvalidBlock { start: start, length: length });
+                }
                 existing_memory.append(&mut vec![0; (length - existing_len)]);
                 existing_memory
             },
@@ -38,12 +64,36 @@ impl Block {
         };
 
         // return block
-        Block {
+        Ok(Block {
             start: start,
             length: length,
             readonly: readonly,
             memory: new_memory,
+            battery: None,
+        })
+    }
+
+    /// Make this block battery-backed RAM kept in `path`. Whatever the file holds is loaded
+    /// over the block's contents, if it exists yet; otherwise it's created on the first flush.
+    pub fn with_battery(mut self, path: &Path) -> Result<Block, EmuError> {
+        if self.readonly {
+            return Err(EmuError::BatteryRam(format!("{} is for a readonly block", path.display())));
+        }
+        match fs::read(path) {
+            Ok(saved) => {
+                if saved.len() > self.length {
+                    return Err(EmuError::BatteryRam(format!("{} is bigger than the block", path.display())));
+                }
+                self.memory[..saved.len()].copy_from_slice(&saved);
+            },
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {},
+            Err(error) => return Err(EmuError::BatteryRam(format!("{}: {}", path.display(), error))),
         }
+        self.battery = Some(Battery {
+            path: path.to_path_buf(),
+            dirty: false,
+        });
+        Ok(self)
     }
 }
 
@@ -62,19 +112,40 @@ impl Block {
 #[derive(Debug)]
 pub struct MMU {
     pub blocks: Vec<Block>,
+
+    /// Memory mapped hardware, see `add_device`
+    pub devices: Vec<MappedDevice>,
+
+    /// Per page access counts, only kept while the profiler is enabled
+    pub heat: Option<PageHeat>,
+
+    /// Pages holding decoded instructions, only kept while the decode cache is enabled
+    pub code_pages: Option<CodePages>,
+
+    /// Why saving battery-backed RAM failed the last time `tick` tried, for the host to report.
+    /// The blocks stay dirty, so the next save tries them again.
+    pub battery_error: Option<EmuError>,
+
+    // cycles since battery-backed RAM was last saved
+    battery_cycles: u32,
 }
 
 impl MMU {
-    pub fn new(blocks: &Vec<Block>) -> MMU {
+    pub fn new(blocks: &Vec<Block>) -> Result<MMU, EmuError> {
 
         let mut mmu = MMU {
             blocks: Vec::new(),
+            devices: Vec::new(),
+            heat: None,
+            code_pages: None,
+            battery_error: None,
+            battery_cycles: 0,
         };
 
         for block in blocks {
-            mmu.add_block(block);
+            mmu.add_block(block)?;
         }
-        mmu
+        Ok(mmu)
     }
 
     fn reset(&mut self) {
@@ -88,7 +159,7 @@ impl MMU {
 
     }
 
-    pub fn add_block(&mut self, new_block: &Block) {
+    pub fn add_block(&mut self, new_block: &Block) -> Result<(), EmuError> {
         // check if new block overlaps with existing blocks
         for block in &self.blocks {
             let new_end_intersects = 
@@ -100,50 +171,164 @@ impl MMU {
                 block.start+block.length < new_block.start + new_block.length;
 
             if new_end_intersects || new_start_intersects {
-                panic!("Error, add memory overlap error.")
+                return Err(EmuError::OverlappingBlock { start: new_block.start, length: new_block.length });
             }
         }
+        if self.device_overlaps(new_block.start, new_block.length) {
+            return Err(EmuError::OverlappingBlock { start: new_block.start, length: new_block.length });
+        }
         
         if new_block.length == 0 || new_block.memory.len() != new_block.length {
-            panic!("Error, tried to initialize illegal memory block.");
+            return Err(EmuError::InvalidBlock { start: new_block.start, length: new_block.length });
         }
         self.blocks.push(new_block.clone());
+        Ok(())
+    }
+
+    /// Map a device at `start`, taking up `device.length()` addresses. Reads and writes
+    /// there go to the device rather than memory.
+    pub fn add_device(&mut self, start: usize, device: Box<dyn Device>) -> Result<(), EmuError> {
+        let length = device.length();
+        let overlaps_block = self.blocks.iter()
+            .any(|block| start < block.start + block.length && block.start < start + length);
+
+        if overlaps_block || self.device_overlaps(start, length) {
+            return Err(EmuError::OverlappingBlock { start: start, length: length });
+        }
+        self.devices.push(MappedDevice {
+            start: start,
+            length: length,
+            device: device,
+        });
+        Ok(())
+    }
+
+    fn device_overlaps(&self, start: usize, length: usize) -> bool {
+        self.devices.iter()
+            .any(|mapped| start < mapped.start + mapped.length && mapped.start < start + length)
+    }
+
+    fn get_device(&mut self, addr: usize) -> Option<&mut MappedDevice> {
+        self.devices.iter_mut().find(|mapped| addr >= mapped.start && addr < mapped.start + mapped.length)
+    }
+
+    /// Pass the cycles taken by an instruction on to every device, and save battery-backed
+    /// RAM every `BATTERY_FLUSH_CYCLES`
+    pub fn tick(&mut self, cycles: u32) {
+        for mapped in &mut self.devices {
+            mapped.device.tick(cycles);
+        }
+
+        self.battery_cycles += cycles;
+        if self.battery_cycles >= BATTERY_FLUSH_CYCLES {
+            self.battery_cycles = 0;
+            // the program can't do anything about it, so keep it for the host
+            if let Err(error) = self.flush() {
+                self.battery_error = Some(error);
+            }
+        }
+    }
+
+    /// Save every battery-backed block that has changed to its file. Hosts call this on a
+    /// clean shutdown, on top of the saves made as the cpu runs.
+    pub fn flush(&mut self) -> Result<(), EmuError> {
+        for block in &mut self.blocks {
+            if let Some(ref mut battery) = block.battery {
+                if battery.dirty {
+                    fs::write(&battery.path, &block.memory)
+                        .map_err(|error| EmuError::BatteryRam(format!("{}: {}", battery.path.display(), error)))?;
+                    battery.dirty = false;
+                }
+            }
+        }
+        Ok(())
+    }
 
+    /// If any device is asserting IRQ
+    pub fn irq(&self) -> bool {
+        self.devices.iter().any(|mapped| mapped.device.irq())
     }
 
-    // note this returns a copy of the Block
-    fn get_block(&mut self, addr: usize) -> &mut Block {
-        let mut index = 0;
+    fn get_block(&mut self, addr: usize) -> Result<&mut Block, EmuError> {
         for block in &mut self.blocks {
             if addr >= block.start && addr < block.start + block.length {
-                return block;
+                return Ok(block);
             }
-            index += 1;
         }
-        panic!("Error, block not found.");
+        Err(EmuError::UnmappedAddress(addr))
+    }
+
+    // Like get_block, for callers (like the debugger) that need to check an address before
+    // touching it.
+    pub fn block_at(&self, addr: usize) -> Option<&Block> {
+        self.blocks.iter().find(|block| addr >= block.start && addr < block.start + block.length)
     }
 
-    pub fn write(&mut self, addr: usize, value: u8) {
-        let block = self.get_block(addr);
+    // Read a byte without side effects or counting it as an access, for looking ahead at the
+    // next opcode or for the debugger. Devices can't be read without side effects, so they
+    // give None like unmapped memory.
+    pub fn peek(&self, addr: usize) -> Option<u8> {
+        self.block_at(addr).map(|block| block.memory[addr - block.start])
+    }
+
+    /// Copy `data` into memory from `addr`, the way a ROM gets programmed: readonly blocks are
+    /// written too, and nothing is counted as an access. It all has to land in blocks.
+    pub fn load(&mut self, addr: usize, data: &[u8]) -> Result<(), EmuError> {
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(ref mut code_pages) = self.code_pages {
+                code_pages.touch(addr + i);
+            }
+            let block = self.get_block(addr + i)?;
+            let block_start = block.start;
+            block.memory[addr + i - block_start] = *byte;
+            if let Some(ref mut battery) = block.battery {
+                battery.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, addr: usize, value: u8) -> Result<(), EmuError> {
+        if let Some(ref mut heat) = self.heat {
+            heat.writes[(addr >> 8) & 0xFF] += 1;
+        }
+        if let Some(ref mut code_pages) = self.code_pages {
+            code_pages.touch(addr);
+        }
+        if let Some(mapped) = self.get_device(addr) {
+            mapped.device.write(addr - mapped.start, value);
+            return Ok(());
+        }
+        let block = self.get_block(addr)?;
         // let block = &mut self.blocks[block_number];
 
         // check if block is writable
         if block.readonly   {
-            panic!("Error, attempted to write to readonly memory.")
+            return Err(EmuError::RomWrite(addr));
         }
         let block_start = block.start;
         block.memory[addr - block_start] = value;
+        if let Some(ref mut battery) = block.battery {
+            battery.dirty = true;
+        }
+        Ok(())
     }
 
-    pub fn read(&mut self, addr: usize) -> u8 {
-        let block = self.get_block(addr);
+    pub fn read(&mut self, addr: usize) -> Result<u8, EmuError> {
+        if let Some(ref mut heat) = self.heat {
+            heat.reads[(addr >> 8) & 0xFF] += 1;
+        }
+        if let Some(mapped) = self.get_device(addr) {
+            return Ok(mapped.device.read(addr - mapped.start));
+        }
+        let block = self.get_block(addr)?;
         // let block = &self.blocks[block_number];
         let index = addr - block.start;
-        block.memory[index]
+        Ok(block.memory[index])
     }
 
-    pub fn read_word(&mut self, addr: usize) -> u16 {
-        ((self.read(addr+1) as u16) << 8) + (self.read(addr) as u16)
+    pub fn read_word(&mut self, addr: usize) -> Result<u16, EmuError> {
+        Ok(((self.read(addr+1)? as u16) << 8) + (self.read(addr)? as u16))
     }
 }
 
@@ -154,16 +339,16 @@ mod tests {
 
     #[test]
     fn test_create_empty() {
-        let mmu = MMU::new(&Vec::new());
+        MMU::new(&Vec::new()).unwrap();
     }
 
     #[test]
     fn test_create() {
         let mut blocks = Vec::new();
-        blocks.push(Block::new(0, 128, false, None));
-        let mut mmu = MMU::new(&blocks);
-        blocks.push(Block::new(128, 256, false, None));
-        mmu = MMU::new(&blocks);
+        blocks.push(Block::new(0, 128, false, None).unwrap());
+        MMU::new(&blocks).unwrap();
+        blocks.push(Block::new(128, 256, false, None).unwrap());
+        MMU::new(&blocks).unwrap();
     }
 
     #[test]
@@ -173,8 +358,8 @@ mod tests {
         memory[0] = 1;
         memory[1] = 2;
         memory[2] = 3;
-        blocks.push(Block::new(0, 128, false, Some(memory)));
-        let mmu = MMU::new(&blocks);
+        blocks.push(Block::new(0, 128, false, Some(memory)).unwrap());
+        let mmu = MMU::new(&blocks).unwrap();
 
         assert_eq!(mmu.blocks[0].memory[0], 1);
         assert_eq!(mmu.blocks[0].memory[1], 2);
@@ -182,86 +367,192 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_create_overlapping() {
         let mut blocks = Vec::new();
-        blocks.push(Block::new(0, 129, false, None));
+        blocks.push(Block::new(0, 129, false, None).unwrap());
         let mut mmu = MMU::new(&blocks);
-        blocks.push(Block::new(128, 256, false, None));
+        blocks.push(Block::new(128, 256, false, None).unwrap());
         mmu = MMU::new(&blocks);
+        assert_eq!(mmu.unwrap_err(), EmuError::OverlappingBlock { start: 128, length: 256 });
+    }
+
+    #[test]
+    fn test_create_illegal_block() {
+        assert_eq!(Block::new(16, 0, false, None).unwrap_err(),
+                   EmuError::InvalidBlock { start: 16, length: 0 });
+        assert_eq!(Block::new(16, 2, true, Some(vec![1, 2, 3])).unwrap_err(),
+                   EmuError::InvalidBlock { start: 16, length: 2 });
     }
 
     #[test]
     fn test_add_block() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, false, None));
-        mmu.add_block(&Block::new(128, 128, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
+        mmu.add_block(&Block::new(128, 128, false, None).unwrap()).unwrap();
     }
 
     #[test]
-    #[should_panic]
     fn test_add_block_overlapping() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 129, false, None));
-        mmu.add_block(&Block::new(128, 256, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 129, false, None).unwrap()).unwrap();
+        assert!(mmu.add_block(&Block::new(128, 256, false, None).unwrap()).is_err());
     }
 
     #[test]
     fn test_write() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
 
-        mmu.write(16, 25);
+        mmu.write(16, 25).unwrap();
         assert_eq!(mmu.blocks[0].memory[16], 25);
     }
 
     #[test]
     fn test_write_multiple_blocks() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, false, None));
-        mmu.add_block(&Block::new(128, 128, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
+        mmu.add_block(&Block::new(128, 128, false, None).unwrap()).unwrap();
 
-        mmu.write(16, 25);
-        mmu.write(130, 14);
+        mmu.write(16, 25).unwrap();
+        mmu.write(130, 14).unwrap();
         assert_eq!(mmu.blocks[0].memory[16], 25);
         assert_eq!(mmu.blocks[1].memory[2], 14);
     }
 
     #[test]
-    #[should_panic]
     fn test_write_readonly() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, true, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, true, None).unwrap()).unwrap();
 
-        mmu.write(16, 25);
+        assert_eq!(mmu.write(16, 25), Err(EmuError::RomWrite(16)));
+    }
+
+    #[test]
+    fn test_load() {
+        let mut mmu = MMU::new(&vec![
+            Block::new(0, 4, false, None).unwrap(),
+            Block::new(4, 4, true, None).unwrap(),
+        ]).unwrap();
+
+        mmu.load(2, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(mmu.blocks[0].memory, vec![0, 0, 1, 2]);
+        assert_eq!(mmu.blocks[1].memory, vec![3, 4, 0, 0]);
+        assert_eq!(mmu.load(6, &[5, 6, 7]), Err(EmuError::UnmappedAddress(8)));
+    }
+
+    #[test]
+    fn test_battery() {
+        let path = ::std::env::temp_dir().join(format!("battery_test_{}.sav", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // no file yet, so it starts out with the initial contents
+        let block = Block::new(0x6000, 4, false, Some(vec![9])).unwrap().with_battery(&path).unwrap();
+        let mut mmu = MMU::new(&vec![block]).unwrap();
+        assert_eq!(mmu.read(0x6000), Ok(9));
+        mmu.flush().unwrap();
+        assert!(!path.exists());
+
+        // saved once enough cycles have gone by
+        mmu.write(0x6001, 0x42).unwrap();
+        mmu.tick(BATTERY_FLUSH_CYCLES - 1);
+        assert!(!path.exists());
+        mmu.tick(1);
+        assert_eq!(fs::read(&path).unwrap(), vec![9, 0x42, 0, 0]);
+
+        // and on a flush
+        mmu.write(0x6003, 0x43).unwrap();
+        mmu.flush().unwrap();
+        let block = Block::new(0x6000, 4, false, None).unwrap().with_battery(&path).unwrap();
+        assert_eq!(block.memory, vec![9, 0x42, 0, 0x43]);
+
+        assert!(Block::new(0x6000, 2, false, None).unwrap().with_battery(&path).is_err());
+        assert!(Block::new(0x6000, 4, true, None).unwrap().with_battery(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        // a save that fails is kept for the host, rather than stopping the cpu
+        let missing = path.join("missing.sav");
+        let block = Block::new(0x6000, 4, false, None).unwrap().with_battery(&missing).unwrap();
+        let mut mmu = MMU::new(&vec![block]).unwrap();
+        mmu.write(0x6000, 1).unwrap();
+        mmu.tick(BATTERY_FLUSH_CYCLES);
+        assert!(mmu.battery_error.take().is_some());
+        assert!(mmu.flush().is_err());
     }
 
     #[test]
     fn test_read() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
 
-        mmu.write(16, 25);
-        assert_eq!(mmu.read(16), 25);
+        mmu.write(16, 25).unwrap();
+        assert_eq!(mmu.read(16), Ok(25));
     }
 
     #[test]
-    #[should_panic]
     fn test_index_error() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
 
-        mmu.write(128, 25);
+        assert_eq!(mmu.write(128, 25), Err(EmuError::UnmappedAddress(128)));
+        assert_eq!(mmu.read(128), Err(EmuError::UnmappedAddress(128)));
     }
 
     // test_reset
     #[test]
     fn test_reset() {
-        let mut mmu = MMU::new(&Vec::new());
-        mmu.add_block(&Block::new(0, 128, false, None));
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
 
-        mmu.write(16, 25);
+        mmu.write(16, 25).unwrap();
         mmu.reset();
-        assert_eq!(mmu.read(16), 0);
+        assert_eq!(mmu.read(16), Ok(0));
+    }
+
+    // a device that counts its reads, to check accesses reach it
+    #[derive(Debug)]
+    struct Counter {
+        reads: u8,
+        last_write: (usize, u8),
+    }
+
+    impl Device for Counter {
+        fn length(&self) -> usize {
+            4
+        }
+
+        fn read(&mut self, _reg: usize) -> u8 {
+            self.reads += 1;
+            self.reads
+        }
+
+        fn write(&mut self, reg: usize, val: u8) {
+            self.last_write = (reg, val);
+        }
+    }
+
+    #[test]
+    fn test_add_device() {
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
+        mmu.add_device(0x80, Box::new(Counter { reads: 0, last_write: (0, 0) })).unwrap();
+
+        assert_eq!(mmu.read(0x81), Ok(1));
+        assert_eq!(mmu.read(0x83), Ok(2));
+        mmu.write(0x82, 7).unwrap();
+        assert_eq!(mmu.read(0x84), Err(EmuError::UnmappedAddress(0x84)));
+        assert_eq!(mmu.peek(0x81), None);
+        assert_eq!(format!("{:?}", mmu.devices[0].device), "Counter { reads: 2, last_write: (2, 7) }");
+    }
+
+    #[test]
+    fn test_add_device_overlapping() {
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        mmu.add_block(&Block::new(0, 128, false, None).unwrap()).unwrap();
+
+        assert_eq!(mmu.add_device(0x7E, Box::new(Counter { reads: 0, last_write: (0, 0) })),
+                   Err(EmuError::OverlappingBlock { start: 0x7E, length: 4 }));
+        mmu.add_device(0x80, Box::new(Counter { reads: 0, last_write: (0, 0) })).unwrap();
+        assert_eq!(mmu.add_block(&Block::new(0x82, 16, false, None).unwrap()),
+                   Err(EmuError::OverlappingBlock { start: 0x82, length: 16 }));
     }
 }
\ No newline at end of file
@@ -1,5 +1,8 @@
+use std::sync::OnceLock;
+
 use mmu::{Block};
 use cpu::{Instr, CPU, make_cpu};
+use error::EmuError;
 
 
 // build op table
@@ -19,6 +22,29 @@ fn no_arg(cpu: &mut CPU) -> u16 {
     0
 }
 
+// base cycle count of each opcode, indexed the same way as the op table. The extra cycles
+// for crossing a page boundary or taking a branch are added by the addressing modes and
+// the branch ops as they run.
+pub const CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
+
 
 pub fn make_op_table() -> [Instr; 256] {
     let mut ops = [Instr::new(CPU::im, op_not_implemented); 256];
@@ -121,9 +147,9 @@ pub fn make_op_table() -> [Instr; 256] {
     ops[0xEE] = Instr::new(CPU::a_a,  op_inc);
     ops[0xFE] = Instr::new(CPU::ax_a,  op_inc);
 
-    ops[0xE8] = Instr::new(CPU::im,  op_inx);
+    ops[0xE8] = Instr::new(no_arg,  op_inx);
 
-    ops[0xC8] = Instr::new(CPU::im,  op_iny);
+    ops[0xC8] = Instr::new(no_arg,  op_iny);
 
     //jmp
     ops[0x4C] = Instr::new(CPU::a_a,  op_jmp);
@@ -163,12 +189,12 @@ pub fn make_op_table() -> [Instr; 256] {
 
     //nop
     ops[0x1A] = Instr::new(no_arg,  op_nop);
-    ops[0x3A] = Instr::new(CPU::im,  op_nop);
-    ops[0x5A] = Instr::new(CPU::im,  op_nop);
-    ops[0x7A] = Instr::new(CPU::im,  op_nop);
-    ops[0xDA] = Instr::new(CPU::im,  op_nop);
-    ops[0xEA] = Instr::new(CPU::im,  op_nop);
-    ops[0xFA] = Instr::new(CPU::im,  op_nop);
+    ops[0x3A] = Instr::new(no_arg,  op_nop);
+    ops[0x5A] = Instr::new(no_arg,  op_nop);
+    ops[0x7A] = Instr::new(no_arg,  op_nop);
+    ops[0xDA] = Instr::new(no_arg,  op_nop);
+    ops[0xEA] = Instr::new(no_arg,  op_nop);
+    ops[0xFA] = Instr::new(no_arg,  op_nop);
 
 
     //ora
@@ -245,14 +271,36 @@ pub fn make_op_table() -> [Instr; 256] {
     ops[0x9A] = Instr::new(no_arg,  op_txs);
     ops[0xBA] = Instr::new(no_arg,  op_tsx);
 
+    //jam
+    for opcode in [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2].iter() {
+        ops[*opcode] = Instr::new(no_arg,  op_jam);
+    }
 
     ops
 }
 
+// The table never changes, so it is built once and every CPU holds a reference to it
+// rather than its own copy.
+pub fn op_table() -> &'static [Instr; 256] {
+    static OPS: OnceLock<[Instr; 256]> = OnceLock::new();
+    OPS.get_or_init(make_op_table)
+}
+
 
 // implement ops
 fn op_not_implemented(cpu: &mut CPU, src: u16) {
-    panic!("Error, this op is not implemented.")
+    // leave the pc on the opcode, so stepping again reports the same error
+    let pc = cpu.op_pc;
+    let opcode = cpu.read(pc as usize);
+    cpu.r.pc = pc;
+    cpu.raise(EmuError::IllegalOpcode { opcode: opcode, pc: pc });
+}
+
+// the cpu locks up until it is reset
+fn op_jam(cpu: &mut CPU, _src: u16) {
+    let pc = cpu.op_pc;
+    cpu.r.pc = pc;
+    cpu.raise(EmuError::Jammed { pc: pc });
 }
 
 // add - add memory to accumulator with carry
@@ -287,20 +335,19 @@ fn op_and(cpu: &mut CPU, src: u16) {
 
 // asl - arithmetic shift left
 fn op_asl(cpu: &mut CPU, src: u16) {
-    let mut v = cpu.mmu.read(src as usize);
-    v = v << 1;
-    cpu.mmu.write(src as usize, v);
+    let v = (cpu.read(src as usize) as u16) << 1;
+    cpu.write(src as usize, (v & 0xFF) as u8);
 
     cpu.r.set_flag('C', v > 0xFF);
-    cpu.r.zn(v & 0xFF);
+    cpu.r.zn((v & 0xFF) as u8);
 }
 
 fn op_asl_acc(cpu: &mut CPU, src: u16) {
-    let v = cpu.r.a << 1;
-    cpu.r.a = v & 0xFF;
+    let v = (cpu.r.a as u16) << 1;
+    cpu.r.a = (v & 0xFF) as u8;
 
     cpu.r.set_flag('C', v > 0xFF);
-    cpu.r.zn(v & 0xFF);
+    cpu.r.zn((v & 0xFF) as u8);
 }
 
 // Branching ops
@@ -353,6 +400,7 @@ fn op_beq(cpu: &mut CPU, src: u16) {
 }
 
 fn branch(cpu: &mut CPU, src: u16) {
+    cpu.branch_taken = true;
     let o = cpu.r.pc;
     cpu.r.pc = cpu.r.pc.wrapping_add(cpu.from_twos_com(src) as u16);
 
@@ -379,7 +427,10 @@ fn op_brk(cpu: &mut CPU, src: u16) {
 
     let pc = cpu.r.pc;
     cpu.stack_push_word(pc);
-    
+    if let Some(ref mut sanitizer) = cpu.sanitizer {
+        sanitizer.interrupt(pc);
+    }
+
     let p = cpu.r.p;
     cpu.stack_push(p);
     cpu.r.set_flag('I', true);
@@ -416,13 +467,13 @@ fn op_cpy(cpu: &mut CPU, src: u16) {
 
 //dec
 fn op_dec(cpu: &mut CPU, src: u16) {
-    let mut v = cpu.mmu.read(src as usize);
+    let mut v = cpu.read(src as usize);
     if v == 0 {
         v = 0xFF;
     } else {
         v -= 1;
     }
-    cpu.mmu.write(src as usize, v);
+    cpu.write(src as usize, v);
     cpu.r.zn(v);
 }
 
@@ -444,7 +495,7 @@ fn op_dey(cpu: &mut CPU, src: u16) {
     } else {
         v -= 1;
     }
-    cpu.r.x = v;
+    cpu.r.y = v;
     cpu.r.zn(v);
 }
 
@@ -485,19 +536,19 @@ fn op_sed(cpu: &mut CPU, src: u16) {
 
 //inc
 fn op_inc(cpu: &mut CPU, src: u16) {
-    let v = (cpu.mmu.read(src as usize)+1) & 0xFF;
-    cpu.mmu.write(src as usize, v);
+    let v = cpu.read(src as usize).wrapping_add(1);
+    cpu.write(src as usize, v);
     cpu.r.zn(v);
 }
 
 fn op_inx(cpu: &mut CPU, src: u16) {
-    let v = (cpu.r.x+1) & 0xFF;
+    let v = cpu.r.x.wrapping_add(1);
     cpu.r.x = v;
     cpu.r.zn(v);
 }
 
 fn op_iny(cpu: &mut CPU, src: u16) {
-    let v = (cpu.r.y+1) & 0xFF;
+    let v = cpu.r.y.wrapping_add(1);
     cpu.r.y = v;
     cpu.r.zn(v);
 }
@@ -507,8 +558,11 @@ fn op_jmp(cpu: &mut CPU, src: u16) {
 }
 
 fn op_jsr(cpu: &mut CPU, src: u16) {
-    let pc = cpu.r.pc-1;
+    let pc = cpu.r.pc.wrapping_sub(1);
     cpu.stack_push_word(pc);
+    if let Some(ref mut sanitizer) = cpu.sanitizer {
+        sanitizer.call(pc.wrapping_add(1));
+    }
     cpu.r.pc = src;
 }
 
@@ -536,11 +590,11 @@ fn op_lsra(cpu: &mut CPU, src: u16) {
 }
 
 fn op_lsr(cpu: &mut CPU, src: u16) {
-    let mut v = cpu.mmu.read(src as usize);
+    let mut v = cpu.read(src as usize);
     let val = v & 0x01 != 0;
     cpu.r.set_flag('C', val);
     v = v >> 1;
-    cpu.mmu.write(src as usize, v);
+    cpu.write(src as usize, v);
     cpu.r.zn(v);
 }
 
@@ -587,9 +641,9 @@ fn op_rola(cpu: &mut CPU, src: u16) {
 }
 
 fn op_rol(cpu: &mut CPU, src: u16) {
-    let v_old = cpu.mmu.read(src as usize);
+    let v_old = cpu.read(src as usize);
     let v_new = ((v_old << 1) + cpu.r.get_flag('C') as u8) & 0xFF;
-    cpu.mmu.write(src as usize, v_new);
+    cpu.write(src as usize, v_new);
 
     cpu.r.set_flag('C', v_old & 0x80 != 0);
     cpu.r.zn(v_new);
@@ -605,9 +659,9 @@ fn op_rora(cpu: &mut CPU, src: u16) {
 }
 
 fn op_ror(cpu: &mut CPU, src: u16) {
-    let v_old = cpu.mmu.read(src as usize);
+    let v_old = cpu.read(src as usize);
     let v_new = ((v_old >> 1) + (cpu.r.get_flag('C') as u8)* 0x80) & 0xFF;
-    cpu.mmu.write(src as usize, v_new);
+    cpu.write(src as usize, v_new);
 
     cpu.r.set_flag('C', v_old & 0x01 != 0);
     cpu.r.zn(v_new);
@@ -616,10 +670,16 @@ fn op_ror(cpu: &mut CPU, src: u16) {
 fn op_rti(cpu: &mut CPU, src: u16) {
     cpu.r.p = cpu.stack_pop();
     cpu.r.pc = cpu.stack_pop_word();
+    if let Some(ref mut sanitizer) = cpu.sanitizer {
+        sanitizer.rti(cpu.op_pc, cpu.r.pc);
+    }
 }
 
 fn op_rts(cpu: &mut CPU, src: u16) {
-    cpu.r.pc = (cpu.stack_pop_word() + 1) & 0xFFFF;
+    cpu.r.pc = cpu.stack_pop_word().wrapping_add(1);
+    if let Some(ref mut sanitizer) = cpu.sanitizer {
+        sanitizer.rts(cpu.op_pc, cpu.r.pc);
+    }
 }
 
 fn op_sbc(cpu: &mut CPU, src: u16) {
@@ -641,13 +701,13 @@ fn op_sbc(cpu: &mut CPU, src: u16) {
 }
 
 fn op_sta(cpu: &mut CPU, src: u16) {
-    cpu.mmu.write(src as usize, cpu.r.a);
+    cpu.write(src as usize, cpu.r.a);
 }
 fn op_stx(cpu: &mut CPU, src: u16) {
-    cpu.mmu.write(src as usize, cpu.r.x);
+    cpu.write(src as usize, cpu.r.x);
 }
 fn op_sty(cpu: &mut CPU, src: u16) {
-    cpu.mmu.write(src as usize, cpu.r.y);
+    cpu.write(src as usize, cpu.r.y);
 }
 
 // transfers
@@ -697,7 +757,7 @@ mod tests {
     #[test]
     fn test_adc() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![1, 2, 250, 3, 100, 100]));
+        let mut cpu = make_cpu(Some(vec![1, 2, 250, 3, 100, 100])).unwrap();
         let src = (ops[0x69].addr)(&mut cpu);
         (ops[0x69].code)(&mut cpu, src);
         assert_eq!(cpu.r.a, 1);
@@ -728,7 +788,7 @@ mod tests {
     #[test]
     fn test_adc_decimal() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x01, 0x55, 0x50]));
+        let mut cpu = make_cpu(Some(vec![0x01, 0x55, 0x50])).unwrap();
         cpu.r.set_flag('D', true);
 
         let src = (ops[0x69].addr)(&mut cpu);
@@ -748,7 +808,7 @@ mod tests {
     #[test]
     fn test_and() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0xFF, 0xFF, 0x01, 0x2]));
+        let mut cpu = make_cpu(Some(vec![0xFF, 0xFF, 0x01, 0x2])).unwrap();
 
         cpu.r.a = 0x00;
         let src = (ops[0x29].addr)(&mut cpu);
@@ -774,23 +834,40 @@ mod tests {
     #[test]
     fn test_asl() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00]));
+        let mut cpu = make_cpu(Some(vec![0x00])).unwrap();
 
         cpu.r.a = 1;
         let src = (ops[0x0A].addr)(&mut cpu);
         (ops[0x0A].code)(&mut cpu, src);
         assert_eq!(cpu.r.a, 2);
 
-        cpu.mmu.write(0, 4);
+        cpu.mmu.write(0, 4).unwrap();
         let src = (ops[0x06].addr)(&mut cpu);
         (ops[0x06].code)(&mut cpu, src);
-        assert_eq!(cpu.mmu.read(0), 8);
+        assert_eq!(cpu.mmu.read(0).unwrap(), 8);
+
+        // the top bit goes into the carry
+        cpu.r.a = 0x81;
+        let src = (ops[0x0A].addr)(&mut cpu);
+        (ops[0x0A].code)(&mut cpu, src);
+        assert_eq!(cpu.r.a, 0x02);
+        assert_eq!(cpu.r.get_flag('C'), true);
+
+        // in memory too
+        cpu.r.pc = 0x1000;
+        cpu.r.set_flag('C', false);
+        cpu.mmu.write(0, 0x80).unwrap();
+        let src = (ops[0x06].addr)(&mut cpu);
+        (ops[0x06].code)(&mut cpu, src);
+        assert_eq!(cpu.mmu.read(0).unwrap(), 0x00);
+        assert_eq!(cpu.r.get_flag('C'), true);
+        assert_eq!(cpu.r.get_flag('Z'), true);
     }
 
     #[test]
     fn test_branch() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x01, 0x00, 0x00, 0xFC]));
+        let mut cpu = make_cpu(Some(vec![0x01, 0x00, 0x00, 0xFC])).unwrap();
 
         let src = (ops[0x10].addr)(&mut cpu);
         (ops[0x10].code)(&mut cpu, src);
@@ -813,8 +890,8 @@ mod tests {
     #[test]
     fn test_bit() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00, 0x00, 0x10]));
-        cpu.mmu.write(0, 0xFF);
+        let mut cpu = make_cpu(Some(vec![0x00, 0x00, 0x10])).unwrap();
+        cpu.mmu.write(0, 0xFF).unwrap();
         cpu.r.a = 1;
 
         let src = (ops[0x24].addr)(&mut cpu);
@@ -833,9 +910,9 @@ mod tests {
     #[test]
     fn test_brk() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
-        let block = Block::new(0xFFFE, 0x2, true, Some(vec![0x34, 0x12]));
-        cpu.mmu.add_block(&block);
+        let mut cpu = make_cpu(None).unwrap();
+        let block = Block::new(0xFFFE, 0x2, true, Some(vec![0x34, 0x12])).unwrap();
+        cpu.mmu.add_block(&block).unwrap();
         cpu.r.p = 239;
 
         let src = (ops[0x00].addr)(&mut cpu);
@@ -850,7 +927,7 @@ mod tests {
     #[test]
     fn test_cmp() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x0F, 0x10, 0x11, 0xFE, 0xFF, 0x00, 0x7F]));
+        let mut cpu = make_cpu(Some(vec![0x0F, 0x10, 0x11, 0xFE, 0xFF, 0x00, 0x7F])).unwrap();
 
         cpu.r.a = 0x10;
         let src = (ops[0xC9].addr)(&mut cpu);
@@ -900,7 +977,7 @@ mod tests {
     #[test]
     fn test_cpx() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x0F, 0x10, 0x11]));
+        let mut cpu = make_cpu(Some(vec![0x0F, 0x10, 0x11])).unwrap();
 
         cpu.r.x = 0x10;
         let src = (ops[0xE0].addr)(&mut cpu);
@@ -925,7 +1002,7 @@ mod tests {
     #[test]
     fn test_cpy() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x0F, 0x10, 0x11]));
+        let mut cpu = make_cpu(Some(vec![0x0F, 0x10, 0x11])).unwrap();
 
         cpu.r.y = 0x10;
         let src = (ops[0xC0].addr)(&mut cpu);
@@ -950,16 +1027,16 @@ mod tests {
     #[test]
     fn test_dec() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00]));
+        let mut cpu = make_cpu(Some(vec![0x00])).unwrap();
         let src = (ops[0xC6].addr)(&mut cpu);
         (ops[0xC6].code)(&mut cpu, src);
-        assert_eq!(cpu.mmu.read(0x00), 0xFF);
+        assert_eq!(cpu.mmu.read(0x00).unwrap(), 0xFF);
     }
 
     #[test]
     fn test_dex() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
         let src = (ops[0xCA].addr)(&mut cpu);
         (ops[0xCA].code)(&mut cpu, src);
         assert_eq!(cpu.r.x, 0xFF);
@@ -968,16 +1045,17 @@ mod tests {
     #[test]
     fn test_dey() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
         let src = (ops[0x88].addr)(&mut cpu);
         (ops[0x88].code)(&mut cpu, src);
-        assert_eq!(cpu.r.x, 0xFF);
+        assert_eq!(cpu.r.y, 0xFF);
+        assert_eq!(cpu.r.x, 0x00);
     }
 
     #[test]
     fn test_eor() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x0F, 0xF0, 0xFF]));
+        let mut cpu = make_cpu(Some(vec![0x0F, 0xF0, 0xFF])).unwrap();
         let src = (ops[0x49].addr)(&mut cpu);
         (ops[0x49].code)(&mut cpu, src);
         assert_eq!(cpu.r.a, 0x0F);
@@ -994,7 +1072,7 @@ mod tests {
     #[test]
     fn test_flag_ops() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
         let src = (ops[0x38].addr)(&mut cpu);
         (ops[0x38].code)(&mut cpu, src);
         assert_eq!(cpu.r.get_flag('C'), true);
@@ -1023,34 +1101,37 @@ mod tests {
     #[test]
     fn test_inc() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00,]));
+        let mut cpu = make_cpu(Some(vec![0x00,])).unwrap();
         let src = (ops[0xe6].addr)(&mut cpu);
         (ops[0xe6].code)(&mut cpu, src);
-        assert_eq!(cpu.mmu.read(0x00), 0x01);
+        assert_eq!(cpu.mmu.read(0x00).unwrap(), 0x01);
     }
 
     #[test]
     fn test_inx() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
         let src = (ops[0xE8].addr)(&mut cpu);
         (ops[0xE8].code)(&mut cpu, src);
         assert_eq!(cpu.r.x, 0x01);
+        // implied, so there's no operand to skip
+        assert_eq!(cpu.r.pc, 0x1000);
     }
 
     #[test]
     fn test_iny() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
         let src = (ops[0xC8].addr)(&mut cpu);
         (ops[0xC8].code)(&mut cpu, src);
         assert_eq!(cpu.r.y, 0x01);
+        assert_eq!(cpu.r.pc, 0x1000);
     }
 
     #[test]
     fn test_jmp() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00, 0x10]));
+        let mut cpu = make_cpu(Some(vec![0x00, 0x10])).unwrap();
         let src = (ops[0x4C].addr)(&mut cpu);
         (ops[0x4C].code)(&mut cpu, src);
         assert_eq!(cpu.r.pc, 0x1000);
@@ -1063,7 +1144,7 @@ mod tests {
     #[test]
     fn test_jsr() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00, 0x10]));
+        let mut cpu = make_cpu(Some(vec![0x00, 0x10])).unwrap();
         let src = (ops[0x20].addr)(&mut cpu);
         (ops[0x20].code)(&mut cpu, src);
         assert_eq!(cpu.r.pc, 0x1000);
@@ -1073,7 +1154,7 @@ mod tests {
     #[test]
     fn test_lda() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x01,]));
+        let mut cpu = make_cpu(Some(vec![0x01,])).unwrap();
         let src = (ops[0xA9].addr)(&mut cpu);
         (ops[0xA9].code)(&mut cpu, src);
         assert_eq!(cpu.r.a, 0x01);
@@ -1082,7 +1163,7 @@ mod tests {
     #[test]
     fn test_ldx() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x01,]));
+        let mut cpu = make_cpu(Some(vec![0x01,])).unwrap();
         let src = (ops[0xA2].addr)(&mut cpu);
         (ops[0xA2].code)(&mut cpu, src);
         assert_eq!(cpu.r.x, 0x01);
@@ -1091,7 +1172,7 @@ mod tests {
     #[test]
     fn test_ldy() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x01,]));
+        let mut cpu = make_cpu(Some(vec![0x01,])).unwrap();
         let src = (ops[0xA0].addr)(&mut cpu);
         (ops[0xA0].code)(&mut cpu, src);
         assert_eq!(cpu.r.y, 0x01);
@@ -1100,7 +1181,7 @@ mod tests {
     #[test]
     fn test_lsr() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x00,]));
+        let mut cpu = make_cpu(Some(vec![0x00,])).unwrap();
         cpu.r.a = 0x02;
 
         let src = (ops[0x4A].addr)(&mut cpu);
@@ -1113,17 +1194,29 @@ mod tests {
         assert_eq!(cpu.r.a, 0x00);
         assert_eq!(cpu.r.get_flag('C'), true);
 
-        cpu.mmu.write(0x00, 0x02);
+        cpu.mmu.write(0x00, 0x02).unwrap();
         let src = (ops[0x46].addr)(&mut cpu);
         (ops[0x46].code)(&mut cpu, src);
-        assert_eq!(cpu.mmu.read(0x00), 0x01);
+        assert_eq!(cpu.mmu.read(0x00).unwrap(), 0x01);
     }
 
 
+    #[test]
+    fn test_nop() {
+        let ops = make_op_table();
+        let mut cpu = make_cpu(None).unwrap();
+        // the official nop and the unofficial single byte ones
+        for opcode in [0x1A, 0x3A, 0x5A, 0x7A, 0xDA, 0xEA, 0xFA].iter() {
+            let src = (ops[*opcode].addr)(&mut cpu);
+            (ops[*opcode].code)(&mut cpu, src);
+            assert_eq!(cpu.r.pc, 0x1000);
+        }
+    }
+
     #[test]
     fn test_ora() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec![0x0F, 0xF0, 0xFF]));
+        let mut cpu = make_cpu(Some(vec![0x0F, 0xF0, 0xFF])).unwrap();
 
         let src = (ops[0x09].addr)(&mut cpu);
         (ops[0x09].code)(&mut cpu, src);
@@ -1142,7 +1235,7 @@ mod tests {
     #[test]
     fn test_p() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(None);
+        let mut cpu = make_cpu(None).unwrap();
 
         cpu.r.a = 0xCC;
         let src = (ops[0x48].addr)(&mut cpu);
@@ -1170,7 +1263,7 @@ mod tests {
     #[test]
     fn test_rol() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec!(0x00)));
+        let mut cpu = make_cpu(Some(vec!(0x00))).unwrap();
 
         cpu.r.a = 0xFF;
         let src = (ops[0x2A].addr)(&mut cpu);
@@ -1185,14 +1278,14 @@ mod tests {
 
         let src = (ops[0x26].addr)(&mut cpu);
         (ops[0x26].code)(&mut cpu, src);
-        assert_eq!(cpu.mmu.read(0x00), 0x01);
+        assert_eq!(cpu.mmu.read(0x00).unwrap(), 0x01);
         assert_eq!(cpu.r.get_flag('C'), false);
     }
 
     #[test]
     fn test_ror() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec!(0x00)));
+        let mut cpu = make_cpu(Some(vec!(0x00))).unwrap();
 
         cpu.r.a = 0xFF;
         let src = (ops[0x6A].addr)(&mut cpu);
@@ -1207,7 +1300,7 @@ mod tests {
 
         let src = (ops[0x66].addr)(&mut cpu);
         (ops[0x66].code)(&mut cpu, src);
-        assert_eq!(cpu.mmu.read(0x00), 0x80);
+        assert_eq!(cpu.mmu.read(0x00).unwrap(), 0x80);
         assert_eq!(cpu.r.get_flag('C'), false);
     }
 
@@ -1215,7 +1308,7 @@ mod tests {
     #[test]
     fn test_rti() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec!(0x00)));
+        let mut cpu = make_cpu(Some(vec!(0x00))).unwrap();
 
         cpu.stack_push_word(0x1234);
         cpu.stack_push(0xFD);
@@ -1235,7 +1328,7 @@ mod tests {
     #[test]
     fn test_rts() {
         let ops = make_op_table();
-        let mut cpu = make_cpu(Some(vec!(0x00)));
+        let mut cpu = make_cpu(Some(vec!(0x00))).unwrap();
 
         cpu.stack_push_word(0x1234);
         let src = (ops[0x60].addr)(&mut cpu);
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+
+use symbols::SymbolTable;
+
+/// Memory access counts for each of the 256 pages of the address space.
+///
+/// The MMU holds one of these while profiling is enabled and bumps the counters on every
+/// `read` and `write`, so instruction fetches and stack traffic show up here too.
+#[derive(Clone, Debug)]
+pub struct PageHeat {
+    pub reads: Vec<u64>,
+    pub writes: Vec<u64>,
+}
+
+impl PageHeat {
+    pub fn new() -> PageHeat {
+        PageHeat {
+            reads: vec![0; 256],
+            writes: vec![0; 256],
+        }
+    }
+}
+
+impl Default for PageHeat {
+    fn default() -> PageHeat {
+        PageHeat::new()
+    }
+}
+
+/// Execution counts for a single instruction address
+#[derive(Clone, Debug, Default)]
+pub struct PcStats {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/// Call counts for a single JSR target
+///
+/// Inclusive cycles are everything spent between the JSR and the matching RTS, exclusive
+/// cycles leave out the time spent in nested subroutines.
+#[derive(Clone, Debug, Default)]
+pub struct CallStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Debug)]
+struct Frame {
+    target: u16,
+    entry: u64,
+    child_cycles: u64,
+}
+
+/// This collects where a program spends its time. It is fed one instruction at a time by
+/// `CPU::step` once `CPU::enable_profiler` has been called.
+///
+/// Subroutines are tracked with a shadow call stack that is pushed on JSR and popped on RTS,
+/// which gives both the call graph and the stacks for a flamegraph.
+#[derive(Debug)]
+pub struct Profiler {
+    /// Stats keyed by the address of the instruction
+    pub pcs: HashMap<u16, PcStats>,
+
+    /// Stats keyed by the JSR target address
+    pub calls: HashMap<u16, CallStats>,
+
+    /// Total instructions and cycles seen
+    pub instructions: u64,
+    pub cycles: u64,
+
+    stack: Vec<Frame>,
+    path: Vec<u16>,
+    folded: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            pcs: HashMap::new(),
+            calls: HashMap::new(),
+            instructions: 0,
+            cycles: 0,
+            stack: Vec::new(),
+            path: Vec::new(),
+            folded: HashMap::new(),
+        }
+    }
+
+    /// Record one executed instruction. `next_pc` is the program counter after the
+    /// instruction ran, which for a JSR is the subroutine being entered.
+    pub fn record(&mut self, pc: u16, opcode: u8, cycles: u32, next_pc: u16) {
+        let cycles = cycles as u64;
+        self.instructions += 1;
+        self.cycles += cycles;
+
+        let stats = self.pcs.entry(pc).or_default();
+        stats.count += 1;
+        stats.cycles += cycles;
+
+        // the instruction is charged to the stack it ran in, so a JSR belongs to the caller
+        // and an RTS belongs to the subroutine that is returning
+        if let Some(total) = self.folded.get_mut(&self.path) {
+            *total += cycles;
+        } else {
+            self.folded.insert(self.path.clone(), cycles);
+        }
+
+        match opcode {
+            // jsr
+            0x20 => {
+                self.stack.push(Frame {
+                    target: next_pc,
+                    entry: self.cycles,
+                    child_cycles: 0,
+                });
+                self.path.push(next_pc);
+            },
+            // rts
+            0x60 => {
+                // an RTS without a matching JSR (ie a computed jump) is ignored
+                if let Some(frame) = self.stack.pop() {
+                    self.path.pop();
+                    let inclusive = self.cycles - frame.entry;
+
+                    let stats = self.calls.entry(frame.target).or_default();
+                    stats.calls += 1;
+                    stats.inclusive += inclusive;
+                    stats.exclusive += inclusive - frame.child_cycles;
+
+                    if let Some(parent) = self.stack.last_mut() {
+                        parent.child_cycles += inclusive;
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// A human readable report, with the hottest addresses, subroutines and pages first.
+    /// Addresses are followed by their names from `symbols`.
+    pub fn report(&self, heat: Option<&PageHeat>, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("instructions: {}, cycles: {}\n", self.instructions, self.cycles));
+
+        out.push_str("\n-- hot spots --\n");
+        out.push_str("addr      count     cycles      %\n");
+        let mut pcs: Vec<(&u16, &PcStats)> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        for (pc, stats) in pcs {
+            out.push_str(&format!("${:04x} {:>10} {:>10} {:>6.2}  {}\n",
+                pc, stats.count, stats.cycles, self.percent(stats.cycles),
+                symbols.name(*pc).unwrap_or_default()));
+        }
+
+        out.push_str("\n-- subroutines --\n");
+        out.push_str("addr      calls  inclusive  exclusive\n");
+        let mut calls: Vec<(&u16, &CallStats)> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (target, stats) in calls {
+            out.push_str(&format!("${:04x} {:>10} {:>10} {:>10}  {}\n",
+                target, stats.calls, stats.inclusive, stats.exclusive,
+                symbols.name(*target).unwrap_or_default()));
+        }
+
+        if let Some(heat) = heat {
+            out.push_str("\n-- memory pages --\n");
+            out.push_str("page      reads     writes\n");
+            let mut pages: Vec<usize> = (0..256)
+                .filter(|&p| heat.reads[p] + heat.writes[p] > 0)
+                .collect();
+            pages.sort_by(|&a, &b| (heat.reads[b] + heat.writes[b])
+                .cmp(&(heat.reads[a] + heat.writes[a]))
+                .then(a.cmp(&b)));
+            for page in pages {
+                out.push_str(&format!("${:02x}xx {:>10} {:>10}\n",
+                    page, heat.reads[page], heat.writes[page]));
+            }
+        }
+        out
+    }
+
+    /// The exclusive cycles of every call stack seen, one `main;$0606;$0638 123` line per
+    /// stack. This is the folded format read by flamegraph.pl and inferno. Subroutines with a
+    /// name in `symbols` go by it instead, ie `main;init;drawApple 123`.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self.folded.iter()
+            .map(|(path, cycles)| {
+                let mut line = String::from("main");
+                for &target in path {
+                    match symbols.name(target) {
+                        Some(name) => line.push_str(&format!(";{}", name)),
+                        None => line.push_str(&format!(";${:04x}", target)),
+                    }
+                }
+                format!("{} {}", line, cycles)
+            })
+            .collect();
+        lines.sort();
+
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.cycles == 0 {
+            0.0
+        } else {
+            100.0 * cycles as f64 / self.cycles as f64
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use cpu::make_cpu;
+
+    // jsr $1006 / jmp $1003 / lda #$05 / rts
+    fn make_program() -> Vec<u8> {
+        vec![0x20, 0x06, 0x10, 0x4C, 0x03, 0x10, 0xA9, 0x05, 0x60]
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut cpu = make_cpu(Some(make_program())).unwrap();
+        cpu.enable_profiler();
+
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        let p = cpu.profiler.as_ref().unwrap();
+        assert_eq!(p.instructions, 5);
+        assert_eq!(p.pcs[&0x1000].count, 1);
+        assert_eq!(p.pcs[&0x1000].cycles, 6);
+        assert_eq!(p.pcs[&0x1003].count, 2);
+        assert_eq!(p.pcs[&0x1003].cycles, 6);
+        assert_eq!(p.cycles, 6 + 2 + 6 + 3 + 3);
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let mut cpu = make_cpu(Some(make_program())).unwrap();
+        cpu.enable_profiler();
+
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        let p = cpu.profiler.as_ref().unwrap();
+        assert_eq!(p.calls[&0x1006].calls, 1);
+        assert_eq!(p.calls[&0x1006].inclusive, 8);
+        assert_eq!(p.calls[&0x1006].exclusive, 8);
+        assert_eq!(p.folded(&SymbolTable::new()), "main 12\nmain;$1006 8\n");
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let mut p = Profiler::new();
+        p.record(0x1000, 0x20, 6, 0x2000);
+        p.record(0x2000, 0x20, 6, 0x3000);
+        p.record(0x3000, 0xEA, 2, 0x3001);
+        p.record(0x3001, 0x60, 6, 0x2003);
+        p.record(0x2003, 0x60, 6, 0x1003);
+
+        assert_eq!(p.calls[&0x3000].inclusive, 8);
+        assert_eq!(p.calls[&0x3000].exclusive, 8);
+        assert_eq!(p.calls[&0x2000].inclusive, 20);
+        assert_eq!(p.calls[&0x2000].exclusive, 12);
+        assert_eq!(p.folded(&SymbolTable::new()), "main 6\nmain;$2000 12\nmain;$2000;$3000 8\n");
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("inner", 0x3000);
+        assert_eq!(p.folded(&symbols), "main 6\nmain;$2000 12\nmain;$2000;inner 8\n");
+    }
+
+    #[test]
+    fn test_page_heat() {
+        let mut cpu = make_cpu(Some(make_program())).unwrap();
+        cpu.enable_profiler();
+
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        // the stack lives in page $01, the program in page $10
+        let heat = cpu.mmu.heat.as_ref().unwrap();
+        assert_eq!(heat.writes[0x01], 2);
+        assert_eq!(heat.reads[0x01], 2);
+        assert_eq!(heat.writes[0x10], 0);
+        assert!(heat.reads[0x10] > 0);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("load", 0x1006);
+        let report = cpu.profiler.as_ref().unwrap().report(Some(heat), &symbols);
+        assert!(report.contains("$1006"));
+        assert!(report.contains("  load\n"));
+        assert!(report.contains("  load+2\n"));
+        assert!(report.contains("$10xx"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use mmu::MMU;
+
+/// Something a program did that is almost certainly a bug
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Issue {
+    /// A read of RAM that nothing has written yet
+    UninitializedRead(usize),
+
+    /// An RTS that didn't go back to just after the JSR it should be returning from, and
+    /// where that JSR would have returned to (None if there was no call to return from)
+    BadRts { to: u16, expected: Option<u16> },
+
+    /// Like `BadRts`, for an RTI and the interrupt it should be returning from
+    BadRti { to: u16, expected: Option<u16> },
+
+    /// The stack pointer wrapped around the stack page
+    StackWrap,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::UninitializedRead(addr) =>
+                write!(f, "read of uninitialized memory at ${:04x}", addr),
+            Issue::BadRts { to, expected: Some(expected) } =>
+                write!(f, "RTS to ${:04x}, but the last JSR returns to ${:04x}", to, expected),
+            Issue::BadRts { to, expected: None } =>
+                write!(f, "RTS to ${:04x} without a JSR", to),
+            Issue::BadRti { to, expected: Some(expected) } =>
+                write!(f, "RTI to ${:04x}, but the interrupt returns to ${:04x}", to, expected),
+            Issue::BadRti { to, expected: None } =>
+                write!(f, "RTI to ${:04x} outside of an interrupt", to),
+            Issue::StackWrap =>
+                write!(f, "stack pointer wrapped around"),
+        }
+    }
+}
+
+/// An issue, and the address of the instruction that caused it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Report {
+    pub pc: u16,
+    pub issue: Issue,
+}
+
+#[derive(Clone, Debug)]
+struct Frame {
+    return_to: u16,
+    interrupt: bool,
+}
+
+/// This watches for reads of memory that was never written, unbalanced JSR/RTS and
+/// interrupt/RTI, and stack wrap around, once `CPU::enable_sanitizer` has been called.
+///
+/// Every writable block is shadowed by a map of which bytes have been written, starting out
+/// all unwritten, so anything put in RAM has to be loaded (see `Image::load`) or written
+/// after the sanitizer is enabled. ROM and devices aren't checked. Calls and interrupts are
+/// followed on a shadow call stack, which is unwound to the matching frame when a return
+/// skips some, the way a program bailing out of nested subroutines would.
+///
+/// Each issue is reported once per instruction, in the order they are found.
+#[derive(Debug)]
+pub struct Sanitizer {
+    pub reports: Vec<Report>,
+
+    written: Vec<(usize, Vec<bool>)>,
+    calls: Vec<Frame>,
+    seen: HashSet<Report>,
+}
+
+impl Sanitizer {
+    pub fn new(mmu: &MMU) -> Sanitizer {
+        Sanitizer {
+            reports: Vec::new(),
+            written: mmu.blocks.iter()
+                .filter(|block| !block.readonly)
+                .map(|block| (block.start, vec![false; block.length]))
+                .collect(),
+            calls: Vec::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn report(&mut self, pc: u16, issue: Issue) {
+        let report = Report {
+            pc: pc,
+            issue: issue,
+        };
+        if self.seen.insert(report.clone()) {
+            self.reports.push(report);
+        }
+    }
+
+    fn shadow(&mut self, addr: usize) -> Option<&mut bool> {
+        self.written.iter_mut()
+            .find(|&&mut (start, ref shadow)| addr >= start && addr < start + shadow.len())
+            .map(|&mut (start, ref mut shadow)| &mut shadow[addr - start])
+    }
+
+    /// Mark `length` bytes from `addr` as written
+    pub fn write(&mut self, addr: usize, length: usize) {
+        for addr in addr..addr + length {
+            if let Some(written) = self.shadow(addr) {
+                *written = true;
+            }
+        }
+    }
+
+    pub fn read(&mut self, pc: u16, addr: usize) {
+        if self.shadow(addr) == Some(&mut false) {
+            self.report(pc, Issue::UninitializedRead(addr));
+        }
+    }
+
+    /// A JSR, which should be returned from at `return_to`
+    pub fn call(&mut self, return_to: u16) {
+        self.calls.push(Frame {
+            return_to: return_to,
+            interrupt: false,
+        });
+    }
+
+    /// An interrupt (or BRK) taken, which should be returned from at `return_to`
+    pub fn interrupt(&mut self, return_to: u16) {
+        self.calls.push(Frame {
+            return_to: return_to,
+            interrupt: true,
+        });
+    }
+
+    pub fn rts(&mut self, pc: u16, to: u16) {
+        if let Some(expected) = self.unwind(to, false) {
+            self.report(pc, Issue::BadRts { to: to, expected: expected });
+        }
+    }
+
+    pub fn rti(&mut self, pc: u16, to: u16) {
+        if let Some(expected) = self.unwind(to, true) {
+            self.report(pc, Issue::BadRti { to: to, expected: expected });
+        }
+    }
+
+    // Pop the frame being returned to. If it isn't the innermost one, give back where the
+    // innermost frame should have returned to.
+    fn unwind(&mut self, to: u16, interrupt: bool) -> Option<Option<u16>> {
+        let expected = self.calls.last().map(|frame| frame.return_to);
+        let found = self.calls.iter()
+            .rposition(|frame| frame.interrupt == interrupt && frame.return_to == to);
+
+        match found {
+            Some(i) if i + 1 == self.calls.len() => {
+                self.calls.pop();
+                None
+            },
+            Some(i) => {
+                self.calls.truncate(i);
+                Some(expected)
+            },
+            // nowhere it could be returning to, so leave the frames for the real return
+            None => Some(expected),
+        }
+    }
+
+    pub fn stack_wrap(&mut self, pc: u16) {
+        self.report(pc, Issue::StackWrap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use cpu::make_cpu;
+
+    #[test]
+    fn test_uninitialized_read() {
+        // lda $10 / sta $11 / lda $11 / lda $1000
+        let mut cpu = make_cpu(Some(vec![0xA5, 0x10, 0x85, 0x11, 0xA5, 0x11, 0xAD, 0x00, 0x10])).unwrap();
+        cpu.enable_sanitizer();
+
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // the ROM isn't checked
+        let reports = &cpu.sanitizer.as_ref().unwrap().reports;
+        assert_eq!(*reports, vec![Report { pc: 0x1000, issue: Issue::UninitializedRead(0x10) }]);
+    }
+
+    #[test]
+    fn test_unbalanced_pla() {
+        // pla, with a byte on the stack that was never pushed
+        let mut cpu = make_cpu(Some(vec![0x68])).unwrap();
+        cpu.enable_sanitizer();
+        cpu.r.s = 0xFE;
+
+        cpu.step().unwrap();
+        let reports = &cpu.sanitizer.as_ref().unwrap().reports;
+        assert_eq!(*reports, vec![Report { pc: 0x1000, issue: Issue::UninitializedRead(0x1FF) }]);
+    }
+
+    #[test]
+    fn test_calls() {
+        // pha / pha / jsr $1008 / jmp $1005 / pla / pla / rts
+        let mut cpu = make_cpu(Some(vec![
+            0x48, 0x48, 0x20, 0x08, 0x10, 0x4C, 0x05, 0x10, 0x68, 0x68, 0x60,
+        ])).unwrap();
+        cpu.enable_sanitizer();
+
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        // the return address was pulled off, so the RTS uses the two bytes pushed before
+        assert_eq!(cpu.r.pc, 0x0001);
+        let reports = &cpu.sanitizer.as_ref().unwrap().reports;
+        assert_eq!(*reports, vec![
+            Report { pc: 0x100A, issue: Issue::BadRts { to: 0x0001, expected: Some(0x1005) } },
+        ]);
+    }
+
+    #[test]
+    fn test_unwind() {
+        let mut sanitizer = Sanitizer::new(&MMU::new(&Vec::new()).unwrap());
+
+        sanitizer.call(0x1003);
+        sanitizer.call(0x2003);
+        sanitizer.interrupt(0x2100);
+        sanitizer.rti(0x3000, 0x2100);
+        sanitizer.rts(0x2010, 0x2003);
+        assert!(sanitizer.reports.is_empty());
+
+        // returning past a frame drops it
+        sanitizer.call(0x2003);
+        sanitizer.call(0x3003);
+        sanitizer.rts(0x4000, 0x1003);
+        sanitizer.rts(0x1010, 0x0600);
+        sanitizer.rti(0x1020, 0x0600);
+        assert_eq!(sanitizer.reports, vec![
+            Report { pc: 0x4000, issue: Issue::BadRts { to: 0x1003, expected: Some(0x3003) } },
+            Report { pc: 0x1010, issue: Issue::BadRts { to: 0x0600, expected: None } },
+            Report { pc: 0x1020, issue: Issue::BadRti { to: 0x0600, expected: None } },
+        ]);
+    }
+
+    #[test]
+    fn test_stack_wrap() {
+        let mut cpu = make_cpu(Some(vec![0x48])).unwrap();
+        cpu.enable_sanitizer();
+        cpu.r.s = 0;
+
+        assert!(cpu.step().is_err());
+        let reports = &cpu.sanitizer.as_ref().unwrap().reports;
+        assert_eq!(*reports, vec![Report { pc: 0x1000, issue: Issue::StackWrap }]);
+    }
+}
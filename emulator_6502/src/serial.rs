@@ -0,0 +1,357 @@
+#[cfg(unix)]
+extern crate libc;
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+#[cfg(unix)]
+use std::ffi::CStr;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+/// The host end of a serial line, what a serial device like the 6551 ACIA talks to.
+///
+/// Neither side may block: `receive` gives back a byte only if one is already waiting.
+pub trait SerialPort: fmt::Debug + Send {
+    fn receive(&mut self) -> Option<u8>;
+
+    fn send(&mut self, byte: u8);
+}
+
+/// Open a port from a command line description: `stdio`, `pty` or `tcp:<port>`
+pub fn open_port(spec: &str) -> io::Result<Box<dyn SerialPort>> {
+    if spec == "stdio" {
+        return Ok(Box::new(StdioPort::new()));
+    }
+    if spec == "pty" {
+        let port = PtyPort::open()?;
+        println!("Serial port on {}", port.path);
+        return Ok(Box::new(port));
+    }
+    if let Some(number) = spec.strip_prefix("tcp:") {
+        let number = number.parse::<u16>()
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "tcp port is not a number"))?;
+        let port = TcpPort::listen(number)?;
+        println!("Serial port on 127.0.0.1:{}", port.local_port()?);
+        return Ok(Box::new(port));
+    }
+    Err(io::Error::new(ErrorKind::InvalidInput, format!("unknown serial port {}", spec)))
+}
+
+// Read from a blocking source on its own thread, handing each byte over through a channel.
+// With `newline_to_cr` an enter from a line buffered terminal arrives as the CR most 6502
+// software expects.
+fn spawn_reader<R: Read + Send + 'static>(mut source: R, newline_to_cr: bool) -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        loop {
+            let count = match source.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(count) => count,
+            };
+            for &byte in &buffer[..count] {
+                let byte = if newline_to_cr && byte == b'\n' { b'\r' } else { byte };
+                if sender.send(byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// A port made of two channels, for driving the serial line from the same program (tests
+/// and embedding the emulator in something else)
+#[derive(Debug)]
+pub struct ChannelPort {
+    pub input: Receiver<u8>,
+    pub output: Sender<u8>,
+}
+
+impl ChannelPort {
+    /// The port, the sender for bytes to receive and the receiver for bytes sent
+    pub fn new() -> (ChannelPort, Sender<u8>, Receiver<u8>) {
+        let (input_sender, input) = channel();
+        let (output, output_receiver) = channel();
+        let port = ChannelPort {
+            input: input,
+            output: output,
+        };
+        (port, input_sender, output_receiver)
+    }
+}
+
+impl SerialPort for ChannelPort {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn send(&mut self, byte: u8) {
+        // nobody listening is the same as a disconnected cable
+        let _ = self.output.send(byte);
+    }
+}
+
+/// The terminal the emulator was started from
+#[derive(Debug)]
+pub struct StdioPort {
+    input: Receiver<u8>,
+}
+
+impl StdioPort {
+    pub fn new() -> StdioPort {
+        StdioPort {
+            input: spawn_reader(io::stdin(), true),
+        }
+    }
+}
+
+impl Default for StdioPort {
+    fn default() -> StdioPort {
+        StdioPort::new()
+    }
+}
+
+impl SerialPort for StdioPort {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn send(&mut self, byte: u8) {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let _ = handle.write_all(&[byte]);
+        let _ = handle.flush();
+    }
+}
+
+/// A pseudo terminal, which screen, minicom or any other terminal program can open at
+/// `path` as if it were a real serial port
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct PtyPort {
+    pub path: String,
+    input: Receiver<u8>,
+    master: File,
+
+    // Held open so the master doesn't see a hang up while no terminal is attached
+    _slave: File,
+}
+
+#[cfg(unix)]
+impl PtyPort {
+    pub fn open() -> io::Result<PtyPort> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+
+        let path = unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        };
+
+        // raw mode, so nothing is echoed back or line buffered before a terminal sets it up
+        let slave = OpenOptions::new().read(true).write(true).open(&path)?;
+        unsafe {
+            let mut termios = ::std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(PtyPort {
+            path: path,
+            input: spawn_reader(master.try_clone()?, false),
+            master: master,
+            _slave: slave,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl SerialPort for PtyPort {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn send(&mut self, byte: u8) {
+        let _ = self.master.write_all(&[byte]);
+    }
+}
+
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub struct PtyPort {
+    pub path: String,
+}
+
+#[cfg(not(unix))]
+impl PtyPort {
+    pub fn open() -> io::Result<PtyPort> {
+        Err(io::Error::new(ErrorKind::Other, "pseudo terminals need a unix host"))
+    }
+}
+
+#[cfg(not(unix))]
+impl SerialPort for PtyPort {
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn send(&mut self, byte: u8) {}
+}
+
+/// A local TCP port, for `nc localhost <port>` or a telnet client in raw mode. One
+/// connection is served at a time, and anything sent while nobody is connected is lost.
+#[derive(Debug)]
+pub struct TcpPort {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+}
+
+impl TcpPort {
+    pub fn listen(port: u16) -> io::Result<TcpPort> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpPort {
+            listener: listener,
+            stream: None,
+        })
+    }
+
+    /// The port listened on, which is chosen by the OS when listening on port 0
+    pub fn local_port(&self) -> io::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    fn connect(&mut self) {
+        if self.stream.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    let _ = stream.set_nodelay(true);
+                    self.stream = Some(stream);
+                }
+            }
+        }
+    }
+}
+
+impl SerialPort for TcpPort {
+    fn receive(&mut self) -> Option<u8> {
+        self.connect();
+
+        let mut byte = [0];
+        let result = match self.stream {
+            Some(ref mut stream) => stream.read(&mut byte),
+            None => return None,
+        };
+        match result {
+            Ok(1) => Some(byte[0]),
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => None,
+            // hung up, wait for the next connection
+            _ => {
+                self.stream = None;
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, byte: u8) {
+        self.connect();
+
+        let failed = match self.stream {
+            Some(ref mut stream) => stream.write_all(&[byte]).is_err(),
+            None => false,
+        };
+        if failed {
+            self.stream = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use std::time::Duration;
+
+    // ports are polled, so give the other side a moment
+    fn receive_wait(port: &mut dyn SerialPort) -> Option<u8> {
+        for _ in 0..200 {
+            if let Some(byte) = port.receive() {
+                return Some(byte);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    #[test]
+    fn test_channel_port() {
+        let (mut port, input, output) = ChannelPort::new();
+        assert_eq!(port.receive(), None);
+        input.send(b'A').unwrap();
+        assert_eq!(port.receive(), Some(b'A'));
+        port.send(b'B');
+        assert_eq!(output.try_recv(), Ok(b'B'));
+    }
+
+    #[test]
+    fn test_tcp_port() {
+        let mut port = TcpPort::listen(0).unwrap();
+        // sent before anyone connects, so lost
+        port.send(b'X');
+
+        let mut client = TcpStream::connect(("127.0.0.1", port.local_port().unwrap())).unwrap();
+        client.write_all(b"hi").unwrap();
+        assert_eq!(receive_wait(&mut port), Some(b'h'));
+        assert_eq!(receive_wait(&mut port), Some(b'i'));
+
+        port.send(b'!');
+        let mut reply = [0];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"!");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pty_port() {
+        let mut port = PtyPort::open().unwrap();
+        let mut terminal = OpenOptions::new().read(true).write(true).open(&port.path).unwrap();
+
+        terminal.write_all(b"\r").unwrap();
+        assert_eq!(receive_wait(&mut port), Some(b'\r'));
+
+        port.send(b'>');
+        let mut reply = [0];
+        terminal.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b">");
+    }
+
+    #[test]
+    fn test_open_port() {
+        assert!(open_port("tcp:0").is_ok());
+        assert!(open_port("tcp:x").is_err());
+        assert!(open_port("com1").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+extern crate serde;
+extern crate serde_json;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use self::serde::Deserialize;
+
+use cpu::CPU;
+use error::EmuError;
+use mmu::{Block, MMU};
+
+/// The cpu state on one side of a single step test
+#[derive(Clone, Debug, Deserialize)]
+pub struct State {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+
+    /// `[address, value]` for every byte of memory the instruction touches
+    pub ram: Vec<(u16, u8)>,
+}
+
+/// One vector from the per-opcode JSON single-step tests (as published for the 6502 by the
+/// ProcessorTests / SingleStepTests projects). Each file, `a9.json` say, holds a few thousand
+/// of these for a single opcode.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SingleStepTest {
+    pub name: String,
+    pub initial: State,
+    #[serde(rename = "final")]
+    pub expected: State,
+
+    /// `[address, value, "read" | "write"]` for each bus cycle. The emulator doesn't make the
+    /// dummy reads and writes of a real 6502, so its accesses are checked to turn up here in
+    /// the same order, with the extra cycles in between skipped.
+    pub cycles: Vec<(u16, u8, String)>,
+}
+
+/// The results of running a file of tests
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub passed: usize,
+
+    /// Test name and what didn't match, for each failed test
+    pub failures: Vec<(String, Vec<String>)>,
+}
+
+pub fn parse_tests(text: &str) -> Result<Vec<SingleStepTest>, serde_json::Error> {
+    serde_json::from_str(text)
+}
+
+/// Set up a cpu with a flat 64K of RAM in the initial state, execute one `step`, and compare
+/// the registers, memory, bus accesses and cycle count with the final state. Returns every
+/// mismatch. The stack pointer wrapping isn't one, the vectors expect it to wrap like a real
+/// 6502 does.
+pub fn run_test(test: &SingleStepTest) -> Result<(), Vec<String>> {
+    let mut mmu = MMU::new(&Vec::new()).unwrap();
+    mmu.add_block(&Block::new(0, 0x10000, false, None).unwrap()).unwrap();
+    let mut cpu = CPU::new(mmu);
+
+    let initial = &test.initial;
+    cpu.r.pc = initial.pc;
+    cpu.r.s = initial.s as u16;
+    cpu.r.a = initial.a;
+    cpu.r.x = initial.x;
+    cpu.r.y = initial.y;
+    cpu.r.p = initial.p;
+    for &(addr, val) in &initial.ram {
+        cpu.mmu.load(addr as usize, &[val]).unwrap();
+    }
+
+    let mut problems = Vec::new();
+    cpu.enable_bus_log();
+    match cpu.step() {
+        Ok(()) | Err(EmuError::StackWrap { .. }) => {},
+        Err(error) => problems.push(format!("error: {}", error)),
+    }
+
+    let expected = &test.expected;
+    let registers = [
+        ("pc", cpu.r.pc, expected.pc),
+        ("s", cpu.r.s, expected.s as u16),
+        ("a", cpu.r.a as u16, expected.a as u16),
+        ("x", cpu.r.x as u16, expected.x as u16),
+        ("y", cpu.r.y as u16, expected.y as u16),
+        ("p", cpu.r.p as u16, expected.p as u16),
+    ];
+    for &(name, got, want) in registers.iter() {
+        if got != want {
+            problems.push(format!("{}: got ${:02x}, expected ${:02x}", name, got, want));
+        }
+    }
+    for &(addr, want) in &expected.ram {
+        let got = cpu.mmu.blocks[0].memory[addr as usize];
+        if got != want {
+            problems.push(format!("${:04x}: got ${:02x}, expected ${:02x}", addr, got, want));
+        }
+    }
+    let mut cycles = test.cycles.iter();
+    for &(addr, val, kind) in cpu.bus_log.as_ref().unwrap() {
+        if !cycles.any(|cycle| *cycle == (addr, val, kind.to_string())) {
+            problems.push(format!("bus: unexpected {} of ${:02x} at ${:04x}", kind, val, addr));
+            break;
+        }
+    }
+    if cpu.r.cc as usize != test.cycles.len() {
+        problems.push(format!("cycles: got {}, expected {}", cpu.r.cc, test.cycles.len()));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+pub fn run_tests(tests: &[SingleStepTest]) -> Summary {
+    let mut summary = Summary::default();
+    for test in tests {
+        match run_test(test) {
+            Ok(()) => summary.passed += 1,
+            Err(problems) => summary.failures.push((test.name.clone(), problems)),
+        }
+    }
+    summary
+}
+
+/// Run every test in a JSON file
+pub fn run_file(path: &Path) -> io::Result<Summary> {
+    let text = fs::read_to_string(path)?;
+    let tests = parse_tests(&text)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    Ok(run_tests(&tests))
+}
+
+/// Run a single JSON file, or every `.json` file in a directory, printing a line per file
+/// and the first few failures of each. Returns the total passed and failed.
+pub fn run_suite(path: &Path) -> io::Result<(usize, usize)> {
+    let mut files = Vec::new();
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let file = entry?.path();
+            if file.extension().is_some_and(|ext| ext == "json") {
+                files.push(file);
+            }
+        }
+        files.sort();
+    } else {
+        files.push(path.to_path_buf());
+    }
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for file in files {
+        let summary = run_file(&file)?;
+        println!("{}: {} passed, {} failed", file.display(), summary.passed, summary.failures.len());
+        for (name, problems) in summary.failures.iter().take(3) {
+            println!("    {}: {}", name, problems.join(", "));
+        }
+        passed += summary.passed;
+        failed += summary.failures.len();
+    }
+    Ok((passed, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+
+    // lda #$05 and sta $0200, in the published format
+    const TESTS: &str = r#"[
+        {
+            "name": "a9 05",
+            "initial": { "pc": 4096, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                         "ram": [[4096, 169], [4097, 5]] },
+            "final": { "pc": 4098, "s": 253, "a": 5, "x": 0, "y": 0, "p": 36,
+                       "ram": [[4096, 169], [4097, 5]] },
+            "cycles": [[4096, 169, "read"], [4097, 5, "read"]]
+        },
+        {
+            "name": "8d 00 02",
+            "initial": { "pc": 4096, "s": 253, "a": 7, "x": 0, "y": 0, "p": 36,
+                         "ram": [[4096, 141], [4097, 0], [4098, 2], [512, 0]] },
+            "final": { "pc": 4099, "s": 253, "a": 7, "x": 0, "y": 0, "p": 36,
+                       "ram": [[4096, 141], [4097, 0], [4098, 2], [512, 7]] },
+            "cycles": [[4096, 141, "read"], [4097, 0, "read"], [4098, 2, "read"], [512, 7, "write"]]
+        }
+    ]"#;
+
+    #[test]
+    fn test_parse() {
+        let tests = parse_tests(TESTS).unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].name, "a9 05");
+        assert_eq!(tests[0].expected.a, 5);
+        assert_eq!(tests[1].initial.ram[3], (0x200, 0));
+        assert_eq!(tests[1].cycles[3].2, "write");
+    }
+
+    #[test]
+    fn test_run_passing() {
+        let summary = run_tests(&parse_tests(TESTS).unwrap());
+        assert_eq!(summary.passed, 2);
+        assert!(summary.failures.is_empty());
+    }
+
+    #[test]
+    fn test_run_mismatch() {
+        let mut test = parse_tests(TESTS).unwrap().remove(1);
+        test.expected.a = 8;
+        test.expected.ram[3] = (0x200, 8);
+        test.cycles.pop();
+
+        let problems = run_test(&test).unwrap_err();
+        assert_eq!(problems, vec![
+            "a: got $07, expected $08".to_string(),
+            "$0200: got $07, expected $08".to_string(),
+            "bus: unexpected write of $07 at $0200".to_string(),
+            "cycles: got 4, expected 3".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_run_bus_mismatch() {
+        let mut test = parse_tests(TESTS).unwrap().remove(1);
+        test.cycles[1] = (0x1001, 0x01, "read".to_string());
+
+        let problems = run_test(&test).unwrap_err();
+        assert_eq!(problems, vec!["bus: unexpected read of $00 at $1001".to_string()]);
+    }
+
+    #[test]
+    fn test_run_stack_wrap() {
+        // pha with the stack pointer at $00
+        let test = parse_tests(r#"[{
+            "name": "48",
+            "initial": { "pc": 4096, "s": 0, "a": 66, "x": 0, "y": 0, "p": 36,
+                         "ram": [[4096, 72], [4097, 0], [256, 0]] },
+            "final": { "pc": 4097, "s": 255, "a": 66, "x": 0, "y": 0, "p": 36,
+                       "ram": [[4096, 72], [4097, 0], [256, 66]] },
+            "cycles": [[4096, 72, "read"], [4097, 0, "read"], [256, 66, "write"]]
+        }]"#).unwrap();
+
+        assert_eq!(run_test(&test[0]), Ok(()));
+    }
+
+    #[test]
+    fn test_run_illegal_opcode() {
+        let mut test = parse_tests(TESTS).unwrap().remove(0);
+        test.initial.ram[0] = (0x1000, 0xFF);
+
+        let problems = run_test(&test).unwrap_err();
+        assert_eq!(problems[0], "error: illegal opcode $ff at $1000");
+    }
+}
@@ -5,7 +5,7 @@ extern crate opengl_graphics;
 
 extern crate rand;
 
-use self::piston::window::WindowSettings;
+use self::piston::window::{AdvancedWindow, WindowSettings};
 use self::piston::event_loop::*;
 use self::piston::input::*;
 use self::glutin_window::GlutinWindow as Window;
@@ -18,32 +18,18 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-use ops::make_op_table;
-use cpu::{CPU, Instr};
-use mmu::{Block, MMU};
+use cpu::{make_snake_cpu, CPU};
+use clock::{Clock, Speed};
 
-
-fn make_snake_cpu(rom_init: Option<Vec<u8>>) -> CPU {
-        let mut mmu = MMU::new(&Vec::new());
-        // RAM
-        mmu.add_block(&Block::new(0, 0x600, false, None));
-        // ROM
-        mmu.add_block(&Block::new(0x600, 0x1000, true, rom_init));
-
-        let mut cpu = CPU::new(mmu);
-        cpu.r.pc = 0x600;
-
-        // since for some reason we're reading 0xff and 0xfe as direct memory access,
-        // have the stack start at 0xfc instead of 0xff
-        cpu.r.s = 0xfc;
-        cpu
-}
+// About what the game ran at when every update stepped 15 instructions, which is playable.
+// Its delay loop is all that sets the pace, so at a real 1 MHz it's far too fast.
+const SNAKE_SPEED: Speed = Speed::Hz(6_000);
 
 
 pub struct SnakeApp {
     gl: GlGraphics,
-    ops: [Instr; 256],
     cpu: CPU,
+    clock: Clock,
 }
 
 impl SnakeApp {
@@ -106,36 +92,47 @@ impl SnakeApp {
     fn update(&mut self, args: &UpdateArgs) {
 
         let mut rng = rand::thread_rng();
-        
-        for i in 0..15 {
+
+        // catch up with the clock, however long it has been since the last update
+        let due = self.clock.cycles_due();
+        let start = self.cpu.r.cc;
+        while self.cpu.r.running && self.cpu.r.cc.wrapping_sub(start) < due {
 
             // set 0xfe to random byte
-            self.cpu.mmu.blocks[0].memory[0xfe] = rng.gen_range(0, 16);
+            self.poke(0xfe, rng.gen_range(0, 16));
 
-            self.cpu.step(self.ops);
+            // on an error stop the cpu, but leave the window up showing the last frame
+            if let Err(error) = self.cpu.step() {
+                println!("Error, cpu stopped: {}", error);
+                self.cpu.r.running = false;
+            }
         }
+        self.clock.ran(self.cpu.r.cc.wrapping_sub(start));
 
     }
 
     fn handle_press(&mut self, button: &Button) {
 
         // apply input on paddle
-        let ram = &mut self.cpu.mmu.blocks[0].memory;
         match button {
             Button::Keyboard(Key::Up) => {
-                ram[0xff] = 0x77;
+                self.poke(0xff, 0x77);
             }
 
             Button::Keyboard(Key::Down) => {
-                ram[0xff] = 0x73;
+                self.poke(0xff, 0x73);
             }
 
             Button::Keyboard(Key::Left) => {
-                ram[0xff] = 0x61;
+                self.poke(0xff, 0x61);
             }
 
             Button::Keyboard(Key::Right) => {
-                ram[0xff] = 0x64;
+                self.poke(0xff, 0x64);
+            }
+
+            Button::Keyboard(Key::Tab) => {
+                self.clock.toggle_fast_forward();
             }
             _ => {
                 // println!("this action isn't being handled now");
@@ -143,15 +140,33 @@ impl SnakeApp {
         }
 
     }
+
+    // Hand the program a key or random number, through the MMU rather than straight into
+    // the RAM block so the decode cache sees the write
+    fn poke(&mut self, addr: usize, value: u8) {
+        if let Err(error) = self.cpu.mmu.load(addr, &[value]) {
+            println!("Error, {}", error);
+        }
+    }
 }
 
 
-pub fn play_snake() {
+// Play at `speed`, or SNAKE_SPEED. Tab toggles fast forward, and the speed it's really
+// running at is shown in the title.
+pub fn play_snake(speed: Option<Speed>) {
     let mut rom_file = File::open("/home/q/Desktop/demos/emulator_6502/snake.bin").unwrap();
 
     let mut buffer = Vec::new();
     rom_file.read_to_end(&mut buffer).unwrap();
 
+    let cpu = match make_snake_cpu(Some(buffer)) {
+        Ok(cpu) => cpu,
+        Err(error) => {
+            println!("Error, could not load snake: {}", error);
+            return;
+        }
+    };
+
     // init GUI
     let opengl = OpenGL::V3_2;
     let mut window: Window = WindowSettings::new(
@@ -166,8 +181,8 @@ pub fn play_snake() {
     // Create a new game and run it.
     let mut app = SnakeApp {
         gl: GlGraphics::new(opengl),
-        ops: make_op_table(),
-        cpu: make_snake_cpu(Some(buffer)),
+        cpu: cpu,
+        clock: Clock::new(speed.unwrap_or(SNAKE_SPEED)),
     };
 
     let mut events = Events::new(EventSettings::new());
@@ -183,6 +198,11 @@ pub fn play_snake() {
         if let Some(p) = e.press_args() {
             app.handle_press(&p);
         }
+
+        if let Some(mhz) = app.clock.effective_mhz() {
+            let fast_forward = if app.clock.fast_forward { ", fast forward" } else { "" };
+            window.set_title(format!("snake ({:.3} MHz{})", mhz, fast_forward));
+        }
     }
 
     panic!("snake game is over");
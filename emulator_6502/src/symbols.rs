@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Names for addresses, loaded from a label file so traces, profiles and breakpoints can say
+/// `loop` rather than `$0638`.
+///
+/// These formats are understood, one symbol per line, anything else is skipped:
+///
+/// - the assembler's own `0638 loop`
+/// - VICE and ca65 (`ld65 -Ln`) label files, `al C:0638 .loop`
+/// - assignments, `loop = $0638` or `loop equ $0638`
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// The first name given for each address
+    pub names: BTreeMap<u16, String>,
+
+    /// Every name, case insensitive like the assembler's labels
+    pub addresses: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            names: BTreeMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        table.read(text);
+        table
+    }
+
+    /// Add the symbols in `text`, on top of any already there
+    pub fn read(&mut self, text: &str) {
+        for line in text.lines() {
+            let tokens = line.split_whitespace().collect::<Vec<&str>>();
+            let symbol = match tokens.as_slice() {
+                // the address is hex, after an optional memory space
+                ["al", address, name] => u16::from_str_radix(address.splitn(2, ':').last().unwrap(), 16).ok()
+                    .map(|address| (name.trim_start_matches('.'), address)),
+                [name, "=", value] | [name, "equ", value] | [name, "EQU", value] =>
+                    parse_number(value).map(|address| (*name, address)),
+                [address, name] => u16::from_str_radix(address, 16).ok()
+                    .map(|address| (*name, address)),
+                _ => None,
+            };
+            if let Some((name, address)) = symbol {
+                self.insert(name, address);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.names.entry(address).or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_lowercase(), address);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The address of a symbol
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.addresses.get(&name.to_lowercase()).cloned()
+    }
+
+    /// The name of `address` relative to the closest symbol at or before it, like `loop` or
+    /// `loop+3`
+    pub fn name(&self, address: u16) -> Option<String> {
+        self.names.range(..=address).next_back().map(|(&start, name)| {
+            if start == address {
+                name.clone()
+            } else {
+                format!("{}+{}", name, address - start)
+            }
+        })
+    }
+
+    /// `$0638`, followed by its name if it has one
+    pub fn describe(&self, address: u16) -> String {
+        match self.name(address) {
+            Some(name) => format!("${:04x} {}", address, name),
+            None => format!("${:04x}", address),
+        }
+    }
+}
+
+// A number written as $hex, 0xhex or decimal
+fn parse_number(text: &str) -> Option<u16> {
+    if let Some(digits) = text.strip_prefix('$') {
+        u16::from_str_radix(digits, 16).ok()
+    } else if let Some(digits) = text.strip_prefix("0x") {
+        u16::from_str_radix(digits, 16).ok()
+    } else {
+        text.parse::<u16>().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let table = SymbolTable::parse("\
+            0600 init\n\
+            al C:0638 .updateSnake\n\
+            al 0700 .draw\n\
+            sysRandom = $fe\n\
+            screen equ 0x0200\n\
+            rows = 32\n\
+            ; a comment\n\
+            this line is ignored\n");
+
+        assert_eq!(table.lookup("init"), Some(0x0600));
+        assert_eq!(table.lookup("UPDATESNAKE"), Some(0x0638));
+        assert_eq!(table.lookup("draw"), Some(0x0700));
+        assert_eq!(table.lookup("sysrandom"), Some(0xfe));
+        assert_eq!(table.lookup("screen"), Some(0x0200));
+        assert_eq!(table.lookup("rows"), Some(32));
+        assert_eq!(table.names.len(), 6);
+    }
+
+    #[test]
+    fn test_name() {
+        let mut table = SymbolTable::new();
+        table.insert("loop", 0x0638);
+        table.insert("again", 0x0638);
+        table.insert("done", 0x0640);
+
+        assert_eq!(table.name(0x0600), None);
+        assert_eq!(table.name(0x0638), Some("loop".to_string()));
+        assert_eq!(table.name(0x063b), Some("loop+3".to_string()));
+        assert_eq!(table.describe(0x0641), "$0641 done+1");
+        assert_eq!(table.describe(0x0500), "$0500");
+        assert_eq!(table.lookup("again"), Some(0x0638));
+    }
+}
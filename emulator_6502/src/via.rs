@@ -0,0 +1,524 @@
+use device::Device;
+
+// register numbers, relative to where the VIA is mapped
+const ORB: usize = 0x0;
+const ORA: usize = 0x1;
+const DDRB: usize = 0x2;
+const DDRA: usize = 0x3;
+const T1CL: usize = 0x4;
+const T1CH: usize = 0x5;
+const T1LL: usize = 0x6;
+const T1LH: usize = 0x7;
+const T2CL: usize = 0x8;
+const T2CH: usize = 0x9;
+const SR: usize = 0xA;
+const ACR: usize = 0xB;
+const PCR: usize = 0xC;
+const IFR: usize = 0xD;
+const IER: usize = 0xE;
+const ORA_NO_HANDSHAKE: usize = 0xF;
+
+// interrupt flag (and enable) bits
+pub const IRQ_CA2: u8 = 0x01;
+pub const IRQ_CA1: u8 = 0x02;
+pub const IRQ_SR: u8 = 0x04;
+pub const IRQ_CB2: u8 = 0x08;
+pub const IRQ_CB1: u8 = 0x10;
+pub const IRQ_T2: u8 = 0x20;
+pub const IRQ_T1: u8 = 0x40;
+
+/// A MOS 6522 Versatile Interface Adapter: two parallel ports, two 16 bit timers and a shift
+/// register, any of which can interrupt the CPU. Map it with `MMU::add_device`, it takes up
+/// 16 addresses.
+///
+/// Both timers count down once a cycle. Timer 1 either fires once after being loaded or,
+/// with ACR bit 6 set, reloads from its latch and keeps firing every latch + 2 cycles. Timer
+/// 2 is one-shot only, and with ACR bit 5 set counts pulses on PB6 (`pulse_pb6`) instead.
+///
+/// The host side of the ports is the `pa_in`/`pb_in` pins and `port_a`/`port_b`, and the CA1
+/// and CB1 edge interrupts are driven with `set_ca1`/`set_cb1`. The CA2/CB2 handshake modes
+/// and PB7 timer output aren't modelled.
+#[derive(Debug)]
+pub struct Via {
+    pub ora: u8,
+    pub orb: u8,
+    pub ddra: u8,
+    pub ddrb: u8,
+
+    /// The levels on the port pins that are set as inputs, driven by whatever is attached
+    pub pa_in: u8,
+    pub pb_in: u8,
+
+    pub t1_counter: u16,
+    pub t1_latch: u16,
+    t1_armed: bool,
+    t1_reload: bool,
+
+    pub t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+
+    pub sr: u8,
+    sr_bits_left: u8,
+    sr_timer: u32,
+
+    /// The level on CB2, which the shift register shifts in from and out to
+    pub cb2: bool,
+
+    pub acr: u8,
+    pub pcr: u8,
+    pub ifr: u8,
+    pub ier: u8,
+
+    ca1: bool,
+    cb1: bool,
+}
+
+impl Via {
+    pub fn new() -> Via {
+        Via {
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            pa_in: 0xFF,
+            pb_in: 0xFF,
+            t1_counter: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t1_reload: false,
+            t2_counter: 0xFFFF,
+            t2_latch_low: 0xFF,
+            t2_armed: false,
+            sr: 0,
+            sr_bits_left: 0,
+            sr_timer: 0,
+            cb2: true,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+            ca1: true,
+            cb1: true,
+        }
+    }
+
+    /// What port A is driving, pins set as inputs read high
+    pub fn port_a(&self) -> u8 {
+        (self.ora & self.ddra) | !self.ddra
+    }
+
+    /// What port B is driving, pins set as inputs read high
+    pub fn port_b(&self) -> u8 {
+        (self.orb & self.ddrb) | !self.ddrb
+    }
+
+    /// Drive the CA1 input, which interrupts on the edge selected by PCR bit 0
+    pub fn set_ca1(&mut self, level: bool) {
+        let positive = self.pcr & 0x01 != 0;
+        if level != self.ca1 && level == positive {
+            self.ifr |= IRQ_CA1;
+        }
+        self.ca1 = level;
+    }
+
+    /// Drive the CB1 input, which interrupts on the edge selected by PCR bit 4. In the
+    /// shift register modes clocked by CB1 each rising edge also shifts a bit.
+    pub fn set_cb1(&mut self, level: bool) {
+        let positive = self.pcr & 0x10 != 0;
+        if level != self.cb1 && level == positive {
+            self.ifr |= IRQ_CB1;
+        }
+        if level && !self.cb1 && self.sr_mode() & 0x03 == 0x03 {
+            self.shift();
+        }
+        self.cb1 = level;
+    }
+
+    /// A falling edge on PB6, which timer 2 counts in pulse counting mode
+    pub fn pulse_pb6(&mut self) {
+        if self.acr & 0x20 != 0 {
+            self.count_t2(1);
+        }
+    }
+
+    fn sr_mode(&self) -> u8 {
+        (self.acr >> 2) & 0x07
+    }
+
+    // cycles between shifts, for the modes that aren't clocked by CB1
+    fn sr_period(&self) -> u32 {
+        match self.sr_mode() {
+            // under timer 2, which times each half of the shift clock
+            0x1 | 0x4 | 0x5 => 2 * (self.t2_latch_low as u32 + 2),
+            // under the system clock
+            0x2 | 0x6 => 2,
+            _ => 0,
+        }
+    }
+
+    // start shifting 8 bits, on any access to the shift register
+    fn start_shift(&mut self) {
+        self.ifr &= !IRQ_SR;
+        if self.sr_mode() != 0 {
+            self.sr_bits_left = 8;
+            self.sr_timer = self.sr_period();
+        }
+    }
+
+    fn shift(&mut self) {
+        if self.sr_bits_left == 0 {
+            return;
+        }
+        let mode = self.sr_mode();
+        if mode & 0x04 == 0 {
+            // shift in from CB2
+            self.sr = (self.sr << 1) | (self.cb2 as u8);
+        } else {
+            // shift out to CB2, the bits go round so the same byte repeats
+            self.cb2 = self.sr & 0x80 != 0;
+            self.sr = self.sr.rotate_left(1);
+        }
+
+        self.sr_bits_left -= 1;
+        if self.sr_bits_left == 0 {
+            if mode == 0x4 {
+                // free running output never finishes
+                self.sr_bits_left = 8;
+            } else {
+                self.ifr |= IRQ_SR;
+            }
+        }
+    }
+
+    // The counter underflows a cycle after it reaches zero. In free run mode the latch is
+    // copied back in on the cycle after that, giving a period of latch + 2.
+    fn count_t1(&mut self, cycles: u32) {
+        let mut left = cycles;
+        while left > 0 {
+            if self.t1_reload {
+                self.t1_counter = self.t1_latch;
+                self.t1_reload = false;
+                left -= 1;
+                continue;
+            }
+            if left <= self.t1_counter as u32 {
+                self.t1_counter -= left as u16;
+                return;
+            }
+
+            left -= self.t1_counter as u32 + 1;
+            self.t1_counter = 0xFFFF;
+            if self.t1_armed {
+                self.ifr |= IRQ_T1;
+            }
+            if self.acr & 0x40 != 0 {
+                self.t1_reload = true;
+            } else {
+                self.t1_armed = false;
+            }
+        }
+    }
+
+    // timer 2 only interrupts the first time it underflows after being loaded
+    fn count_t2(&mut self, count: u32) {
+        if count > self.t2_counter as u32 && self.t2_armed {
+            self.ifr |= IRQ_T2;
+            self.t2_armed = false;
+        }
+        self.t2_counter = self.t2_counter.wrapping_sub(count as u16);
+    }
+
+    fn read_ifr(&self) -> u8 {
+        if self.irq() {
+            self.ifr | 0x80
+        } else {
+            self.ifr
+        }
+    }
+}
+
+impl Default for Via {
+    fn default() -> Via {
+        Via::new()
+    }
+}
+
+impl Device for Via {
+    fn length(&self) -> usize {
+        16
+    }
+
+    fn read(&mut self, reg: usize) -> u8 {
+        match reg {
+            ORB => {
+                self.ifr &= !(IRQ_CB1 | IRQ_CB2);
+                (self.orb & self.ddrb) | (self.pb_in & !self.ddrb)
+            },
+            ORA => {
+                self.ifr &= !(IRQ_CA1 | IRQ_CA2);
+                (self.ora & self.ddra) | (self.pa_in & !self.ddra)
+            },
+            ORA_NO_HANDSHAKE => (self.ora & self.ddra) | (self.pa_in & !self.ddra),
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1CL => {
+                self.ifr &= !IRQ_T1;
+                self.t1_counter as u8
+            },
+            T1CH => (self.t1_counter >> 8) as u8,
+            T1LL => self.t1_latch as u8,
+            T1LH => (self.t1_latch >> 8) as u8,
+            T2CL => {
+                self.ifr &= !IRQ_T2;
+                self.t2_counter as u8
+            },
+            T2CH => (self.t2_counter >> 8) as u8,
+            SR => {
+                self.start_shift();
+                self.sr
+            },
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.read_ifr(),
+            // bit 7 always reads as set
+            IER => self.ier | 0x80,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: usize, val: u8) {
+        match reg {
+            ORB => {
+                self.ifr &= !(IRQ_CB1 | IRQ_CB2);
+                self.orb = val;
+            },
+            ORA => {
+                self.ifr &= !(IRQ_CA1 | IRQ_CA2);
+                self.ora = val;
+            },
+            ORA_NO_HANDSHAKE => self.ora = val,
+            DDRB => self.ddrb = val,
+            DDRA => self.ddra = val,
+            T1CL | T1LL => self.t1_latch = (self.t1_latch & 0xFF00) | val as u16,
+            // loading the high byte of the counter starts the timer
+            T1CH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((val as u16) << 8);
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.t1_reload = false;
+                self.ifr &= !IRQ_T1;
+            },
+            T1LH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((val as u16) << 8);
+                self.ifr &= !IRQ_T1;
+            },
+            T2CL => self.t2_latch_low = val,
+            T2CH => {
+                self.t2_counter = ((val as u16) << 8) | self.t2_latch_low as u16;
+                self.t2_armed = true;
+                self.ifr &= !IRQ_T2;
+            },
+            SR => {
+                self.sr = val;
+                self.start_shift();
+            },
+            ACR => self.acr = val,
+            PCR => self.pcr = val,
+            // writing a 1 clears that flag
+            IFR => self.ifr &= !(val & 0x7F),
+            // bit 7 says whether the other bits set or clear enables
+            IER => {
+                if val & 0x80 != 0 {
+                    self.ier |= val & 0x7F;
+                } else {
+                    self.ier &= !(val & 0x7F);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.count_t1(cycles);
+        if self.acr & 0x20 == 0 {
+            self.count_t2(cycles);
+        }
+
+        let period = self.sr_period();
+        if self.sr_bits_left > 0 && period > 0 {
+            let mut left = cycles;
+            while left >= self.sr_timer && self.sr_bits_left > 0 {
+                left -= self.sr_timer;
+                self.shift();
+                self.sr_timer = period;
+            }
+            self.sr_timer -= left.min(self.sr_timer);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7F != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // import parent scope
+    use super::*;
+    use cpu::{CPU, StopReason};
+    use mmu::{Block, MMU};
+
+    #[test]
+    fn test_ports() {
+        let mut via = Via::new();
+        via.write(DDRA, 0x0F);
+        via.write(ORA, 0x35);
+        via.pa_in = 0xA0;
+
+        assert_eq!(via.port_a(), 0xF5);
+        assert_eq!(via.read(ORA), 0xA5);
+
+        via.write(DDRB, 0xFF);
+        via.write(ORB, 0x42);
+        assert_eq!(via.port_b(), 0x42);
+        assert_eq!(via.read(ORB), 0x42);
+    }
+
+    #[test]
+    fn test_t1_one_shot() {
+        let mut via = Via::new();
+        via.write(T1CL, 0x10);
+        via.write(T1CH, 0x00);
+
+        via.tick(0x10);
+        assert_eq!(via.t1_counter, 0);
+        assert_eq!(via.ifr, 0);
+
+        via.tick(1);
+        assert_eq!(via.ifr, IRQ_T1);
+        // the flag shows, but doesn't interrupt until enabled
+        assert!(!via.irq());
+        via.write(IER, 0x80 | IRQ_T1);
+        assert!(via.irq());
+        assert_eq!(via.read(IFR), 0x80 | IRQ_T1);
+
+        // reading the low counter clears it, and a one-shot doesn't fire again
+        via.read(T1CL);
+        via.tick(0x20000);
+        assert_eq!(via.ifr, 0);
+    }
+
+    #[test]
+    fn test_t1_free_run() {
+        let mut via = Via::new();
+        via.write(ACR, 0x40);
+        via.write(T1CL, 0x08);
+        via.write(T1CH, 0x00);
+
+        via.tick(9);
+        assert_eq!(via.ifr, IRQ_T1);
+        via.write(IFR, IRQ_T1);
+
+        // reloaded from the latch, the next one is latch + 2 cycles later
+        via.tick(9);
+        assert_eq!(via.ifr, 0);
+        via.tick(1);
+        assert_eq!(via.ifr, IRQ_T1);
+    }
+
+    #[test]
+    fn test_t2() {
+        let mut via = Via::new();
+        via.write(T2CL, 0x04);
+        via.write(T2CH, 0x00);
+
+        via.tick(5);
+        assert_eq!(via.ifr, IRQ_T2);
+        via.read(T2CL);
+        via.tick(0x10000);
+        assert_eq!(via.ifr, 0);
+
+        // pulse counting
+        via.write(ACR, 0x20);
+        via.write(T2CL, 0x01);
+        via.write(T2CH, 0x00);
+        via.tick(100);
+        assert_eq!(via.t2_counter, 1);
+        via.pulse_pb6();
+        via.pulse_pb6();
+        assert_eq!(via.ifr, IRQ_T2);
+    }
+
+    #[test]
+    fn test_shift_register() {
+        let mut via = Via::new();
+
+        // shift out under the system clock, a bit every 2 cycles
+        via.write(ACR, 0x18);
+        via.write(SR, 0x81);
+        via.tick(2);
+        assert!(via.cb2);
+        via.tick(2);
+        assert!(!via.cb2);
+        via.tick(12);
+        assert_eq!(via.ifr, IRQ_SR);
+        assert_eq!(via.sr, 0x81);
+
+        // shift in on CB1
+        via.write(ACR, 0x0C);
+        via.read(SR);
+        assert_eq!(via.ifr, 0);
+        for i in 0..8 {
+            via.cb2 = i % 2 == 0;
+            via.set_cb1(false);
+            via.set_cb1(true);
+        }
+        assert_eq!(via.sr, 0xAA);
+        assert_eq!(via.ifr, IRQ_SR | IRQ_CB1);
+    }
+
+    #[test]
+    fn test_ca1_edge() {
+        let mut via = Via::new();
+        via.set_ca1(false);
+        assert_eq!(via.ifr, IRQ_CA1);
+        via.read(ORA);
+        assert_eq!(via.ifr, 0);
+
+        // positive edge only
+        via.write(PCR, 0x01);
+        via.set_ca1(true);
+        assert_eq!(via.ifr, IRQ_CA1);
+    }
+
+    #[test]
+    fn test_timer_interrupts_cpu() {
+        let mut mmu = MMU::new(&Vec::new()).unwrap();
+        // RAM
+        mmu.add_block(&Block::new(0, 0x1000, false, None).unwrap()).unwrap();
+        // vectors, IRQ goes to $0300
+        mmu.add_block(&Block::new(0xFFFA, 6, true, Some(vec![0, 0, 0, 0, 0x00, 0x03])).unwrap()).unwrap();
+        mmu.add_device(0x6000, Box::new(Via::new())).unwrap();
+
+        // lda #$c0 / sta $600e  (enable timer 1 interrupts)
+        // lda #$10 / sta $6004 / lda #$00 / sta $6005  (start timer 1 at $0010)
+        // cli / jmp $0210
+        let program = [0xA9, 0xC0, 0x8D, 0x0E, 0x60,
+                       0xA9, 0x10, 0x8D, 0x04, 0x60, 0xA9, 0x00, 0x8D, 0x05, 0x60,
+                       0x58, 0x4C, 0x10, 0x02];
+        // inc $10 / lda $6004 (acknowledge) / rti
+        let handler = [0xE6, 0x10, 0xAD, 0x04, 0x60, 0x40];
+        mmu.blocks[0].memory[0x200..0x200 + program.len()].copy_from_slice(&program);
+        mmu.blocks[0].memory[0x300..0x300 + handler.len()].copy_from_slice(&handler);
+
+        let mut cpu = CPU::new(mmu);
+        cpu.r.pc = 0x200;
+
+        // a jmp to itself isn't the end while an interrupt can still come
+        assert_eq!(cpu.run(200), Ok(StopReason::Budget));
+        assert_eq!(cpu.mmu.blocks[0].memory[0x10], 1);
+        assert_eq!(cpu.r.pc, 0x210);
+        assert!(!cpu.r.get_flag('I'));
+    }
+}